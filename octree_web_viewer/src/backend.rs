@@ -86,7 +86,8 @@ fn get_octree_from_state(
     })
 }
 
-/// Asynchronous Handler to get Node Data
+/// Asynchronous Handler to get Node Data. Compressed nodes are sent decompressed, so the client
+/// does not need to know about compression.
 pub async fn get_nodes_data(
     (octree_id, state, nodes): (
         web::Path<String>,
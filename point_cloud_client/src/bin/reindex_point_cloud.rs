@@ -0,0 +1,144 @@
+use clap::Clap;
+use nalgebra::{Isometry3, Point3, Quaternion, Translation3, UnitQuaternion};
+use point_cloud_client::reindex::{reindex_point_cloud, Layout};
+use point_cloud_client::PointCloudClientBuilder;
+use point_viewer::data_provider::{ChecksummingDataSink, DataSink, OnDiskDataProvider};
+use point_viewer::geometry::Aabb;
+use point_viewer::iterator::{OutputFrame, PointLocation, PointQuery};
+use point_viewer::read_write::Compression;
+use point_viewer::utils::{f64s_from_str, point3f64_from_str};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+fn quaternion_from_str(s: &str) -> std::result::Result<UnitQuaternion<f64>, &'static str> {
+    let q = f64s_from_str(s, 4)?;
+    Ok(UnitQuaternion::from_quaternion(Quaternion::new(
+        q[0], q[1], q[2], q[3],
+    )))
+}
+
+fn local_enu_from_str(s: &str) -> std::result::Result<OutputFrame, &'static str> {
+    let lat_lng = f64s_from_str(s, 2)?;
+    Ok(OutputFrame::LocalEnu {
+        lat: lat_lng[0],
+        lng: lat_lng[1],
+    })
+}
+
+#[derive(Clap)]
+#[clap(about = "Re-indexes point clouds into a new octree or S2 cells.")]
+struct CommandlineArguments {
+    /// The locations containing the point clouds to re-index.
+    #[clap(parse(from_str), required = true)]
+    locations: Vec<String>,
+
+    /// Output directory to write the point cloud into.
+    #[clap(long, parse(from_os_str))]
+    output_directory: PathBuf,
+
+    /// Write S2 cells instead of an octree. Their positions are expected to be in ECEF.
+    #[clap(long)]
+    s2_cells: bool,
+
+    /// Minimal precision of the octree. Not used for S2 cells.
+    #[clap(long, default_value = "0.001")]
+    resolution: f64,
+
+    /// The S2 level of the cells. Not used for octrees.
+    #[clap(long, default_value = "20")]
+    split_level: u64,
+
+    /// The attributes to keep. Octrees need color and can only store color and intensity.
+    #[clap(long, default_value = "color", use_delimiter = true)]
+    attributes: Vec<String>,
+
+    /// Only keep the points in the bounding box with this minimum. Requires `--crop-max`.
+    #[clap(long, requires = "crop-max", parse(try_from_str = point3f64_from_str))]
+    crop_min: Option<Point3<f64>>,
+
+    /// Only keep the points in the bounding box with this maximum. Requires `--crop-min`.
+    #[clap(long, requires = "crop-min", parse(try_from_str = point3f64_from_str))]
+    crop_max: Option<Point3<f64>>,
+
+    /// Translation of the output frame from the input frame, applied after the rotation.
+    #[clap(long, parse(try_from_str = point3f64_from_str))]
+    translation: Option<Point3<f64>>,
+
+    /// Rotation of the output frame from the input frame as a quaternion "w x y z".
+    #[clap(long, parse(try_from_str = quaternion_from_str))]
+    rotation: Option<UnitQuaternion<f64>>,
+
+    /// Transform ECEF positions into the local east-north-up frame at "latitude longitude" in
+    /// degrees.
+    #[clap(
+        long,
+        conflicts_with_all = &["translation", "rotation"],
+        parse(try_from_str = local_enu_from_str)
+    )]
+    local_enu: Option<OutputFrame>,
+
+    /// Compression of the nodes: none, zstd or lz4.
+    #[clap(long, default_value = "none")]
+    compression: Compression,
+
+    /// Store checksums of the nodes in the meta, so that corrupt nodes can be detected on read.
+    #[clap(long)]
+    checksums: bool,
+
+    /// The maximum number of threads to be running.
+    #[clap(long, default_value = "30")]
+    num_threads: usize,
+}
+
+fn main() {
+    let args = CommandlineArguments::parse();
+    let client = PointCloudClientBuilder::new(&args.locations)
+        .num_threads(args.num_threads)
+        .build()
+        .expect("Couldn't create point cloud client.");
+
+    let location = match (args.crop_min, args.crop_max) {
+        (Some(min), Some(max)) => PointLocation::Aabb(Aabb::new(min, max)),
+        _ => PointLocation::AllPoints,
+    };
+    let output_frame = match (args.local_enu, args.translation, args.rotation) {
+        (Some(local_enu), _, _) => Some(local_enu),
+        (None, None, None) => None,
+        (None, translation, rotation) => Some(OutputFrame::Isometry(Isometry3::from_parts(
+            Translation3::from(translation.unwrap_or_else(Point3::origin).coords),
+            rotation.unwrap_or_else(UnitQuaternion::identity),
+        ))),
+    };
+    let query = PointQuery {
+        attributes: args.attributes.iter().map(String::as_str).collect(),
+        location,
+        output_frame,
+        ..Default::default()
+    };
+
+    let layout = if args.s2_cells {
+        Layout::S2Cells {
+            split_level: args.split_level,
+        }
+    } else {
+        Layout::Octree {
+            resolution: args.resolution,
+        }
+    };
+    let mut data_sink: Arc<dyn DataSink> =
+        Arc::new(OnDiskDataProvider::new(args.output_directory.clone()));
+    if args.checksums {
+        data_sink = Arc::new(ChecksummingDataSink::new(data_sink));
+    }
+    if let Err(e) = reindex_point_cloud(
+        &client,
+        &query,
+        layout,
+        args.compression,
+        data_sink,
+        &args.output_directory,
+    ) {
+        eprintln!("Encountered error:\n{}", e);
+        std::process::exit(1);
+    }
+}
@@ -1,22 +1,127 @@
+use futures::channel::mpsc;
+use futures::executor::block_on;
+use futures::{SinkExt, Stream, StreamExt};
 use point_viewer::data_provider::{DataProvider, DataProviderFactory};
+use point_viewer::deduplication::Deduplication;
 use point_viewer::errors::*;
 use point_viewer::geometry::Aabb;
-use point_viewer::iterator::{ParallelIterator, PointCloud, PointQuery};
-use point_viewer::octree::Octree;
+use point_viewer::iterator::{
+    CancellationToken, ParallelIterator, PointCloud, PointLocation, PointQuery,
+};
+use point_viewer::octree::{NodeId, Octree};
+use point_viewer::read_write::{Encoding, NodeIterator};
 use point_viewer::s2_cells::S2Cells;
 use point_viewer::{PointsBatch, NUM_POINTS_PER_BATCH};
+use s2::cellid::CellID;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
 
-enum PointClouds {
-    Octrees(Vec<Octree>),
-    S2Cells(Vec<S2Cells>),
+pub mod reindex;
+
+/// Either kind of point cloud, so that octrees and S2 cells can be queried together.
+#[allow(clippy::large_enum_variant)]
+pub enum AnyPointCloud {
+    Octree(Octree),
+    S2Cells(S2Cells),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum AnyNodeId {
+    Octree(NodeId),
+    S2Cell(CellID),
+}
+
+impl fmt::Display for AnyNodeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AnyNodeId::Octree(node_id) => node_id.fmt(f),
+            AnyNodeId::S2Cell(cell_id) => cell_id.fmt(f),
+        }
+    }
+}
+
+impl AnyPointCloud {
+    pub fn from_data_provider(data_provider: Box<dyn DataProvider>) -> Result<Self> {
+        let meta = data_provider.meta_proto()?;
+        if meta.version <= 11 || meta.has_octree() {
+            Octree::from_data_provider(data_provider).map(AnyPointCloud::Octree)
+        } else {
+            S2Cells::from_data_provider(data_provider).map(AnyPointCloud::S2Cells)
+        }
+    }
+}
+
+fn wrong_node_id(node_id: AnyNodeId) -> ! {
+    panic!(
+        "Node {} does not belong to this kind of point cloud.",
+        node_id
+    )
 }
 
+impl PointCloud for AnyPointCloud {
+    type Id = AnyNodeId;
+
+    fn nodes_in_location(&self, location: &PointLocation, max_level: Option<u64>) -> Vec<Self::Id> {
+        match self {
+            AnyPointCloud::Octree(octree) => octree
+                .nodes_in_location(location, max_level)
+                .into_iter()
+                .map(AnyNodeId::Octree)
+                .collect(),
+            AnyPointCloud::S2Cells(s2_cells) => s2_cells
+                .nodes_in_location(location, max_level)
+                .into_iter()
+                .map(AnyNodeId::S2Cell)
+                .collect(),
+        }
+    }
+
+    fn encoding_for_node(&self, id: Self::Id) -> Encoding {
+        match (self, id) {
+            (AnyPointCloud::Octree(octree), AnyNodeId::Octree(id)) => octree.encoding_for_node(id),
+            (AnyPointCloud::S2Cells(s2_cells), AnyNodeId::S2Cell(id)) => {
+                s2_cells.encoding_for_node(id)
+            }
+            _ => wrong_node_id(id),
+        }
+    }
+
+    fn points_in_node(
+        &self,
+        attributes: &[&str],
+        node_id: Self::Id,
+        batch_size: usize,
+    ) -> Result<NodeIterator> {
+        match (self, node_id) {
+            (AnyPointCloud::Octree(octree), AnyNodeId::Octree(id)) => {
+                octree.points_in_node(attributes, id, batch_size)
+            }
+            (AnyPointCloud::S2Cells(s2_cells), AnyNodeId::S2Cell(id)) => {
+                s2_cells.points_in_node(attributes, id, batch_size)
+            }
+            _ => wrong_node_id(node_id),
+        }
+    }
+
+    fn bounding_box(&self) -> &Aabb {
+        match self {
+            AnyPointCloud::Octree(octree) => octree.bounding_box(),
+            AnyPointCloud::S2Cells(s2_cells) => s2_cells.bounding_box(),
+        }
+    }
+}
+
+/// Cloning a client is cheap, the point clouds are shared.
+#[derive(Clone)]
 pub struct PointCloudClient {
-    point_clouds: PointClouds,
+    point_clouds: Arc<Vec<AnyPointCloud>>,
     aabb: Aabb,
     num_points_per_batch: usize,
     num_threads: usize,
     buffer_size: usize,
+    deduplication: Option<Deduplication>,
 }
 
 impl PointCloudClient {
@@ -24,38 +129,121 @@ impl PointCloudClient {
         &self.aabb
     }
 
-    fn for_each<C, F>(&self, point_cloud: &[C], point_query: &PointQuery, mut func: F) -> Result<()>
+    fn for_each_impl<F>(
+        &self,
+        point_query: &PointQuery,
+        skip_failed_nodes: bool,
+        mut func: F,
+    ) -> Result<Vec<Error>>
     where
-        C: PointCloud,
         F: FnMut(PointsBatch) -> Result<()>,
     {
         let mut parallel_iterator = ParallelIterator::new(
-            point_cloud,
+            &self.point_clouds,
             point_query,
             self.num_points_per_batch,
             self.num_threads,
             self.buffer_size,
-        );
-        parallel_iterator.try_for_each_batch(&mut func)
+        )
+        .skip_failed_nodes(skip_failed_nodes);
+        if let Some(deduplication) = &self.deduplication {
+            parallel_iterator = parallel_iterator.deduplication(deduplication);
+        }
+        parallel_iterator.try_for_each_batch(&mut func)?;
+        Ok(parallel_iterator.into_failed_nodes())
     }
 
+    /// Calls `func` on batches of the points matching the query. The first node that cannot be
+    /// read aborts the query with an `ErrorKind::NodeReadFailed` error.
     pub fn for_each_point_data<F>(&self, point_query: &PointQuery, func: F) -> Result<()>
     where
         F: FnMut(PointsBatch) -> Result<()>,
     {
-        match &self.point_clouds {
-            PointClouds::Octrees(octrees) => self.for_each(octrees, point_query, func),
-            PointClouds::S2Cells(s2_cells) => self.for_each(s2_cells, point_query, func),
+        self.for_each_impl(point_query, false, func).map(|_| ())
+    }
+
+    /// Like `for_each_point_data`, but nodes that cannot be read are skipped. Their errors are
+    /// returned once the query has finished.
+    pub fn for_each_point_data_skipping_failed_nodes<F>(
+        &self,
+        point_query: &PointQuery,
+        func: F,
+    ) -> Result<Vec<Error>>
+    where
+        F: FnMut(PointsBatch) -> Result<()>,
+    {
+        self.for_each_impl(point_query, true, func)
+    }
+
+    /// Returns the points matching the query as a stream, for use in async code. The query runs
+    /// on its own threads, which pause when `buffer_size` batches are waiting to be polled.
+    /// Dropping the stream cancels the query. The first node that cannot be read ends the stream
+    /// with an `ErrorKind::NodeReadFailed` error.
+    pub fn stream_point_data(&self, point_query: &PointQuery) -> PointDataStream {
+        let (mut sender, receiver) = mpsc::channel(self.buffer_size);
+        let attributes: Vec<String> = point_query
+            .attributes
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        // The query must not borrow anything, since it is moved to another thread.
+        let mut query = point_query.with_attributes(Vec::new());
+        // Cancelled by dropping the stream, or by cancelling the caller's token.
+        let cancellation_token = point_query.cancellation_token.child();
+        query.cancellation_token = cancellation_token.clone();
+        let client = self.clone();
+        std::thread::spawn(move || {
+            let query = PointQuery {
+                attributes: attributes.iter().map(String::as_str).collect(),
+                ..query
+            };
+            let result = client.for_each_point_data(&query, |points_batch| {
+                // Blocks while the stream's buffer is full.
+                block_on(sender.send(Ok(points_batch)))
+                    .map_err(|e| ErrorKind::Channel(e.to_string()).into())
+            });
+            match result {
+                // Either the stream was dropped or the query was cancelled.
+                Ok(()) | Err(Error(ErrorKind::Channel(_), _)) => (),
+                Err(e) => {
+                    let _ = block_on(sender.send(Err(e)));
+                }
+            }
+        });
+        PointDataStream {
+            receiver,
+            cancellation_token,
         }
     }
 }
 
+/// The points matching a query, see `PointCloudClient::stream_point_data`.
+pub struct PointDataStream {
+    receiver: mpsc::Receiver<Result<PointsBatch>>,
+    cancellation_token: CancellationToken,
+}
+
+impl Stream for PointDataStream {
+    type Item = Result<PointsBatch>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for PointDataStream {
+    fn drop(&mut self) {
+        self.cancellation_token.cancel();
+    }
+}
+
 pub struct PointCloudClientBuilder<'a> {
     locations: &'a [String],
     data_provider_factory: DataProviderFactory,
     num_points_per_batch: usize,
     num_threads: usize,
     buffer_size: usize,
+    deduplication: Option<Deduplication>,
 }
 
 impl<'a> PointCloudClientBuilder<'a> {
@@ -66,6 +254,7 @@ impl<'a> PointCloudClientBuilder<'a> {
             num_points_per_batch: NUM_POINTS_PER_BATCH,
             num_threads: std::cmp::max(1, num_cpus::get() - 1),
             buffer_size: 4,
+            deduplication: None,
         }
     }
 
@@ -89,6 +278,13 @@ impl<'a> PointCloudClientBuilder<'a> {
         self
     }
 
+    /// Removes duplicates of points that are contained in several of the point clouds. The
+    /// priorities refer to the point clouds in the order of the locations.
+    pub fn deduplication(mut self, deduplication: Deduplication) -> Self {
+        self.deduplication = Some(deduplication);
+        self
+    }
+
     pub fn build(self) -> Result<PointCloudClient> {
         if self.locations.is_empty() {
             return Err("No locations specified for point cloud client.".into());
@@ -104,39 +300,24 @@ impl<'a> PointCloudClientBuilder<'a> {
             b.grow(*bbox.min());
             b.grow(*bbox.max());
         };
-        let first_meta = data_providers[0].meta_proto()?;
-        let point_clouds = if first_meta.version <= 11 || first_meta.has_octree() {
-            PointClouds::Octrees(
-                data_providers
-                    .into_iter()
-                    .map(|provider| {
-                        Octree::from_data_provider(provider).map(|octree| {
-                            unite(octree.bounding_box(), &mut aabb);
-                            octree
-                        })
-                    })
-                    .collect::<Result<Vec<Octree>>>()?,
-            )
-        } else {
-            PointClouds::S2Cells(
-                data_providers
-                    .into_iter()
-                    .map(|provider| {
-                        S2Cells::from_data_provider(provider).map(|s2_cells| {
-                            unite(s2_cells.bounding_box(), &mut aabb);
-                            s2_cells
-                        })
-                    })
-                    .collect::<Result<Vec<S2Cells>>>()?,
-            )
-        };
+        // Octrees and S2 cells can be mixed.
+        let point_clouds = data_providers
+            .into_iter()
+            .map(|provider| {
+                AnyPointCloud::from_data_provider(provider).map(|point_cloud| {
+                    unite(point_cloud.bounding_box(), &mut aabb);
+                    point_cloud
+                })
+            })
+            .collect::<Result<Vec<AnyPointCloud>>>()?;
 
         Ok(PointCloudClient {
-            point_clouds,
+            point_clouds: Arc::new(point_clouds),
             aabb: aabb.unwrap_or_else(Aabb::zero),
             num_points_per_batch: self.num_points_per_batch,
             num_threads: self.num_threads,
             buffer_size: self.buffer_size,
+            deduplication: self.deduplication,
         })
     }
 }
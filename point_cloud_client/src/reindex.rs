@@ -0,0 +1,212 @@
+use crate::{PointCloudClient, PointDataStream};
+use futures::executor::{block_on_stream, BlockingStream};
+use point_viewer::data_provider::DataSink;
+use point_viewer::errors::*;
+use point_viewer::geometry::Aabb;
+use point_viewer::iterator::PointQuery;
+use point_viewer::octree::build_octree_into;
+use point_viewer::read_write::{
+    AttributeCompression, AttributeEncodings, Compression, Encoding, NodeWriter, OpenMode,
+    S2Splitter,
+};
+use point_viewer::{proto, NumberOfPoints, PointsBatch};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// The attributes an octree can store.
+const OCTREE_ATTRIBUTES: [&str; 2] = ["color", "intensity"];
+
+/// The layout of a re-indexed point cloud.
+#[derive(Clone, Copy, Debug)]
+pub enum Layout {
+    Octree { resolution: f64 },
+    S2Cells { split_level: u64 },
+}
+
+/// Writes the points matching the query into a new point cloud with the given layout. The query
+/// selects the attributes to keep, its location crops the point cloud and its output frame
+/// transforms the positions. S2 cells expect the positions to be in ECEF.
+///
+/// Octrees are built in the working directory, see `build_octree_into`. Since the bounding box
+/// of an octree has to be known in advance, the points are queried twice for it. Octrees need
+/// the color attribute. If the query fails while the octree is built, the nodes written so far
+/// are left in the data sink, but no meta.
+pub fn reindex_point_cloud(
+    client: &PointCloudClient,
+    query: &PointQuery,
+    layout: Layout,
+    compression: Compression,
+    data_sink: Arc<dyn DataSink>,
+    working_directory: impl AsRef<Path>,
+) -> Result<()> {
+    let attribute_compression = AttributeCompression::uniform(
+        &[&["position"], &query.attributes[..]].concat(),
+        compression,
+    );
+    match layout {
+        Layout::Octree { resolution } => {
+            if let Some(attribute) = query
+                .attributes
+                .iter()
+                .find(|attribute| !OCTREE_ATTRIBUTES.contains(attribute))
+            {
+                return Err(ErrorKind::InvalidInput(format!(
+                    "Octrees cannot store the attribute '{}'.",
+                    attribute
+                ))
+                .into());
+            }
+            // The octree generation counts the points of a node by its color blob.
+            if !query.attributes.contains(&"color") {
+                return Err(ErrorKind::InvalidInput(
+                    "Octrees need the attribute 'color'.".to_string(),
+                )
+                .into());
+            }
+            let (bounding_box, num_points) = bounding_box_and_num_points(client, query)?;
+            let input = QueriedPoints::new(client.stream_point_data(query), num_points);
+            let error = Arc::clone(&input.error);
+            let data_sink = Arc::new(MetaUnlessFailed {
+                data_sink,
+                error: Arc::clone(&error),
+            });
+            build_octree_into(
+                data_sink,
+                working_directory,
+                resolution,
+                bounding_box,
+                input,
+                &query.attributes,
+                attribute_compression,
+                AttributeEncodings::default(),
+            );
+            let error = error.lock().unwrap().take();
+            error.map_or(Ok(()), Err)
+        }
+        Layout::S2Cells { split_level } => {
+            let mut s2_splitter = S2Splitter::with_data_sink(
+                split_level,
+                Arc::clone(&data_sink),
+                Encoding::Plain,
+                OpenMode::Truncate,
+                attribute_compression,
+                AttributeEncodings::default(),
+            )?;
+            client.for_each_point_data(query, |batch| Ok(s2_splitter.write(&batch)?))?;
+            let meta = s2_splitter.get_meta()?.ok_or_else(no_points)?;
+            data_sink.write_meta(&meta.to_proto())?;
+            data_sink.finalize()
+        }
+    }
+}
+
+fn no_points() -> Error {
+    ErrorKind::InvalidInput("The query does not match any points.".to_string()).into()
+}
+
+fn bounding_box_and_num_points(
+    client: &PointCloudClient,
+    query: &PointQuery,
+) -> Result<(Aabb, usize)> {
+    let mut bounding_box: Option<Aabb> = None;
+    let mut num_points = 0;
+    client.for_each_point_data(query, |batch| {
+        for pos in &batch.position {
+            bounding_box
+                .get_or_insert_with(|| Aabb::new(*pos, *pos))
+                .grow(*pos);
+        }
+        num_points += batch.position.len();
+        Ok(())
+    })?;
+    Ok((bounding_box.ok_or_else(no_points)?, num_points))
+}
+
+/// The points of a query as input for the octree generation, which cannot handle errors. The
+/// iteration ends at the first error, which is kept for later.
+struct QueriedPoints {
+    batches: BlockingStream<PointDataStream>,
+    num_points: usize,
+    error: Arc<Mutex<Option<Error>>>,
+}
+
+impl QueriedPoints {
+    fn new(stream: PointDataStream, num_points: usize) -> Self {
+        Self {
+            batches: block_on_stream(stream),
+            num_points,
+            error: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl Iterator for QueriedPoints {
+    type Item = PointsBatch;
+
+    fn next(&mut self) -> Option<PointsBatch> {
+        match self.batches.next()? {
+            Ok(batch) => Some(batch),
+            Err(error) => {
+                *self.error.lock().unwrap() = Some(error);
+                None
+            }
+        }
+    }
+}
+
+impl NumberOfPoints for QueriedPoints {
+    fn num_points(&self) -> usize {
+        self.num_points
+    }
+}
+
+/// Passes everything on to the data sink, but neither writes the meta nor finalizes it once
+/// the query failed. The octree generation can't handle errors, so this keeps an incomplete
+/// octree from looking complete.
+struct MetaUnlessFailed {
+    data_sink: Arc<dyn DataSink>,
+    error: Arc<Mutex<Option<Error>>>,
+}
+
+impl MetaUnlessFailed {
+    fn failed(&self) -> bool {
+        self.error.lock().unwrap().is_some()
+    }
+}
+
+impl DataSink for MetaUnlessFailed {
+    fn open_blob(&self, file_name: &str, open_mode: OpenMode) -> Result<Box<dyn Write + Send>> {
+        self.data_sink.open_blob(file_name, open_mode)
+    }
+
+    fn can_append(&self) -> bool {
+        self.data_sink.can_append()
+    }
+
+    fn blob_size(&self, file_name: &str) -> Result<Option<u64>> {
+        self.data_sink.blob_size(file_name)
+    }
+
+    fn remove_blob(&self, file_name: &str) -> Result<()> {
+        self.data_sink.remove_blob(file_name)
+    }
+
+    fn finalize(&self) -> Result<()> {
+        if self.failed() {
+            return Ok(());
+        }
+        self.data_sink.finalize()
+    }
+
+    fn write_blob(&self, file_name: &str, data: &[u8]) -> Result<()> {
+        self.data_sink.write_blob(file_name, data)
+    }
+
+    fn write_meta(&self, meta: &proto::Meta) -> Result<()> {
+        if self.failed() {
+            return Ok(());
+        }
+        self.data_sink.write_meta(meta)
+    }
+}
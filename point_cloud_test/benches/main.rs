@@ -1,12 +1,25 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use point_cloud_client::PointCloudClient;
+use point_cloud_client::{PointCloudClient, PointCloudClientBuilder};
 use point_cloud_test_lib::queries::*;
 use point_cloud_test_lib::{
-    make_octree, make_s2_cells, setup_octree_client, setup_s2_client, Arguments, SyntheticData,
+    directory_size, get_s2_and_octree_path, make_octree, make_octree_with_compression,
+    make_s2_cells, make_s2_cells_with_compression, setup_octree_client, setup_s2_client, Arguments,
+    SyntheticData,
 };
-use point_viewer::iterator::{PointLocation, PointQuery};
+use point_viewer::data_provider::{DataProvider, OnDiskDataProvider};
+use point_viewer::iterator::{PointCloud, PointLocation, PointQuery};
+use point_viewer::octree::Octree;
+use point_viewer::read_write::Compression;
+use point_viewer::s2_cells::S2Cells;
+use std::path::Path;
 use tempdir::TempDir;
 
+const COMPRESSIONS: [(&str, Compression); 3] = [
+    ("uncompressed", Compression::Uncompressed),
+    ("zstd", Compression::Zstd),
+    ("lz4", Compression::Lz4),
+];
+
 fn bench_octree_building_multithreaded(c: &mut Criterion) {
     let mut args = Arguments::default();
     args.num_points = 100_000;
@@ -29,6 +42,92 @@ fn bench_s2_building_singlethreaded(c: &mut Criterion) {
     });
 }
 
+fn bench_octree_building_compressed(c: &mut Criterion) {
+    let mut args = Arguments::default();
+    args.num_points = 100_000;
+    for (name, compression) in &COMPRESSIONS[1..] {
+        c.bench_function(&format!("bench_octree_building_{}", name), |b| {
+            b.iter(|| {
+                let temp_dir = TempDir::new("octree").unwrap();
+                make_octree_with_compression(&args, temp_dir.path(), *compression);
+            })
+        });
+    }
+}
+
+// Compression trades query speed for size, see `Compression` for results. The sizes are printed,
+// since criterion only measures time.
+fn all_query_compressed(c: &mut Criterion) {
+    let args = Arguments::default();
+    for (name, compression) in &COMPRESSIONS {
+        let octree_dir = TempDir::new("octree").unwrap();
+        make_octree_with_compression(&args, octree_dir.path(), *compression);
+        let s2_dir = TempDir::new("s2").unwrap();
+        make_s2_cells_with_compression(&args, s2_dir.path(), *compression);
+        eprintln!(
+            "Size with {}: octree {} bytes, S2 cells {} bytes",
+            name,
+            directory_size(octree_dir.path()),
+            directory_size(s2_dir.path())
+        );
+        let query = PointQuery {
+            attributes: vec!["color"],
+            ..Default::default()
+        };
+        for (point_cloud, dir) in &[("octree", &octree_dir), ("s2", &s2_dir)] {
+            let location = dir.path().to_str().unwrap().to_owned();
+            let client = PointCloudClientBuilder::new(&[location]).build().unwrap();
+            bench_query(
+                &format!("all_query_{}_{}", point_cloud, name),
+                &client,
+                &query,
+                c,
+            );
+        }
+    }
+}
+
+/// Hides that the wrapped data provider can map blobs into memory, so that nodes are streamed.
+fn data_providers(directory: &Path) -> Vec<(&'static str, Box<dyn DataProvider>)> {
+    let on_disk = || OnDiskDataProvider::new(directory);
+    vec![
+        ("mmap", Box::new(on_disk().memory_map(true))),
+        ("streaming", Box::new(on_disk())),
+    ]
+}
+
+fn bench_read_all_nodes<C: PointCloud>(name: &str, point_cloud: &C, c: &mut Criterion) {
+    let batch_size = Arguments::default().batch_size;
+    let node_ids = point_cloud.nodes_in_location(&PointLocation::AllPoints, None);
+    c.bench_function(name, |b| {
+        b.iter(|| {
+            for node_id in &node_ids {
+                let node_iterator = point_cloud
+                    .points_in_node(&["color"], *node_id, batch_size)
+                    .unwrap();
+                node_iterator.for_each(|batch| {
+                    black_box(batch);
+                });
+            }
+        })
+    });
+}
+
+// Compares reading the nodes from memory-mapped files to streaming them from the files, without
+// the query overhead of the client. With the files in the page cache, mmap reads the octree about
+// 3.4x and the S2 cells about 1.9x as fast.
+fn read_all_nodes(c: &mut Criterion) {
+    let (s2_path, octree_path, _) = get_s2_and_octree_path(&Arguments::default());
+    for (name, data_provider) in data_providers(&octree_path) {
+        let octree = Octree::from_data_provider(data_provider).unwrap();
+        bench_read_all_nodes(&format!("read_all_nodes_octree_{}", name), &octree, c);
+    }
+    for (name, data_provider) in data_providers(&s2_path) {
+        let s2_cells = S2Cells::from_data_provider(data_provider).unwrap();
+        bench_read_all_nodes(&format!("read_all_nodes_s2_{}", name), &s2_cells, c);
+    }
+}
+
 fn all_query_octree(b: &mut Criterion) {
     run_bench(
         "all_query_octree",
@@ -76,6 +175,36 @@ fn obb_query_s2(b: &mut Criterion) {
     run_bench("obb_query_s2", setup_s2_client, get_obb_query, b)
 }
 
+fn rotated_obb_query_octree(b: &mut Criterion) {
+    run_bench(
+        "rotated_obb_query_octree",
+        setup_octree_client,
+        get_rotated_obb_query,
+        b,
+    )
+}
+
+// The exact intersection tests select fewer S2 cells than a lat/lng rectangle around the corners
+// of the OBB did. The numbers are printed, since criterion only measures time.
+fn rotated_obb_query_s2(b: &mut Criterion) {
+    let (s2_path, _, data) = get_s2_and_octree_path(&Arguments::default());
+    let s2_cells = S2Cells::from_data_provider(Box::new(OnDiskDataProvider::new(s2_path))).unwrap();
+    let obb = get_rotated_obb(data);
+    eprintln!(
+        "Rotated OBB query: {} S2 cells selected, {} intersecting a lat/lng rectangle",
+        s2_cells
+            .nodes_in_location(&PointLocation::Obb(obb.clone()), None)
+            .len(),
+        num_cells_in_rect_bound(&s2_cells, &obb)
+    );
+    run_bench(
+        "rotated_obb_query_s2",
+        setup_s2_client,
+        get_rotated_obb_query,
+        b,
+    )
+}
+
 fn cell_union_query_octree(b: &mut Criterion) {
     run_bench(
         "cell_union_query_octree",
@@ -98,6 +227,9 @@ criterion_group!(
     benches,
     bench_octree_building_multithreaded,
     bench_s2_building_singlethreaded,
+    bench_octree_building_compressed,
+    all_query_compressed,
+    read_all_nodes,
     all_query_octree,
     all_query_s2,
     box_query_octree,
@@ -106,6 +238,8 @@ criterion_group!(
     frustum_query_s2,
     obb_query_octree,
     obb_query_s2,
+    rotated_obb_query_octree,
+    rotated_obb_query_s2,
     cell_union_query_octree,
     cell_union_query_s2,
 );
@@ -124,9 +258,13 @@ where
         location: gen_location(data),
         ..Default::default()
     };
+    bench_query(name, &client, &query, c);
+}
+
+fn bench_query(name: &str, client: &PointCloudClient, query: &PointQuery, c: &mut Criterion) {
     c.bench_function(name, |b| {
         b.iter(|| {
-            let res = client.for_each_point_data(&query, |batch| {
+            let res = client.for_each_point_data(query, |batch| {
                 black_box(batch);
                 Ok(())
             });
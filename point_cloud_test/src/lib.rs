@@ -1,16 +1,15 @@
 use point_cloud_client::{PointCloudClient, PointCloudClientBuilder};
 /// This module has functions to generate synthetic point clouds in a temp dir
 /// and provides queries on these synthetic point clouds.
-use point_viewer::data_provider::OnDiskDataProvider;
-use point_viewer::octree::{build_octree, Octree};
-use point_viewer::read_write::{Encoding, NodeWriter, OpenMode, RawNodeWriter, S2Splitter};
+use point_viewer::data_provider::{DataSink, OnDiskDataProvider};
+use point_viewer::octree::{build_octree_into, Octree};
+use point_viewer::read_write::{
+    AttributeCompression, AttributeEncodings, Compression, Encoding, NodeWriter, OpenMode,
+    S2Splitter,
+};
 use point_viewer::s2_cells::S2Cells;
-use point_viewer::META_FILENAME;
-use protobuf::Message;
-use std::fs::File;
-use std::io::BufWriter;
 use std::path::{Path, PathBuf};
-use std::sync::Once;
+use std::sync::{Arc, Once};
 use tempdir::TempDir;
 
 pub mod synthetic_data;
@@ -18,6 +17,12 @@ pub use synthetic_data::{Batched, SyntheticData};
 
 pub mod queries;
 
+pub mod mock_s3_server;
+pub use mock_s3_server::MockS3Server;
+
+pub mod static_file_server;
+pub use static_file_server::StaticFileServer;
+
 pub const S2_LEVEL: u64 = 20;
 
 #[derive(Clone, Debug, PartialEq)]
@@ -53,25 +58,64 @@ impl Default for Arguments {
 }
 
 pub fn make_octree(args: &Arguments, dir: &Path) {
+    make_octree_with_compression(args, dir, Compression::Uncompressed)
+}
+
+/// Builds the octree with positions and colors compressed as given.
+pub fn make_octree_with_compression(args: &Arguments, dir: &Path, compression: Compression) {
     let points_oct = SyntheticData::new(args.width, args.height, args.num_points, args.seed);
     let bbox = points_oct.bbox();
     let batches_oct = Batched::new(points_oct, args.batch_size);
 
-    build_octree(dir, args.resolution, bbox, batches_oct, &["color"]);
+    let data_sink = Arc::new(OnDiskDataProvider::new(dir));
+    build_octree_into(
+        data_sink,
+        dir,
+        args.resolution,
+        bbox,
+        batches_oct,
+        &["color"],
+        AttributeCompression::uniform(&["position", "color"], compression),
+        AttributeEncodings::default(),
+    );
 }
 
 pub fn make_s2_cells(args: &Arguments, dir: &Path) {
+    make_s2_cells_with_compression(args, dir, Compression::Uncompressed)
+}
+
+/// Builds the S2 cells with positions and colors compressed as given.
+pub fn make_s2_cells_with_compression(args: &Arguments, dir: &Path, compression: Compression) {
     let points_s2 = SyntheticData::new(args.width, args.height, args.num_points, args.seed);
-    let mut s2_writer: S2Splitter<RawNodeWriter> =
-        S2Splitter::with_split_level(S2_LEVEL, dir, Encoding::Plain, OpenMode::Truncate);
+    let data_sink = Arc::new(OnDiskDataProvider::new(dir));
+    let mut s2_writer = S2Splitter::with_data_sink(
+        S2_LEVEL,
+        data_sink.clone(),
+        Encoding::Plain,
+        OpenMode::Truncate,
+        AttributeCompression::uniform(&["position", "color"], compression),
+        AttributeEncodings::default(),
+    )
+    .expect("Creating the S2 writer failed");
     Batched::new(points_s2, args.batch_size)
         .try_for_each(|batch| s2_writer.write(&batch))
         .expect("Writing failed");
     // An S2 writer that has not written any points cannot produce a meta proto,
     // but in this case we know it did write points.
-    let meta = s2_writer.get_meta().unwrap().to_proto();
-    let mut meta_writer = BufWriter::new(File::create(dir.join(META_FILENAME)).unwrap());
-    meta.write_to_writer(&mut meta_writer).unwrap();
+    let meta = s2_writer
+        .get_meta()
+        .expect("Writing failed")
+        .unwrap()
+        .to_proto();
+    data_sink.write_meta(&meta).unwrap();
+}
+
+/// The total size of the files of a point cloud directory, in bytes.
+pub fn directory_size(dir: &Path) -> u64 {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum()
 }
 
 static INIT: Once = Once::new();
@@ -102,13 +146,9 @@ pub fn get_s2_and_octree_path(args: &Arguments) -> (PathBuf, PathBuf, SyntheticD
 
 pub fn setup_pointcloud(args: &Arguments) -> (S2Cells, Octree, SyntheticData) {
     let (s2_path_buf, oct_path_buf, data) = get_s2_and_octree_path(args);
-    let s2_data_provider = OnDiskDataProvider {
-        directory: s2_path_buf,
-    };
+    let s2_data_provider = OnDiskDataProvider::new(s2_path_buf);
     let s2 = S2Cells::from_data_provider(Box::new(s2_data_provider)).unwrap();
-    let oct_data_provider = OnDiskDataProvider {
-        directory: oct_path_buf,
-    };
+    let oct_data_provider = OnDiskDataProvider::new(oct_path_buf);
     let oct = Octree::from_data_provider(Box::new(oct_data_provider)).unwrap();
     (s2, oct, data)
 }
@@ -0,0 +1,126 @@
+//! An in-memory S3 server supporting path-style GET (including ranges), PUT and DELETE, to test
+//! the `S3DataProvider` and the `S3Writer`.
+
+use crate::static_file_server::{write_header, write_response, Request};
+use std::collections::HashMap;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+type Objects = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+pub struct MockS3Server {
+    endpoint: String,
+    objects: Objects,
+    num_range_requests: Arc<AtomicUsize>,
+}
+
+impl MockS3Server {
+    /// Serves on a free local port until the process exits. Requests that are not signed with
+    /// the access key id `access_key_id` are rejected.
+    pub fn new(access_key_id: &str) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let objects = Objects::default();
+        let num_range_requests = Arc::new(AtomicUsize::new(0));
+        let credential = format!("AWS4-HMAC-SHA256 Credential={}/", access_key_id);
+        let (thread_objects, thread_num_range_requests) =
+            (Arc::clone(&objects), Arc::clone(&num_range_requests));
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let objects = Arc::clone(&thread_objects);
+                let num_range_requests = Arc::clone(&thread_num_range_requests);
+                let credential = credential.clone();
+                std::thread::spawn(move || {
+                    handle_connection(stream.unwrap(), &objects, &num_range_requests, &credential)
+                });
+            }
+        });
+        Self {
+            endpoint,
+            objects,
+            num_range_requests,
+        }
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// The object stored under `/<bucket>/<key>`.
+    pub fn object(&self, path: &str) -> Option<Vec<u8>> {
+        self.objects.lock().unwrap().get(path).cloned()
+    }
+
+    pub fn num_objects(&self) -> usize {
+        self.objects.lock().unwrap().len()
+    }
+
+    pub fn num_range_requests(&self) -> usize {
+        self.num_range_requests.load(Ordering::SeqCst)
+    }
+}
+
+fn handle_connection(
+    mut stream: TcpStream,
+    objects: &Objects,
+    num_range_requests: &AtomicUsize,
+    credential: &str,
+) {
+    let request = match Request::read(&stream) {
+        Ok(request) => request,
+        Err(_) => return,
+    };
+    let signed = request
+        .headers
+        .get("authorization")
+        .map_or(false, |a| a.starts_with(credential))
+        && request.headers.contains_key("x-amz-date");
+    if !signed {
+        return write_response(stream, "403 Forbidden", b"AccessDenied");
+    }
+    let mut objects = objects.lock().unwrap();
+    match request.method.as_str() {
+        "PUT" => {
+            objects.insert(request.path, request.body);
+            write_response(stream, "200 OK", &[]);
+        }
+        "DELETE" => {
+            objects.remove(&request.path);
+            write_response(stream, "204 No Content", &[]);
+        }
+        "GET" => {
+            let object = match objects.get(&request.path) {
+                Some(object) => object,
+                None => return write_response(stream, "404 Not Found", b"NoSuchKey"),
+            };
+            let range = match request.headers.get("range") {
+                Some(range) => range,
+                None => return write_response(stream, "200 OK", object),
+            };
+            num_range_requests.fetch_add(1, Ordering::SeqCst);
+            let (first, last) = parse_range(range);
+            if first >= object.len() {
+                return write_response(stream, "416 Range Not Satisfiable", &[]);
+            }
+            let end = (last + 1).min(object.len());
+            write_response(stream, "206 Partial Content", &object[first..end]);
+        }
+        "HEAD" => {
+            let _ = match objects.get(&request.path) {
+                Some(object) => write_header(&mut stream, "200 OK", object.len()),
+                None => write_header(&mut stream, "404 Not Found", 0),
+            };
+        }
+        _ => write_response(stream, "405 Method Not Allowed", &[]),
+    }
+}
+
+/// Parses `bytes=<first>-<last>`.
+fn parse_range(range: &str) -> (usize, usize) {
+    let mut bounds = range
+        .trim_start_matches("bytes=")
+        .split('-')
+        .map(|b| b.parse().unwrap());
+    (bounds.next().unwrap(), bounds.next().unwrap())
+}
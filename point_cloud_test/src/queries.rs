@@ -1,12 +1,15 @@
 // Some synthetic queries for synthetic data. These are just examples, more can be added.
 use crate::synthetic_data::SyntheticData;
 use crate::S2_LEVEL;
-use nalgebra::{Perspective3, Point3, Vector2, Vector3};
+use nalgebra::{Isometry3, Perspective3, Point3, Vector2, Vector3};
 use nav_types::{ECEF, WGS84};
 use point_viewer::geometry::{Aabb, CellUnion, Frustum, Obb, WebMercatorRect};
 use point_viewer::iterator::PointLocation;
-use point_viewer::math::{FromPoint3, WebMercatorCoord};
+use point_viewer::math::{ConvexPolyhedron, FromPoint3, WebMercatorCoord};
+use point_viewer::s2_cells::S2Cells;
+use s2::cell::Cell;
 use s2::cellid::CellID;
+use s2::region::Region;
 
 pub fn get_aabb(data: SyntheticData) -> Aabb {
     let min_corner = data.bbox().min() + 0.2 * data.bbox().diag();
@@ -35,6 +38,20 @@ pub fn get_obb_query(data: SyntheticData) -> PointLocation {
     PointLocation::Obb(get_obb(data))
 }
 
+// A long and thin OBB along the diagonal of the point cloud, which is a poor fit for a lat/lng
+// rectangle.
+pub fn get_rotated_obb(data: SyntheticData) -> Obb {
+    let rotation = Isometry3::rotation(Vector3::z() * std::f64::consts::FRAC_PI_4);
+    Obb::new(
+        data.ecef_from_local() * rotation,
+        Vector3::new(1.2 * data.half_width, 2.0, data.half_height),
+    )
+}
+
+pub fn get_rotated_obb_query(data: SyntheticData) -> PointLocation {
+    PointLocation::Obb(get_rotated_obb(data))
+}
+
 pub fn get_frustum(data: SyntheticData) -> Frustum {
     let ecef_from_local = *data.ecef_from_local();
     let perspective = Perspective3::new(
@@ -70,3 +87,22 @@ pub fn get_web_mercator_rect(data: SyntheticData) -> WebMercatorRect {
 pub fn get_web_mercator_rect_query(data: SyntheticData) -> PointLocation {
     PointLocation::WebMercatorRect(get_web_mercator_rect(data))
 }
+
+/// The number of cells that a lat/lng rectangle around the corners of the polyhedron intersects,
+/// which is how cells used to be selected.
+pub fn num_cells_in_rect_bound(s2: &S2Cells, polyhedron: &impl ConvexPolyhedron) -> usize {
+    let corner_cells = polyhedron
+        .compute_corners()
+        .iter()
+        .map(|p| CellID::from_point(p))
+        .collect();
+    let mut cell_union = CellUnion(corner_cells);
+    cell_union.normalize();
+    let rect = cell_union.rect_bound();
+    s2.to_meta_proto()
+        .get_s2()
+        .get_cells()
+        .iter()
+        .filter(|cell| rect.intersects_cell(&Cell::from(CellID(cell.id))))
+        .count()
+}
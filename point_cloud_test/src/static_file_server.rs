@@ -0,0 +1,124 @@
+//! A minimal HTTP server for the files of a directory, to test the `HttpDataProvider`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+pub struct StaticFileServer {
+    url: String,
+    num_requests: Arc<AtomicUsize>,
+}
+
+impl StaticFileServer {
+    /// Serves the directory on a free local port until the process exits. Every
+    /// `failure_period`-th request is answered with "503 Service Unavailable", to test retries;
+    /// 0 means that no request fails.
+    pub fn new(directory: &Path, failure_period: usize) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let num_requests = Arc::new(AtomicUsize::new(0));
+        let directory = directory.to_owned();
+        let counter = Arc::clone(&num_requests);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let directory = directory.clone();
+                let request_index = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                let fail = failure_period > 0 && request_index % failure_period == 0;
+                std::thread::spawn(move || handle_connection(stream, &directory, fail));
+            }
+        });
+        Self { url, num_requests }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn num_requests(&self) -> usize {
+        self.num_requests.load(Ordering::SeqCst)
+    }
+}
+
+fn handle_connection(stream: TcpStream, directory: &Path, fail: bool) {
+    let request = match Request::read(&stream) {
+        Ok(request) => request,
+        Err(_) => return,
+    };
+    let (status, body) = if fail {
+        ("503 Service Unavailable", Vec::new())
+    } else {
+        match fs::read(directory.join(request.path.trim_start_matches('/'))) {
+            Ok(body) => ("200 OK", body),
+            Err(_) => ("404 Not Found", Vec::new()),
+        }
+    };
+    write_response(stream, status, &body);
+}
+
+/// An HTTP/1.1 request, of which only the parts needed by the test servers are parsed.
+pub(crate) struct Request {
+    pub method: String,
+    pub path: String,
+    /// With lowercase names.
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn read(stream: &TcpStream) -> io::Result<Self> {
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let path = parts.next().unwrap_or("/").to_string();
+        let mut headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? <= 2 {
+                break;
+            }
+            if let Some(colon) = line.find(':') {
+                headers.insert(
+                    line[..colon].trim().to_lowercase(),
+                    line[colon + 1..].trim().to_string(),
+                );
+            }
+        }
+        let content_length = headers
+            .get("content-length")
+            .and_then(|l| l.parse().ok())
+            .unwrap_or(0);
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+        Ok(Self {
+            method,
+            path,
+            headers,
+            body,
+        })
+    }
+}
+
+/// Writes the response and closes the connection.
+pub(crate) fn write_response(mut stream: TcpStream, status: &str, body: &[u8]) {
+    let _ = write_header(&mut stream, status, body.len()).and_then(|_| stream.write_all(body));
+}
+
+/// Writes only the header, e.g. as the response to a HEAD request.
+pub(crate) fn write_header(
+    stream: &mut TcpStream,
+    status: &str,
+    content_length: usize,
+) -> std::io::Result<()> {
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status, content_length
+    );
+    stream.write_all(header.as_bytes())
+}
@@ -1,11 +1,34 @@
 use nalgebra::{Point3, Vector3};
 use num_integer::div_ceil;
+use point_cloud_client::reindex::{reindex_point_cloud, Layout};
+use point_cloud_client::PointCloudClientBuilder;
 use point_cloud_test_lib::queries::*;
-use point_cloud_test_lib::{setup_pointcloud, Arguments, SyntheticData};
+use point_cloud_test_lib::{
+    get_s2_and_octree_path, make_octree, make_octree_with_compression,
+    make_s2_cells_with_compression, setup_octree_client, setup_pointcloud, Arguments, Batched,
+    MockS3Server, StaticFileServer, SyntheticData, S2_LEVEL,
+};
+use point_viewer::data_provider::{
+    pack_directory, ChecksummingDataSink, DataProvider, DataProviderFactory, DataSink,
+    HttpDataProvider, MemoryDataProvider, OnDiskDataProvider, PackedWriter, S3Client, S3Config,
+    S3DataProvider, S3Location, S3Writer, PACKED_EXTENSION,
+};
+use point_viewer::errors::ErrorKind;
+use point_viewer::filter::AttributeFilter;
 use point_viewer::iterator::PointCloud;
 use point_viewer::iterator::{PointLocation, PointQuery};
 use point_viewer::math::{sat, ConvexPolyhedron, PointCulling};
+use point_viewer::octree::{build_octree_into, NodeId, Octree};
+use point_viewer::read_write::{
+    AttributeCompression, AttributeEncoding, AttributeEncodings, Compression, Encoding, NodeWriter,
+    OpenMode, RawNodeWriter, S2Splitter,
+};
+use point_viewer::s2_cells::{build_pyramid_into, S2Cells, S2Meta};
+use point_viewer::META_FILENAME;
 use std::cmp::Ordering;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
 
 #[test]
 fn num_points_in_octree_meta() {
@@ -32,6 +55,381 @@ fn num_points_in_s2_meta() {
     assert_eq!(num_points, Arguments::default().num_points as u64);
 }
 
+#[test]
+fn mixed_octree_and_s2_client() {
+    let args = Arguments::default();
+    let (s2_path, oct_path, data) = get_s2_and_octree_path(&args);
+    let locations = [
+        oct_path.to_str().unwrap().to_owned(),
+        s2_path.to_str().unwrap().to_owned(),
+    ];
+    let client = PointCloudClientBuilder::new(&locations).build().unwrap();
+    let query = PointQuery {
+        attributes: vec!["color"],
+        location: get_aabb_query(data),
+        ..Default::default()
+    };
+    let mut num_points = 0;
+    client
+        .for_each_point_data(&query, |batch| {
+            num_points += batch.position.len();
+            Ok(())
+        })
+        .unwrap();
+    let (s2, oct, _) = setup_pointcloud(&args);
+    let num_points_oct = query_and_sort(&oct, &query, args.batch_size).len();
+    let num_points_s2 = query_and_sort(&s2, &query, args.batch_size).len();
+    assert!(num_points_oct > 0 && num_points_s2 > 0);
+    assert_eq!(num_points, num_points_oct + num_points_s2);
+}
+
+#[test]
+fn stream_point_data() {
+    let args = Arguments::default();
+    let (client, _) = setup_octree_client(&args);
+    let query = PointQuery::default();
+    let num_points: usize = futures::executor::block_on_stream(client.stream_point_data(&query))
+        .map(|batch| batch.unwrap().position.len())
+        .sum();
+    assert_eq!(num_points, args.num_points);
+
+    // Dropping the stream early must not block.
+    let mut stream = futures::executor::block_on_stream(client.stream_point_data(&query));
+    assert!(stream.next().unwrap().is_ok());
+    drop(stream);
+
+    // Cancelling the query's token ends the stream without an error.
+    let query = PointQuery::default();
+    let mut stream = futures::executor::block_on_stream(client.stream_point_data(&query));
+    assert!(stream.next().unwrap().is_ok());
+    query.cancellation_token.cancel();
+    assert!(stream.all(|batch| batch.is_ok()));
+}
+
+#[test]
+fn http_data_provider() {
+    let args = Arguments::default();
+    let (s2_path, oct_path, _) = get_s2_and_octree_path(&args);
+    // Every seventh request fails and has to be retried.
+    let oct_server = StaticFileServer::new(&oct_path, 7);
+    let s2_server = StaticFileServer::new(&s2_path, 7);
+    let locations = [
+        oct_server.url().to_string(),
+        format!("cache+{}", s2_server.url()),
+    ];
+    let client = PointCloudClientBuilder::new(&locations).build().unwrap();
+    let query = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    let mut num_points = 0;
+    client
+        .for_each_point_data(&query, |batch| {
+            num_points += batch.position.len();
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(num_points, 2 * args.num_points);
+    assert!(oct_server.num_requests() >= 7 && s2_server.num_requests() >= 7);
+
+    let data_provider = HttpDataProvider::new(oct_server.url());
+    match data_provider.data("does_not_exist", &["color"]) {
+        Err(e) => assert!(matches!(e.kind(), ErrorKind::NodeNotFound)),
+        Ok(_) => panic!("The node should not exist."),
+    }
+}
+
+#[test]
+fn s3_data_provider() {
+    let args = Arguments::default();
+    let (_, oct_path, _) = get_s2_and_octree_path(&args);
+    let server = MockS3Server::new("test_key");
+    let config = S3Config {
+        endpoint: server.endpoint().to_string(),
+        region: "us-east-1".to_string(),
+        access_key_id: "test_key".to_string(),
+        secret_access_key: "test_secret".to_string(),
+        session_token: None,
+    };
+    let location: S3Location = "s3://bucket/octree".parse().unwrap();
+    S3Writer::new(S3Client::new(config.clone()), location.clone())
+        .upload_directory(&oct_path)
+        .unwrap();
+    assert_eq!(
+        server.object("/bucket/octree/meta.pb"),
+        Some(std::fs::read(oct_path.join(META_FILENAME)).unwrap())
+    );
+
+    // Small chunks, so that nodes are read with several ranged requests.
+    let data_provider = S3DataProvider::new(S3Client::new(config), location).chunk_size(10_000);
+    let octree = Octree::from_data_provider(Box::new(data_provider)).unwrap();
+    let query = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    assert_eq!(
+        query_and_sort(&octree, &query, args.batch_size).len(),
+        args.num_points
+    );
+    let num_nodes = octree.to_meta_proto().get_octree().get_nodes().len();
+    assert!(server.num_range_requests() > 2 * num_nodes);
+
+    // The factory configures the client from the environment.
+    std::env::set_var("S3_ENDPOINT", server.endpoint());
+    std::env::set_var("AWS_ACCESS_KEY_ID", "test_key");
+    std::env::set_var("AWS_SECRET_ACCESS_KEY", "test_secret");
+    let client = PointCloudClientBuilder::new(&["s3://bucket/octree".to_string()])
+        .build()
+        .unwrap();
+    let mut num_points = 0;
+    client
+        .for_each_point_data(&query, |batch| {
+            num_points += batch.position.len();
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(num_points, args.num_points);
+}
+
+#[test]
+fn packed_point_clouds() {
+    let args = Arguments::default();
+    let (s2_path, oct_path, _) = get_s2_and_octree_path(&args);
+    let packed_dir = tempdir::TempDir::new("packed").unwrap();
+    let locations: Vec<String> = [oct_path, s2_path]
+        .iter()
+        .enumerate()
+        .map(|(i, path)| {
+            let packed_file = packed_dir
+                .path()
+                .join(i.to_string())
+                .with_extension(PACKED_EXTENSION);
+            pack_directory(path, &packed_file).unwrap();
+            packed_file.to_str().unwrap().to_string()
+        })
+        .collect();
+    let client = PointCloudClientBuilder::new(&locations).build().unwrap();
+    let query = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    let mut num_points = 0;
+    client
+        .for_each_point_data(&query, |batch| {
+            num_points += batch.position.len();
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(num_points, 2 * args.num_points);
+}
+
+#[test]
+fn memory_data_provider() {
+    let args = Arguments {
+        num_points: 100_000,
+        ..Default::default()
+    };
+    let data_provider = MemoryDataProvider::new();
+    data_provider.register("memory_data_provider_test");
+    let mut s2_writer = S2Splitter::with_data_sink(
+        S2_LEVEL,
+        Arc::new(data_provider.clone()),
+        Encoding::Plain,
+        OpenMode::Truncate,
+        AttributeCompression::default(),
+        AttributeEncodings::default(),
+    )
+    .unwrap();
+    let points = SyntheticData::new(args.width, args.height, args.num_points, args.seed);
+    Batched::new(points, args.batch_size)
+        .try_for_each(|batch| s2_writer.write(&batch))
+        .unwrap();
+    let meta = s2_writer.get_meta().unwrap().unwrap().to_proto();
+    data_provider.write_meta(&meta).unwrap();
+
+    let client = PointCloudClientBuilder::new(&["memory://memory_data_provider_test".to_string()])
+        .build()
+        .unwrap();
+    let query = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    let mut num_points = 0;
+    client
+        .for_each_point_data(&query, |batch| {
+            num_points += batch.position.len();
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(num_points, args.num_points);
+    MemoryDataProvider::unregister("memory_data_provider_test");
+}
+
+#[test]
+fn buffered_s2_splitter() {
+    let args = Arguments::default();
+    let (s2, _, _) = setup_pointcloud(&args);
+    let data_provider = MemoryDataProvider::new();
+    let mut s2_writer = S2Splitter::with_data_sink(
+        S2_LEVEL,
+        Arc::new(data_provider.clone()),
+        Encoding::Plain,
+        OpenMode::Truncate,
+        AttributeCompression::default(),
+        AttributeEncodings::default(),
+    )
+    .unwrap()
+    .with_max_num_writers(2)
+    .with_max_num_buffered_points(3 * args.batch_size);
+    let points = SyntheticData::new(args.width, args.height, args.num_points, args.seed);
+    Batched::new(points, args.batch_size)
+        .try_for_each(|batch| s2_writer.write(&batch))
+        .unwrap();
+    // The last buffered points are written by `get_meta`.
+    let meta = s2_writer.get_meta().unwrap().unwrap().to_proto();
+    data_provider.write_meta(&meta).unwrap();
+
+    let buffered = S2Cells::from_data_provider(Box::new(data_provider)).unwrap();
+    let all_points = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    assert_eq!(
+        query_and_sort(&buffered, &all_points, args.batch_size),
+        query_and_sort(&s2, &all_points, args.batch_size)
+    );
+}
+
+#[test]
+fn s2_splitter_rejects_delta_encoding() {
+    let encodings = AttributeEncodings::default().with("intensity", AttributeEncoding::delta());
+    assert!(S2Splitter::with_data_sink(
+        S2_LEVEL,
+        Arc::new(MemoryDataProvider::new()),
+        Encoding::Plain,
+        OpenMode::Truncate,
+        AttributeCompression::default(),
+        encodings,
+    )
+    .is_err());
+}
+
+#[test]
+fn append_to_s2_cells() {
+    let args = Arguments::default();
+    let (s2, _, _) = setup_pointcloud(&args);
+    let data_provider = MemoryDataProvider::new();
+    let mut batches = Batched::new(
+        SyntheticData::new(args.width, args.height, args.num_points, args.seed),
+        args.batch_size,
+    );
+    let num_batches = div_ceil(args.num_points, args.batch_size);
+    let mut s2_writer = S2Splitter::with_data_sink(
+        S2_LEVEL,
+        Arc::new(data_provider.clone()),
+        Encoding::Plain,
+        OpenMode::Truncate,
+        AttributeCompression::default(),
+        AttributeEncodings::default(),
+    )
+    .unwrap();
+    batches
+        .by_ref()
+        .take(num_batches / 2)
+        .try_for_each(|batch| s2_writer.write(&batch))
+        .unwrap();
+    data_provider
+        .write_meta(&s2_writer.get_meta().unwrap().unwrap().to_proto())
+        .unwrap();
+
+    let meta = S2Meta::from_data_provider(&data_provider).unwrap();
+    let mut s2_writer =
+        S2Splitter::appending_to_data_sink(Arc::new(data_provider.clone()), meta).unwrap();
+    batches
+        .try_for_each(|batch| s2_writer.write(&batch))
+        .unwrap();
+    data_provider
+        .write_meta(&s2_writer.get_meta().unwrap().unwrap().to_proto())
+        .unwrap();
+
+    let appended = S2Cells::from_data_provider(Box::new(data_provider)).unwrap();
+    assert_eq!(
+        appended.to_meta_proto().get_s2().get_cells().len(),
+        s2.to_meta_proto().get_s2().get_cells().len()
+    );
+    let all_points = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    assert_eq!(
+        query_and_sort(&appended, &all_points, args.batch_size),
+        query_and_sort(&s2, &all_points, args.batch_size)
+    );
+}
+
+#[test]
+fn s2_pyramid() {
+    let args = Arguments::default();
+    let (s2, _, _) = setup_pointcloud(&args);
+    let data_provider = MemoryDataProvider::new();
+    let mut s2_writer = S2Splitter::with_data_sink(
+        S2_LEVEL,
+        Arc::new(data_provider.clone()),
+        Encoding::Plain,
+        OpenMode::Truncate,
+        AttributeCompression::default(),
+        AttributeEncodings::default(),
+    )
+    .unwrap();
+    let points = SyntheticData::new(args.width, args.height, args.num_points, args.seed);
+    Batched::new(points, args.batch_size)
+        .try_for_each(|batch| s2_writer.write(&batch))
+        .unwrap();
+    data_provider
+        .write_meta(&s2_writer.get_meta().unwrap().unwrap().to_proto())
+        .unwrap();
+    let meta = build_pyramid_into(
+        &data_provider,
+        Arc::new(data_provider.clone()),
+        S2_LEVEL - 3,
+    )
+    .unwrap();
+    assert_eq!(
+        meta.levels(),
+        &[S2_LEVEL - 3, S2_LEVEL - 2, S2_LEVEL - 1, S2_LEVEL][..]
+    );
+    // New points would be missing from the coarser cells.
+    let pyramid_meta = S2Meta::from_data_provider(&data_provider).unwrap();
+    assert!(
+        S2Splitter::appending_to_data_sink(Arc::new(data_provider.clone()), pyramid_meta).is_err()
+    );
+
+    // All levels together still hold every point exactly once.
+    let pyramid = S2Cells::from_data_provider(Box::new(data_provider)).unwrap();
+    let all_points = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    assert_eq!(
+        query_and_sort(&pyramid, &all_points, args.batch_size),
+        query_and_sort(&s2, &all_points, args.batch_size)
+    );
+
+    // The coarsest level holds a subsample of about every 64th point.
+    let coarsest = PointQuery {
+        attributes: vec!["color"],
+        max_level: Some(S2_LEVEL - 3),
+        ..Default::default()
+    };
+    assert!(pyramid
+        .nodes_in_location(&coarsest.location, coarsest.max_level)
+        .iter()
+        .all(|cell_id| cell_id.level() == S2_LEVEL - 3));
+    let num_coarsest_points = query_and_sort(&pyramid, &coarsest, args.batch_size).len();
+    assert!(num_coarsest_points >= args.num_points / 64);
+    assert!(num_coarsest_points < args.num_points / 16);
+}
+
 #[test]
 fn check_all_query_equality() {
     check_equality(|_| PointLocation::AllPoints)
@@ -42,6 +440,129 @@ fn check_box_query_equality() {
     check_equality(get_aabb_query)
 }
 
+#[test]
+fn octree_into_data_sinks() {
+    let args = Arguments {
+        num_points: 100_000,
+        ..Default::default()
+    };
+    let build_into = |data_sink: Arc<dyn DataSink>| {
+        let points = SyntheticData::new(args.width, args.height, args.num_points, args.seed);
+        let bbox = points.bbox();
+        let working_dir = tempdir::TempDir::new("working").unwrap();
+        let batches = Batched::new(points, args.batch_size);
+        build_octree_into(
+            data_sink,
+            working_dir.path(),
+            args.resolution,
+            bbox,
+            batches,
+            &["color"],
+            AttributeCompression::default(),
+            AttributeEncodings::default(),
+        );
+        // All nodes were moved into the data sink.
+        assert_eq!(std::fs::read_dir(working_dir.path()).unwrap().count(), 0);
+    };
+    let count_points = |location: String| {
+        let client = PointCloudClientBuilder::new(&[location]).build().unwrap();
+        let query = PointQuery {
+            attributes: vec!["color"],
+            ..Default::default()
+        };
+        let mut num_points = 0;
+        client
+            .for_each_point_data(&query, |batch| {
+                num_points += batch.position.len();
+                Ok(())
+            })
+            .unwrap();
+        num_points
+    };
+
+    let packed_dir = tempdir::TempDir::new("packed").unwrap();
+    let packed_file = packed_dir.path().join("octree.pvpack");
+    build_into(Arc::new(PackedWriter::new(&packed_file)));
+    assert_eq!(
+        count_points(packed_file.to_str().unwrap().to_string()),
+        args.num_points
+    );
+
+    let server = MockS3Server::new("test_key");
+    let config = S3Config {
+        endpoint: server.endpoint().to_string(),
+        region: "us-east-1".to_string(),
+        access_key_id: "test_key".to_string(),
+        secret_access_key: "test_secret".to_string(),
+        session_token: None,
+    };
+    let location: S3Location = "s3://bucket/octree".parse().unwrap();
+    build_into(Arc::new(S3Writer::new(
+        S3Client::new(config.clone()),
+        location.clone(),
+    )));
+    let s3_writer = S3Writer::new(S3Client::new(config.clone()), location.clone());
+    assert_eq!(
+        s3_writer.blob_size(META_FILENAME).unwrap(),
+        server
+            .object("/bucket/octree/meta.pb")
+            .map(|meta| meta.len() as u64)
+    );
+    assert_eq!(s3_writer.blob_size("missing.xyz").unwrap(), None);
+    // Failed uploads are reported when flushing, and once more when finalizing.
+    let forbidden_writer = S3Writer::new(
+        S3Client::new(S3Config {
+            access_key_id: "wrong_key".to_string(),
+            ..config.clone()
+        }),
+        location.clone(),
+    );
+    let mut blob = forbidden_writer
+        .open_blob("forbidden.xyz", OpenMode::Truncate)
+        .unwrap();
+    blob.write_all(b"positions").unwrap();
+    assert!(blob.flush().is_err());
+    drop(blob);
+    assert!(forbidden_writer.finalize().is_err());
+    // Finishing a node writer completes its blobs, so it reports them, too.
+    let mut node_writer = RawNodeWriter::with_data_sink(
+        Arc::new(forbidden_writer),
+        "forbidden",
+        Encoding::Plain,
+        OpenMode::Truncate,
+        AttributeCompression::default(),
+        AttributeEncodings::default(),
+    );
+    let point = SyntheticData::new(args.width, args.height, 1, args.seed)
+        .next()
+        .unwrap();
+    node_writer.write(&point).unwrap();
+    assert!(node_writer.finish().is_err());
+    // S3 objects can't be appended to, which S2 cells need.
+    assert!(S2Splitter::with_data_sink(
+        S2_LEVEL,
+        Arc::new(s3_writer),
+        Encoding::Plain,
+        OpenMode::Truncate,
+        AttributeCompression::default(),
+        AttributeEncodings::default(),
+    )
+    .is_err());
+    let data_provider = S3DataProvider::new(S3Client::new(config), location);
+    let octree = Octree::from_data_provider(Box::new(data_provider)).unwrap();
+    let num_nodes = octree.to_meta_proto().get_octree().get_nodes().len();
+    // One object per node for positions and colors, and the meta.
+    assert_eq!(server.num_objects(), 2 * num_nodes + 1);
+    let query = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    assert_eq!(
+        query_and_sort(&octree, &query, args.batch_size).len(),
+        args.num_points
+    );
+}
+
 #[test]
 fn check_frustum_query_equality() {
     check_equality(get_frustum_query)
@@ -52,6 +573,11 @@ fn check_obb_query_equality() {
     check_equality(get_obb_query);
 }
 
+#[test]
+fn check_rotated_obb_query_equality() {
+    check_equality(get_rotated_obb_query);
+}
+
 #[test]
 fn check_cell_union_query_equality() {
     check_equality(get_cell_union_query)
@@ -98,6 +624,18 @@ where
     assert_points_equal(&points_s2, &points_oct, args.resolution);
 }
 
+#[test]
+fn exact_s2_cell_selection() {
+    let args = Arguments::default();
+    let (s2, _, data) = setup_pointcloud(&args);
+    let obb = get_rotated_obb(data);
+    let num_cells = s2
+        .nodes_in_location(&PointLocation::Obb(obb.clone()), None)
+        .len();
+    let num_cells_before = num_cells_in_rect_bound(&s2, &obb);
+    assert!(num_cells < num_cells_before);
+}
+
 /// We have two implementations of a containment predicate on points:
 /// One via the `PointCulling` trait, and one via the `ConvexPolyhedron` trait.
 /// They should behave the same.
@@ -131,7 +669,10 @@ where
     C: PointCloud,
 {
     let mut points = Vec::new();
-    for node_id in point_cloud.nodes_in_location(&query.location).into_iter() {
+    for node_id in point_cloud
+        .nodes_in_location(&query.location, query.max_level)
+        .into_iter()
+    {
         point_cloud
             .stream_points_for_query_in_node(query, node_id, batch_size, |batch| {
                 let color: &Vec<Vector3<u8>> = batch.get_attribute_vec("color")?;
@@ -153,6 +694,7 @@ where
     points
 }
 
+#[derive(Debug, PartialEq)]
 struct IndexedPoint {
     idx: usize,
     pos: Point3<f64>,
@@ -201,3 +743,272 @@ fn assert_points_equal(points_s2: &[IndexedPoint], points_oct: &[IndexedPoint],
         "More than 1% point index mismatches."
     );
 }
+
+#[test]
+fn compressed_point_clouds() {
+    let args = Arguments {
+        num_points: 100_000,
+        ..Default::default()
+    };
+    let build = |compression| {
+        let octree_dir = tempdir::TempDir::new("octree").unwrap();
+        make_octree_with_compression(&args, octree_dir.path(), compression);
+        let s2_dir = tempdir::TempDir::new("s2").unwrap();
+        make_s2_cells_with_compression(&args, s2_dir.path(), compression);
+        (octree_dir, s2_dir)
+    };
+    let open = |octree_dir: &tempdir::TempDir, s2_dir: &tempdir::TempDir| {
+        let octree =
+            Octree::from_data_provider(Box::new(OnDiskDataProvider::new(octree_dir.path())))
+                .unwrap();
+        let s2 =
+            S2Cells::from_data_provider(Box::new(OnDiskDataProvider::new(s2_dir.path()))).unwrap();
+        (octree, s2)
+    };
+    let query = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+
+    let (octree_dir, s2_dir) = build(Compression::Uncompressed);
+    let (octree, s2) = open(&octree_dir, &s2_dir);
+    let octree_points = query_and_sort(&octree, &query, args.batch_size);
+    let s2_points = query_and_sort(&s2, &query, args.batch_size);
+    let root = "r".parse().unwrap();
+    let root_data = octree.get_node_data(&root).unwrap();
+    for compression in &[Compression::Zstd, Compression::Lz4] {
+        let (compressed_octree_dir, compressed_s2_dir) = build(*compression);
+        let (compressed_octree, compressed_s2) = open(&compressed_octree_dir, &compressed_s2_dir);
+        assert_eq!(
+            compressed_octree
+                .to_meta_proto()
+                .get_attribute_compression()["position"],
+            compression.to_proto()
+        );
+        assert_eq!(
+            query_and_sort(&compressed_octree, &query, args.batch_size),
+            octree_points
+        );
+        // The S2 cells were appended to whenever a cell's writer was reopened, so their blobs
+        // consist of many frames which must all be read.
+        assert_eq!(
+            query_and_sort(&compressed_s2, &query, args.batch_size),
+            s2_points
+        );
+        // The web viewer serves the node data as is, so it has to be decompressed.
+        let compressed_root_data = compressed_octree.get_node_data(&root).unwrap();
+        assert_eq!(compressed_root_data.position, root_data.position);
+        assert_eq!(compressed_root_data.color, root_data.color);
+    }
+}
+
+#[test]
+fn checksums() {
+    let args = Arguments {
+        num_points: 100_000,
+        ..Default::default()
+    };
+    let octree_dir = tempdir::TempDir::new("octree").unwrap();
+    let points = SyntheticData::new(args.width, args.height, args.num_points, args.seed);
+    let bbox = points.bbox();
+    let data_sink = Arc::new(ChecksummingDataSink::new(Arc::new(
+        OnDiskDataProvider::new(octree_dir.path()),
+    )));
+    build_octree_into(
+        data_sink,
+        octree_dir.path(),
+        args.resolution,
+        bbox,
+        Batched::new(points, args.batch_size),
+        &["color"],
+        AttributeCompression::default(),
+        AttributeEncodings::default(),
+    );
+
+    // Every node file has a checksum.
+    let data_provider = OnDiskDataProvider::new(octree_dir.path());
+    let checksums = data_provider.meta_proto().unwrap().take_checksums();
+    let num_files = std::fs::read_dir(octree_dir.path()).unwrap().count();
+    assert_eq!(checksums.len(), num_files - 1);
+
+    let location = octree_dir.path().to_str().unwrap().to_owned();
+    let client = PointCloudClientBuilder::new(&[location])
+        .data_provider_factory(DataProviderFactory::new().verify_checksums(true))
+        .build()
+        .unwrap();
+    let query = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    let mut num_points = 0;
+    client
+        .for_each_point_data(&query, |batch| {
+            num_points += batch.position.len();
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(num_points, args.num_points);
+
+    let root_file = octree_dir.path().join("r.rgb");
+    let mut colors = std::fs::read(&root_file).unwrap();
+    colors[0] ^= 1;
+    std::fs::write(&root_file, &colors).unwrap();
+    let err = client.for_each_point_data(&query, |_| Ok(())).unwrap_err();
+    assert!(err
+        .iter()
+        .any(|cause| cause.to_string() == "The color data of node r does not match its checksum"));
+
+    colors[0] ^= 1;
+    std::fs::write(&root_file, colors).unwrap();
+
+    // Removing points rewrites nodes and reads some of them again, which must not be verified
+    // against the checksums the octree was opened with.
+    let verified_octree = || {
+        let data_provider = DataProviderFactory::new()
+            .verify_checksums(true)
+            .generate_data_provider(octree_dir.path().to_str().unwrap())
+            .unwrap();
+        Octree::from_data_provider(data_provider).unwrap()
+    };
+    let filter: AttributeFilter = "color[2] in [0, 128)".parse().unwrap();
+    let num_removed = verified_octree()
+        .remove_points(
+            Arc::new(OnDiskDataProvider::new(octree_dir.path())),
+            &PointLocation::AllPoints,
+            Some(&filter),
+        )
+        .unwrap();
+    assert!(num_removed > 0);
+    assert_eq!(
+        query_and_sort(&verified_octree(), &query, args.batch_size).len(),
+        args.num_points - num_removed
+    );
+}
+
+#[test]
+fn reindex_point_clouds() {
+    let args = Arguments::default();
+    let (s2_path, octree_path, data) = get_s2_and_octree_path(&args);
+    let (s2, octree, _) = setup_pointcloud(&args);
+    let all_points = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    let reindex = |location: &Path, query: &PointQuery, layout, dir: &Path| {
+        let client = PointCloudClientBuilder::new(&[location.to_str().unwrap().to_owned()])
+            .build()
+            .unwrap();
+        let data_sink = Arc::new(OnDiskDataProvider::new(dir));
+        reindex_point_cloud(
+            &client,
+            query,
+            layout,
+            Compression::Uncompressed,
+            data_sink,
+            dir,
+        )
+        .unwrap();
+        Box::new(OnDiskDataProvider::new(dir))
+    };
+
+    // The S2 cells, cropped into an octree.
+    let cropped = PointQuery {
+        attributes: vec!["color"],
+        location: get_aabb_query(data),
+        ..Default::default()
+    };
+    let octree_dir = tempdir::TempDir::new("octree").unwrap();
+    let layout = Layout::Octree {
+        resolution: args.resolution,
+    };
+    let data_provider = reindex(&s2_path, &cropped, layout, octree_dir.path());
+    let reindexed_octree = Octree::from_data_provider(data_provider).unwrap();
+    let expected = query_and_sort(&s2, &cropped, args.batch_size);
+    let points = query_and_sort(&reindexed_octree, &all_points, args.batch_size);
+    assert_eq!(points.len(), expected.len());
+    assert_points_equal(&expected, &points, args.resolution);
+
+    // The octree into S2 cells.
+    let s2_dir = tempdir::TempDir::new("s2").unwrap();
+    let layout = Layout::S2Cells {
+        split_level: S2_LEVEL,
+    };
+    let data_provider = reindex(&octree_path, &all_points, layout, s2_dir.path());
+    let reindexed_s2 = S2Cells::from_data_provider(data_provider).unwrap();
+    assert_eq!(
+        query_and_sort(&reindexed_s2, &all_points, args.batch_size),
+        query_and_sort(&octree, &all_points, args.batch_size)
+    );
+
+    // Octrees can't be built without colors.
+    let client = PointCloudClientBuilder::new(&[s2_path.to_str().unwrap().to_owned()])
+        .build()
+        .unwrap();
+    let intensities = PointQuery {
+        attributes: vec!["intensity"],
+        ..Default::default()
+    };
+    let dir = tempdir::TempDir::new("octree").unwrap();
+    assert!(reindex_point_cloud(
+        &client,
+        &intensities,
+        Layout::Octree {
+            resolution: args.resolution,
+        },
+        Compression::Uncompressed,
+        Arc::new(OnDiskDataProvider::new(dir.path())),
+        dir.path(),
+    )
+    .is_err());
+}
+
+#[test]
+fn remove_points_from_octree() {
+    let args = Arguments {
+        num_points: 300_000,
+        ..Default::default()
+    };
+    let octree_dir = tempdir::TempDir::new("octree").unwrap();
+    make_octree(&args, octree_dir.path());
+    let on_disk = || OnDiskDataProvider::new(octree_dir.path());
+    let octree = Octree::from_data_provider(Box::new(on_disk())).unwrap();
+    let all_points = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    let points = query_and_sort(&octree, &all_points, args.batch_size);
+
+    // The last color channel is the lowest byte of the point index.
+    let data = SyntheticData::new(args.width, args.height, args.num_points, args.seed);
+    let aabb = get_aabb(data);
+    let filter: AttributeFilter = "color[2] in [0, 128)".parse().unwrap();
+    let num_removed = octree
+        .remove_points(
+            Arc::new(on_disk()),
+            &PointLocation::Aabb(aabb.clone()),
+            Some(&filter),
+        )
+        .unwrap();
+    let (removed, expected): (Vec<_>, Vec<_>) = points
+        .into_iter()
+        .partition(|p| aabb.contains(&p.pos) && p.idx % 256 < 128);
+    assert!(num_removed > 0);
+    assert_eq!(num_removed, removed.len());
+
+    let octree = Octree::from_data_provider(Box::new(on_disk())).unwrap();
+    let remaining = query_and_sort(&octree, &all_points, args.batch_size);
+    assert_eq!(remaining.len(), expected.len());
+    assert_points_equal(&expected, &remaining, args.resolution);
+    let nodes = octree.to_meta_proto().get_octree().get_nodes().to_vec();
+    let num_points: i64 = nodes.iter().map(|n| n.num_points).sum();
+    assert_eq!(num_points as usize, expected.len());
+    // Nodes without points are only kept for their children.
+    let node_ids: Vec<_> = nodes
+        .iter()
+        .map(|n| NodeId::from_proto(n.get_id()))
+        .collect();
+    for node in nodes.iter().filter(|n| n.num_points == 0) {
+        let node_id = NodeId::from_proto(node.get_id());
+        assert!(node_ids.iter().any(|id| id.parent_id() == Some(node_id)));
+    }
+}
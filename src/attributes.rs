@@ -74,7 +74,7 @@ impl AttributeDataType {
 }
 
 /// General field to describe point feature attributes such as color, intensity, ...
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum AttributeData {
     U8(Vec<u8>),
     U16(Vec<u16>),
@@ -212,6 +212,16 @@ impl AttributeData {
         }
         match_attr_data!(self, rhs, idx)
     }
+
+    /// Returns the values at the given indices, in that order.
+    pub fn select(&self, indices: &[usize]) -> Self {
+        macro_rules! rhs {
+            ($dtype:ident, $data:ident, $indices:expr) => {
+                AttributeData::$dtype($indices.iter().map(|i| $data[*i]).collect())
+            };
+        }
+        match_attr_data!(self, rhs, indices)
+    }
 }
 
 macro_rules! try_from_impl {
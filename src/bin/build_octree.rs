@@ -13,9 +13,14 @@
 // limitations under the License.
 
 use clap::Clap;
-use point_viewer::octree::build_octree_from_file;
+use point_viewer::data_provider::{
+    ChecksummingDataSink, DataSink, OnDiskDataProvider, S3Client, S3Config, S3Location, S3Writer,
+};
+use point_viewer::octree::build_octree_from_file_into;
+use point_viewer::read_write::{AttributeCompression, AttributeEncodings, Compression};
 use rayon::ThreadPoolBuilder;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 #[derive(Clap, Debug)]
 #[clap(name = "build_octree")]
@@ -36,6 +41,21 @@ struct CommandlineArguments {
     /// The number of threads used to shard octree building. Set this as high as possible for SSDs.
     #[clap(long, default_value = "10")]
     num_threads: usize,
+
+    /// S3 location to write the octree to, e.g. s3://bucket/path/to/octree. Nodes are uploaded
+    /// as soon as they are finished, the output directory then only holds intermediate data. The
+    /// endpoint and credentials are read from the S3_ENDPOINT, AWS_REGION, AWS_ACCESS_KEY_ID and
+    /// AWS_SECRET_ACCESS_KEY environment variables.
+    #[clap(long)]
+    upload_to: Option<String>,
+
+    /// Compression of the nodes: none, zstd or lz4. zstd compresses better, LZ4 is faster.
+    #[clap(long, default_value = "none")]
+    compression: Compression,
+
+    /// Store checksums of the nodes in the meta, so that corrupt nodes can be detected on read.
+    #[clap(long)]
+    checksums: bool,
 }
 
 fn main() {
@@ -44,10 +64,25 @@ fn main() {
         .num_threads(args.num_threads)
         .build_global()
         .expect("Could not create thread pool.");
-    build_octree_from_file(
-        args.output_directory,
+    let mut data_sink: Arc<dyn DataSink> = match args.upload_to {
+        Some(location) => {
+            let location: S3Location = location.parse().expect("Invalid S3 location.");
+            let config = S3Config::from_env().expect("Could not configure the S3 client.");
+            Arc::new(S3Writer::new(S3Client::new(config), location))
+        }
+        None => Arc::new(OnDiskDataProvider::new(args.output_directory.clone())),
+    };
+    if args.checksums {
+        data_sink = Arc::new(ChecksummingDataSink::new(data_sink));
+    }
+    let attributes = &["color", "intensity"];
+    build_octree_from_file_into(
+        data_sink,
+        &args.output_directory,
         args.resolution,
         args.input,
-        &["color", "intensity"],
+        attributes,
+        AttributeCompression::uniform(&[&["position"], &attributes[..]].concat(), args.compression),
+        AttributeEncodings::default(),
     );
 }
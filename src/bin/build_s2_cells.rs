@@ -0,0 +1,209 @@
+// Copyright 2020 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use clap::Clap;
+use point_viewer::data_provider::{
+    ChecksummingDataSink, DataProvider, DataSink, OnDiskDataProvider,
+};
+use point_viewer::read_write::{
+    attempt_increasing_rlimit_to_max, AttributeCompression, AttributeEncodings, Compression,
+    Encoding, NodeWriter, OpenMode, PlyIterator, S2Splitter,
+};
+use point_viewer::s2_cells::{build_pyramid_into, S2Meta};
+use point_viewer::NUM_POINTS_PER_BATCH;
+use s2::cellid::CellID;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+#[derive(Clap, Debug)]
+#[clap(name = "build_s2_cells")]
+struct CommandlineArguments {
+    /// PLY file to parse for the points.
+    #[clap(parse(from_os_str))]
+    input: PathBuf,
+
+    /// Output directory to write the S2 cells into.
+    #[clap(long, parse(from_os_str))]
+    output_directory: PathBuf,
+
+    /// S2 level of the cells the points are split into. Level 20 corresponds to cells of up to
+    /// about 10m x 10m. Ignored with `--append`.
+    #[clap(long, default_value = "20")]
+    split_level: u64,
+
+    /// The number of cells kept open for writing at a time.
+    #[clap(long, default_value = "25")]
+    max_open_cells: usize,
+
+    /// The number of points buffered in memory and written ordered by cell, so that each cell is
+    /// opened about once per buffer, even if the input is ordered by time. 0 disables buffering.
+    #[clap(long, default_value = "10000000")]
+    buffered_points: usize,
+
+    /// Compression of the cells: none, zstd or lz4. zstd compresses better, LZ4 is faster.
+    /// Ignored with `--append`.
+    #[clap(long, default_value = "none")]
+    compression: Compression,
+
+    /// Store checksums of the cells in the meta, so that corrupt cells can be detected on read.
+    #[clap(long)]
+    checksums: bool,
+
+    /// Add the points to the S2 cells already in the output directory and update their meta,
+    /// instead of overwriting them. The existing split level and compression are kept. S2 cells
+    /// with a pyramid can't be appended to.
+    #[clap(long)]
+    append: bool,
+
+    /// Also build a pyramid up to this S2 level, whose cells hold subsamples of the points of
+    /// their children, for rendering and queries with a level of detail.
+    #[clap(long)]
+    pyramid_level: Option<u64>,
+}
+
+/// Prints the number of points of the cells written to, and a summary.
+fn print_cell_stats(meta: &S2Meta, previous_num_points: &HashMap<CellID, u64>) {
+    let mut written_cells: Vec<(CellID, u64, u64)> = meta
+        .get_cells()
+        .iter()
+        .filter_map(|(cell_id, cell_meta)| {
+            let previous = previous_num_points.get(cell_id).copied().unwrap_or(0);
+            if cell_meta.num_points == previous {
+                return None;
+            }
+            Some((
+                *cell_id,
+                cell_meta.num_points,
+                cell_meta.num_points - previous,
+            ))
+        })
+        .collect();
+    written_cells.sort_unstable();
+    println!("cell\tnum_points\tnum_points_added");
+    for (cell_id, num_points, num_points_added) in &written_cells {
+        println!(
+            "{}\t{}\t{}",
+            cell_id.to_token(),
+            num_points,
+            num_points_added
+        );
+    }
+
+    let num_points_added: u64 = written_cells.iter().map(|(_, _, added)| added).sum();
+    let num_new_cells = written_cells
+        .iter()
+        .filter(|(cell_id, _, _)| !previous_num_points.contains_key(cell_id))
+        .count();
+    let num_points_per_cell = meta.get_cells().values().map(|cell| cell.num_points);
+    eprintln!(
+        "Wrote {} points into {} cells, {} of them new. The point cloud has {} cells with {} to \
+         {} points.",
+        num_points_added,
+        written_cells.len(),
+        num_new_cells,
+        meta.get_cells().len(),
+        num_points_per_cell.clone().min().unwrap_or(0),
+        num_points_per_cell.max().unwrap_or(0),
+    );
+}
+
+fn main() {
+    let args = CommandlineArguments::parse();
+    if args.max_open_cells == 0 {
+        eprintln!("At least one cell has to be open for writing.");
+        std::process::exit(1);
+    }
+    attempt_increasing_rlimit_to_max();
+
+    let on_disk = OnDiskDataProvider::new(args.output_directory.clone());
+    let existing_meta = if args.append {
+        Some(on_disk.meta_proto().expect("Could not read existing meta."))
+    } else {
+        None
+    };
+    let mut data_sink: Arc<dyn DataSink> = Arc::new(on_disk);
+    // The checksums of appended cells are continued from the existing ones.
+    let existing_checksums = existing_meta.as_ref().map(|meta| meta.get_checksums());
+    if args.checksums || existing_checksums.map_or(false, |checksums| !checksums.is_empty()) {
+        data_sink = Arc::new(ChecksummingDataSink::with_checksums(
+            data_sink,
+            existing_checksums.unwrap_or(&HashMap::new()),
+        ));
+    }
+    let stream =
+        PlyIterator::from_file(&args.input, NUM_POINTS_PER_BATCH).expect("Could not read input.");
+    let (s2_splitter, previous_num_points) = match &existing_meta {
+        Some(meta_proto) => {
+            let meta = S2Meta::from_proto(meta_proto.clone()).expect("Not an S2 point cloud.");
+            let previous_num_points = meta
+                .get_cells()
+                .iter()
+                .map(|(cell_id, cell_meta)| (*cell_id, cell_meta.num_points))
+                .collect();
+            let s2_splitter = S2Splitter::appending_to_data_sink(Arc::clone(&data_sink), meta)
+                .expect("Could not append to S2 cells.");
+            (s2_splitter, previous_num_points)
+        }
+        None => {
+            let attributes = &["position", "color", "intensity"];
+            let s2_splitter = S2Splitter::with_data_sink(
+                args.split_level,
+                Arc::clone(&data_sink),
+                Encoding::Plain,
+                OpenMode::Truncate,
+                AttributeCompression::uniform(attributes, args.compression),
+                AttributeEncodings::default(),
+            )
+            .expect("Could not create S2 cells.");
+            (s2_splitter, HashMap::new())
+        }
+    };
+    let mut s2_splitter = s2_splitter
+        .with_max_num_writers(args.max_open_cells)
+        .with_max_num_buffered_points(args.buffered_points);
+    for batch in stream {
+        s2_splitter
+            .write(&batch)
+            .expect("Could not write S2 cells.");
+    }
+    let meta = match s2_splitter.get_meta().expect("Could not write S2 cells.") {
+        Some(meta) => meta,
+        None => {
+            eprintln!("The input does not contain any points.");
+            std::process::exit(1);
+        }
+    };
+    let mut meta_proto = meta.to_proto();
+    if let Some(existing_meta) = existing_meta {
+        meta_proto.set_checksums(existing_meta.get_checksums().clone());
+    }
+    data_sink
+        .write_meta(&meta_proto)
+        .expect("Could not write meta.");
+    data_sink.finalize().expect("Could not finalize S2 cells.");
+    print_cell_stats(&meta, &previous_num_points);
+
+    if let Some(pyramid_level) = args.pyramid_level {
+        let on_disk = || OnDiskDataProvider::new(args.output_directory.clone());
+        // Checksums are kept up to date by `build_pyramid_into` itself.
+        let meta = build_pyramid_into(&on_disk(), Arc::new(on_disk()), pyramid_level)
+            .expect("Could not build pyramid.");
+        eprintln!(
+            "Built a pyramid with {} cells on the levels {:?}.",
+            meta.get_cells().len(),
+            meta.levels()
+        );
+    }
+}
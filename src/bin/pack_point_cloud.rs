@@ -0,0 +1,38 @@
+// Copyright 2020 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use clap::Clap;
+use point_viewer::data_provider::{pack_directory, PACKED_EXTENSION};
+use std::path::PathBuf;
+
+#[derive(Clap, Debug)]
+#[clap(name = "pack_point_cloud")]
+struct CommandlineArguments {
+    /// Directory of the octree or S2 cells to pack.
+    #[clap(parse(from_os_str))]
+    directory: PathBuf,
+
+    /// Packed file to write. Defaults to the directory name with the extension "pvpack".
+    #[clap(long, parse(from_os_str))]
+    output_file: Option<PathBuf>,
+}
+
+fn main() {
+    let args = CommandlineArguments::parse();
+    let directory = args.directory;
+    let output_file = args
+        .output_file
+        .unwrap_or_else(|| directory.with_extension(PACKED_EXTENSION));
+    pack_directory(&directory, &output_file).expect("Packing the point cloud failed.");
+}
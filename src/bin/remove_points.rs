@@ -0,0 +1,82 @@
+// Copyright 2020 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use clap::Clap;
+use nalgebra::Point3;
+use point_viewer::data_provider::OnDiskDataProvider;
+use point_viewer::filter::AttributeFilter;
+use point_viewer::geometry::Aabb;
+use point_viewer::iterator::PointLocation;
+use point_viewer::octree::Octree;
+use point_viewer::utils::point3f64_from_str;
+use s2::cellid::CellID;
+use s2::cellunion::CellUnion;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+#[derive(Clap, Debug)]
+#[clap(name = "remove_points")]
+struct CommandlineArguments {
+    /// Directory of the octree to remove points from. Only the affected nodes are rewritten.
+    #[clap(parse(from_os_str))]
+    directory: PathBuf,
+
+    /// Remove the points in the bounding box with this minimum. Requires `--max`.
+    #[clap(long, requires = "max", parse(try_from_str = point3f64_from_str))]
+    min: Option<Point3<f64>>,
+
+    /// Remove the points in the bounding box with this maximum. Requires `--min`.
+    #[clap(long, requires = "min", parse(try_from_str = point3f64_from_str))]
+    max: Option<Point3<f64>>,
+
+    /// Remove the points in these S2 cells, given as tokens.
+    #[clap(long, conflicts_with = "min", use_delimiter = true)]
+    s2_cells: Vec<String>,
+
+    /// Only remove the points passing this filter, e.g. "intensity < 10".
+    #[clap(long)]
+    filter: Option<AttributeFilter>,
+}
+
+fn main() {
+    let args = CommandlineArguments::parse();
+    let location = match (args.min, args.max) {
+        (Some(min), Some(max)) => PointLocation::Aabb(Aabb::new(min, max)),
+        _ if !args.s2_cells.is_empty() => {
+            let mut cell_union = CellUnion(
+                args.s2_cells
+                    .iter()
+                    .map(|token| CellID::from_token(token))
+                    .collect(),
+            );
+            cell_union.normalize();
+            PointLocation::S2Cells(cell_union)
+        }
+        _ if args.filter.is_some() => PointLocation::AllPoints,
+        _ => {
+            eprintln!("Specify a region or a filter, or both.");
+            std::process::exit(1);
+        }
+    };
+
+    let on_disk = || OnDiskDataProvider::new(args.directory.clone());
+    let octree = Octree::from_data_provider(Box::new(on_disk())).expect("Could not open octree.");
+    match octree.remove_points(Arc::new(on_disk()), &location, args.filter.as_ref()) {
+        Ok(num_removed) => eprintln!("Removed {} points.", num_removed),
+        Err(e) => {
+            eprintln!("Encountered error:\n{}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -13,12 +13,12 @@
 // limitations under the License.
 
 use clap::Clap;
-use point_viewer::data_provider::{DataProvider, OnDiskDataProvider};
+use point_viewer::data_provider::{checksum, DataProvider, OnDiskDataProvider};
 use point_viewer::octree::NodeId;
 use point_viewer::proto;
 use point_viewer::META_FILENAME;
 use protobuf::Message;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::BufWriter;
 use std::path::{Path, PathBuf};
 
@@ -28,6 +28,11 @@ struct CommandlineArguments {
     /// Directory of octree to upgrade.
     #[clap(parse(from_os_str))]
     directory: PathBuf,
+
+    /// Store checksums of all node files in the meta, so that corrupt nodes can be detected on
+    /// read. Existing checksums are replaced.
+    #[clap(long)]
+    add_checksums: bool,
 }
 
 fn write_meta(directory: &Path, mut meta: proto::Meta, version: i32) {
@@ -80,11 +85,29 @@ fn upgrade_version12(directory: &Path, mut meta: proto::Meta) {
     write_meta(directory, meta, 13);
 }
 
+fn upgrade_version13(directory: &Path, meta: proto::Meta) {
+    eprintln!("Upgrading version 13 => 14.");
+    write_meta(directory, meta, 14);
+}
+
+fn add_checksums(directory: &Path, mut meta: proto::Meta) {
+    eprintln!("Adding checksums.");
+    let mut checksums = std::collections::HashMap::new();
+    for entry in fs::read_dir(directory).unwrap() {
+        let entry = entry.unwrap();
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type().unwrap().is_file() && file_name != META_FILENAME {
+            checksums.insert(file_name, checksum(&fs::read(entry.path()).unwrap()));
+        }
+    }
+    meta.set_checksums(checksums);
+    let version = meta.version;
+    write_meta(directory, meta, version);
+}
+
 fn main() {
     let args = CommandlineArguments::parse();
-    let data_provider = OnDiskDataProvider {
-        directory: args.directory.clone(),
-    };
+    let data_provider = OnDiskDataProvider::new(args.directory.clone());
 
     loop {
         let meta = data_provider
@@ -95,11 +118,15 @@ fn main() {
             10 => upgrade_version10(&args.directory, meta),
             11 => upgrade_version11(&args.directory, meta),
             12 => upgrade_version12(&args.directory, meta),
+            13 => upgrade_version13(&args.directory, meta),
             other if other == point_viewer::CURRENT_VERSION => {
                 eprintln!(
                     "Point cloud at current version {}",
                     point_viewer::CURRENT_VERSION
                 );
+                if args.add_checksums {
+                    add_checksums(&args.directory, meta);
+                }
                 break;
             }
             other => {
@@ -0,0 +1,210 @@
+use crate::data_provider::DataProvider;
+use crate::errors::*;
+use crate::proto;
+use lru::LruCache;
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Locations starting with this prefix are wrapped in a `CachingDataProvider` by the
+/// `DataProviderFactory`, e.g. `cache+/path/to/octree`.
+pub const CACHE_PREFIX: &str = "cache+";
+
+/// Default size of the cache of data providers created by the `DataProviderFactory`.
+pub const DEFAULT_CACHE_SIZE_BYTES: usize = 1 << 30;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+struct Blobs {
+    /// Keyed by node id and attribute.
+    cache: LruCache<(String, String), Arc<[u8]>>,
+    num_bytes: usize,
+}
+
+/// Wraps another data provider and keeps the meta and the most recently used node attributes
+/// in memory, up to a maximum number of bytes.
+pub struct CachingDataProvider {
+    data_provider: Box<dyn DataProvider>,
+    meta: Mutex<Option<proto::Meta>>,
+    blobs: Mutex<Blobs>,
+    max_num_bytes: usize,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl CachingDataProvider {
+    pub fn new(data_provider: Box<dyn DataProvider>, max_num_bytes: usize) -> Self {
+        Self {
+            data_provider,
+            meta: Mutex::new(None),
+            blobs: Mutex::new(Blobs {
+                cache: LruCache::unbounded(),
+                num_bytes: 0,
+            }),
+            max_num_bytes,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// Hits and misses of the meta and of node attributes, counted per attribute.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The number of bytes of node data currently in the cache.
+    pub fn num_bytes(&self) -> usize {
+        self.blobs.lock().unwrap().num_bytes
+    }
+
+    fn insert(&self, key: (String, String), blob: Arc<[u8]>) {
+        // Blobs that would evict everything else are not worth caching.
+        if blob.len() > self.max_num_bytes {
+            return;
+        }
+        let mut blobs = self.blobs.lock().unwrap();
+        blobs.num_bytes += blob.len();
+        if let Some(old_blob) = blobs.cache.put(key, blob) {
+            blobs.num_bytes -= old_blob.len();
+        }
+        while blobs.num_bytes > self.max_num_bytes {
+            match blobs.cache.pop_lru() {
+                Some((_, evicted)) => blobs.num_bytes -= evicted.len(),
+                None => break,
+            }
+        }
+    }
+}
+
+impl DataProvider for CachingDataProvider {
+    fn meta_proto(&self) -> Result<proto::Meta> {
+        let mut meta = self.meta.lock().unwrap();
+        if let Some(meta) = &*meta {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(meta.clone());
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let new_meta = self.data_provider.meta_proto()?;
+        *meta = Some(new_meta.clone());
+        Ok(new_meta)
+    }
+
+    fn data(
+        &self,
+        node_id: &str,
+        node_attributes: &[&str],
+    ) -> Result<HashMap<String, Box<dyn Read + Send>>> {
+        let mut cached = HashMap::new();
+        let mut missing = Vec::new();
+        {
+            let mut blobs = self.blobs.lock().unwrap();
+            for node_attribute in node_attributes {
+                let key = (node_id.to_string(), (*node_attribute).to_string());
+                match blobs.cache.get(&key) {
+                    Some(blob) => {
+                        cached.insert(key.1, Arc::clone(blob));
+                    }
+                    None => missing.push(*node_attribute),
+                }
+            }
+        }
+        self.hits.fetch_add(cached.len(), Ordering::Relaxed);
+        self.misses.fetch_add(missing.len(), Ordering::Relaxed);
+
+        if !missing.is_empty() {
+            for (node_attribute, mut reader) in self.data_provider.data(node_id, &missing)? {
+                let mut bytes = Vec::new();
+                reader.read_to_end(&mut bytes)?;
+                let blob: Arc<[u8]> = bytes.into();
+                self.insert(
+                    (node_id.to_string(), node_attribute.clone()),
+                    Arc::clone(&blob),
+                );
+                cached.insert(node_attribute, blob);
+            }
+        }
+        Ok(cached
+            .into_iter()
+            .map(|(node_attribute, blob)| {
+                let reader: Box<dyn Read + Send> = Box::new(Cursor::new(blob));
+                (node_attribute, reader)
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns 100 bytes per attribute and counts the requested attributes.
+    struct FakeDataProvider {
+        num_reads: Arc<AtomicUsize>,
+    }
+
+    impl DataProvider for FakeDataProvider {
+        fn meta_proto(&self) -> Result<proto::Meta> {
+            self.num_reads.fetch_add(1, Ordering::SeqCst);
+            Ok(proto::Meta::new())
+        }
+
+        fn data(
+            &self,
+            _node_id: &str,
+            node_attributes: &[&str],
+        ) -> Result<HashMap<String, Box<dyn Read + Send>>> {
+            self.num_reads
+                .fetch_add(node_attributes.len(), Ordering::SeqCst);
+            Ok(node_attributes
+                .iter()
+                .map(|a| {
+                    let reader: Box<dyn Read + Send> = Box::new(Cursor::new(vec![0u8; 100]));
+                    ((*a).to_string(), reader)
+                })
+                .collect())
+        }
+    }
+
+    #[test]
+    fn test_caching_data_provider() {
+        let num_reads = Arc::new(AtomicUsize::new(0));
+        let data_provider = CachingDataProvider::new(
+            Box::new(FakeDataProvider {
+                num_reads: Arc::clone(&num_reads),
+            }),
+            250,
+        );
+        data_provider.meta_proto().unwrap();
+        data_provider.meta_proto().unwrap();
+        assert_eq!(num_reads.load(Ordering::SeqCst), 1);
+
+        let data = data_provider.data("r0", &["position", "color"]).unwrap();
+        assert_eq!(data.len(), 2);
+        let mut bytes = Vec::new();
+        for mut reader in data.into_iter().map(|(_, r)| r) {
+            reader.read_to_end(&mut bytes).unwrap();
+        }
+        assert_eq!(bytes.len(), 200);
+        assert_eq!(num_reads.load(Ordering::SeqCst), 3);
+
+        // Only the intensity has to be read.
+        data_provider
+            .data("r0", &["position", "intensity"])
+            .unwrap();
+        assert_eq!(num_reads.load(Ordering::SeqCst), 4);
+        // The color was evicted, since only two blobs fit.
+        assert_eq!(data_provider.num_bytes(), 200);
+        data_provider.data("r0", &["color"]).unwrap();
+        assert_eq!(num_reads.load(Ordering::SeqCst), 5);
+
+        assert_eq!(data_provider.stats(), CacheStats { hits: 2, misses: 5 });
+    }
+}
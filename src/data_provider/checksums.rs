@@ -0,0 +1,274 @@
+use crate::data_provider::{blob_name, DataProvider, DataSink, MappedBlob};
+use crate::errors::*;
+use crate::proto;
+use crate::read_write::OpenMode;
+use crc32fast::Hasher;
+use std::collections::HashMap;
+use std::io::{self, Cursor, Read, Write};
+use std::sync::{Arc, Mutex};
+
+/// The CRC32 checksum of a blob, as stored in the meta.
+pub fn checksum(data: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/// Keyed by blob name. `None` if the checksum is unknown, because the blob was appended to
+/// without having been written through this data sink before, or if the blob was removed.
+type Checksums = HashMap<String, Option<u32>>;
+
+/// Wraps another data sink and keeps track of the checksums of the blobs written into it, which
+/// are added to the meta when it is written. Checksums cover the blobs as stored, i.e. compressed
+/// if they are compressed. Checksums that are already in the meta are kept for the blobs that
+/// were not written or removed through this data sink.
+pub struct ChecksummingDataSink {
+    data_sink: Arc<dyn DataSink>,
+    checksums: Arc<Mutex<Checksums>>,
+}
+
+impl ChecksummingDataSink {
+    pub fn new(data_sink: Arc<dyn DataSink>) -> Self {
+        Self {
+            data_sink,
+            checksums: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Starts from the checksums of a meta that is appended to, so that the checksums of the
+    /// blobs that are appended to are continued instead of becoming unknown.
+    pub fn with_checksums(data_sink: Arc<dyn DataSink>, checksums: &HashMap<String, u32>) -> Self {
+        let checksums = checksums
+            .iter()
+            .map(|(file_name, checksum)| (file_name.clone(), Some(*checksum)))
+            .collect();
+        Self {
+            data_sink,
+            checksums: Arc::new(Mutex::new(checksums)),
+        }
+    }
+
+    /// Wraps the data sink for rewriting some blobs of the point cloud with this meta, if it has
+    /// checksums. Checksums of the blobs that are not rewritten stay valid.
+    pub(crate) fn for_rewriting(
+        data_sink: Arc<dyn DataSink>,
+        meta: &proto::Meta,
+    ) -> Arc<dyn DataSink> {
+        if meta.get_checksums().is_empty() {
+            data_sink
+        } else {
+            Arc::new(Self::new(data_sink))
+        }
+    }
+}
+
+impl DataSink for ChecksummingDataSink {
+    fn open_blob(&self, file_name: &str, open_mode: OpenMode) -> Result<Box<dyn Write + Send>> {
+        let hasher = match open_mode {
+            OpenMode::Truncate => Some(Hasher::new()),
+            OpenMode::Append => match self.checksums.lock().unwrap().get(file_name) {
+                Some(checksum) => checksum.map(Hasher::new_with_initial),
+                None if self.data_sink.blob_size(file_name)?.unwrap_or(0) > 0 => None,
+                None => Some(Hasher::new()),
+            },
+        };
+        let writer = self.data_sink.open_blob(file_name, open_mode)?;
+        Ok(Box::new(ChecksumWriter {
+            writer,
+            hasher,
+            file_name: file_name.to_string(),
+            checksums: Arc::clone(&self.checksums),
+        }))
+    }
+
+    fn can_append(&self) -> bool {
+        self.data_sink.can_append()
+    }
+
+    fn blob_size(&self, file_name: &str) -> Result<Option<u64>> {
+        self.data_sink.blob_size(file_name)
+    }
+
+    fn remove_blob(&self, file_name: &str) -> Result<()> {
+        self.checksums
+            .lock()
+            .unwrap()
+            .insert(file_name.to_string(), None);
+        self.data_sink.remove_blob(file_name)
+    }
+
+    fn finalize(&self) -> Result<()> {
+        self.data_sink.finalize()
+    }
+
+    fn write_meta(&self, meta: &proto::Meta) -> Result<()> {
+        let mut meta = meta.clone();
+        let checksums = meta.mut_checksums();
+        for (file_name, checksum) in self.checksums.lock().unwrap().iter() {
+            match checksum {
+                Some(checksum) => checksums.insert(file_name.clone(), *checksum),
+                None => checksums.remove(file_name),
+            };
+        }
+        self.data_sink.write_meta(&meta)
+    }
+}
+
+/// Records the checksum of everything written when it is dropped.
+struct ChecksumWriter {
+    writer: Box<dyn Write + Send>,
+    // `None` if the checksum is unknown.
+    hasher: Option<Hasher>,
+    file_name: String,
+    checksums: Arc<Mutex<Checksums>>,
+}
+
+impl Write for ChecksumWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let num_written = self.writer.write(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..num_written]);
+        }
+        Ok(num_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Drop for ChecksumWriter {
+    fn drop(&mut self) {
+        let checksum = self.hasher.take().map(Hasher::finalize);
+        self.checksums
+            .lock()
+            .unwrap()
+            .insert(std::mem::take(&mut self.file_name), checksum);
+    }
+}
+
+/// Wraps another data provider and verifies the blobs it reads against the checksums in the
+/// meta. Blobs without a checksum are not verified. Since a blob has to be read completely
+/// before it can be verified, it is kept in memory.
+pub struct VerifyingDataProvider {
+    data_provider: Box<dyn DataProvider>,
+    checksums: HashMap<String, u32>,
+}
+
+impl VerifyingDataProvider {
+    pub fn new(data_provider: Box<dyn DataProvider>) -> Result<Self> {
+        let checksums = data_provider.meta_proto()?.take_checksums();
+        Ok(Self {
+            data_provider,
+            checksums,
+        })
+    }
+
+    fn verify(&self, node_id: &str, node_attribute: &str, data: &[u8]) -> Result<()> {
+        match self.checksums.get(&blob_name(node_id, node_attribute)) {
+            Some(expected) if *expected != checksum(data) => {
+                Err(ErrorKind::CorruptNode(node_id.to_string(), node_attribute.to_string()).into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl DataProvider for VerifyingDataProvider {
+    fn meta_proto(&self) -> Result<proto::Meta> {
+        self.data_provider.meta_proto()
+    }
+
+    fn data(
+        &self,
+        node_id: &str,
+        node_attributes: &[&str],
+    ) -> Result<HashMap<String, Box<dyn Read + Send>>> {
+        self.data_provider
+            .data(node_id, node_attributes)?
+            .into_iter()
+            .map(|(node_attribute, mut reader)| {
+                let mut data = Vec::new();
+                reader.read_to_end(&mut data)?;
+                self.verify(node_id, &node_attribute, &data)?;
+                let reader: Box<dyn Read + Send> = Box::new(Cursor::new(data));
+                Ok((node_attribute, reader))
+            })
+            .collect()
+    }
+
+    fn mapped_data(
+        &self,
+        node_id: &str,
+        node_attributes: &[&str],
+    ) -> Result<Option<HashMap<String, MappedBlob>>> {
+        let blobs = self.data_provider.mapped_data(node_id, node_attributes)?;
+        if let Some(blobs) = &blobs {
+            for (node_attribute, blob) in blobs {
+                self.verify(node_id, node_attribute, (**blob).as_ref())?;
+            }
+        }
+        Ok(blobs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_provider::MemoryDataProvider;
+
+    #[test]
+    fn test_checksums() {
+        let data_provider = MemoryDataProvider::new();
+        data_provider.register("test_checksums");
+        let data_sink = ChecksummingDataSink::new(Arc::new(data_provider.clone()));
+        data_sink.write_blob("r0.xyz", b"positions").unwrap();
+        data_sink.write_blob("r0.rgb", b"col").unwrap();
+        let mut writer = data_sink.open_blob("r0.rgb", OpenMode::Append).unwrap();
+        writer.write_all(b"ors").unwrap();
+        drop(writer);
+        data_sink.write_blob("r1.xyz", b"removed").unwrap();
+        data_sink.remove_blob("r1.xyz").unwrap();
+        let mut meta = proto::Meta::new();
+        meta.mut_checksums().insert("r1.xyz".to_string(), 0);
+        meta.mut_checksums().insert("r2.xyz".to_string(), 0);
+        data_sink.write_meta(&meta).unwrap();
+
+        // The checksum of the removed blob is dropped, the one of the untouched blob is kept.
+        let checksums = data_provider.meta_proto().unwrap().take_checksums();
+        assert_eq!(checksums.len(), 3);
+        assert_eq!(checksums["r2.xyz"], 0);
+        assert_eq!(checksums["r0.rgb"], checksum(b"colors"));
+
+        let verifying = VerifyingDataProvider::new(Box::new(data_provider.clone())).unwrap();
+        let mut data = verifying.data("r0", &["position", "color"]).unwrap();
+        let mut colors = Vec::new();
+        data.get_mut("color")
+            .unwrap()
+            .read_to_end(&mut colors)
+            .unwrap();
+        assert_eq!(colors, b"colors");
+
+        data_provider.write_blob("r0.rgb", b"colorz").unwrap();
+        match verifying.data("r0", &["color"]) {
+            Err(Error(ErrorKind::CorruptNode(node_id, attribute), _)) => {
+                assert_eq!((node_id.as_str(), attribute.as_str()), ("r0", "color"));
+            }
+            _ => panic!("Corruption was not detected."),
+        }
+
+        // Appending to a blob whose checksum is known from the meta continues its checksum.
+        let meta = data_provider.meta_proto().unwrap();
+        let data_sink = ChecksummingDataSink::with_checksums(
+            Arc::new(data_provider.clone()),
+            meta.get_checksums(),
+        );
+        data_provider.write_blob("r0.rgb", b"colors").unwrap();
+        let mut writer = data_sink.open_blob("r0.rgb", OpenMode::Append).unwrap();
+        writer.write_all(b" appended").unwrap();
+        drop(writer);
+        data_sink.write_meta(&meta).unwrap();
+        let checksums = data_provider.meta_proto().unwrap().take_checksums();
+        assert_eq!(checksums["r0.rgb"], checksum(b"colors appended"));
+    }
+}
@@ -3,6 +3,9 @@ use crate::proto;
 use std::collections::HashMap;
 use std::io::Read;
 
+/// The bytes of a blob that can be decoded in place, e.g. because the blob is memory-mapped.
+pub type MappedBlob = Box<dyn AsRef<[u8]> + Send>;
+
 pub trait DataProvider: Send + Sync {
     fn meta_proto(&self) -> Result<proto::Meta>;
     fn data(
@@ -10,4 +13,14 @@ pub trait DataProvider: Send + Sync {
         node_id: &str,
         node_attributes: &[&str],
     ) -> Result<HashMap<String, Box<dyn Read + Send>>>;
+
+    /// Like `data`, but returns the blobs as bytes, if this data provider can do so without
+    /// reading them first. Returns `None` otherwise, in which case `data` has to be used.
+    fn mapped_data(
+        &self,
+        _node_id: &str,
+        _node_attributes: &[&str],
+    ) -> Result<Option<HashMap<String, MappedBlob>>> {
+        Ok(None)
+    }
 }
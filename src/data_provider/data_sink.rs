@@ -0,0 +1,55 @@
+use crate::attribute_extension;
+use crate::errors::*;
+use crate::proto;
+use crate::read_write::OpenMode;
+use crate::META_FILENAME;
+use protobuf::Message;
+use std::io::Write;
+
+/// The write side of a `DataProvider`: stores the blobs of a point cloud, i.e. one per node and
+/// attribute, and its meta data. Blobs are named like the files of a point cloud directory, see
+/// `blob_name`.
+pub trait DataSink: Send + Sync {
+    /// Opens a blob for writing. With `OpenMode::Truncate` it is created empty, with
+    /// `OpenMode::Append` data is written after its current contents. The blob is complete once
+    /// the writer is flushed, which reports errors e.g. of uploading it. Data that was not
+    /// flushed is completed when the writer is dropped, and errors are only reported by
+    /// `finalize`.
+    fn open_blob(&self, file_name: &str, open_mode: OpenMode) -> Result<Box<dyn Write + Send>>;
+
+    /// Whether `open_blob` supports `OpenMode::Append`.
+    fn can_append(&self) -> bool {
+        true
+    }
+
+    /// The size of a blob, or `None` if it doesn't exist.
+    fn blob_size(&self, file_name: &str) -> Result<Option<u64>>;
+
+    /// Removes a blob. Blobs that don't exist are ignored.
+    fn remove_blob(&self, file_name: &str) -> Result<()>;
+
+    /// Called once after everything was written, e.g. to pack the blobs. Errors that happened
+    /// while completing blobs that were not flushed are reported here.
+    fn finalize(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn write_blob(&self, file_name: &str, data: &[u8]) -> Result<()> {
+        let mut writer = self.open_blob(file_name, OpenMode::Truncate)?;
+        writer.write_all(data)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn write_meta(&self, meta: &proto::Meta) -> Result<()> {
+        let data = meta
+            .write_to_bytes()
+            .chain_err(|| format!("Could not serialize {}", META_FILENAME))?;
+        self.write_blob(META_FILENAME, &data)
+    }
+}
+
+/// The name of the blob holding an attribute of a node.
+pub fn blob_name(node_id: &str, node_attribute: &str) -> String {
+    format!("{}.{}", node_id, attribute_extension(node_attribute))
+}
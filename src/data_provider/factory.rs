@@ -1,21 +1,46 @@
-use crate::data_provider::{DataProvider, OnDiskDataProvider};
+use crate::data_provider::{
+    http_data_provider, memory_data_provider, s3_data_provider, CachingDataProvider, DataProvider,
+    OnDiskDataProvider, PackedDataProvider, VerifyingDataProvider, CACHE_PREFIX,
+    DEFAULT_CACHE_SIZE_BYTES, MEMORY_PREFIX, PACKED_EXTENSION, S3_PREFIX,
+};
 use crate::errors::*;
 use fnv::FnvHashMap;
+use std::ffi::OsStr;
 use std::path::Path;
 
 pub type DataProviderFactoryResult = Result<Box<dyn DataProvider>>;
 pub type DataProviderFactoryFunction = fn(&str) -> DataProviderFactoryResult;
 
-#[derive(Default, Clone)]
+#[derive(Clone)]
 pub struct DataProviderFactory {
     data_provider_fn_map: FnvHashMap<String, DataProviderFactoryFunction>,
+    cache_size_bytes: usize,
+    verify_checksums: bool,
+    memory_map: bool,
+}
+
+impl Default for DataProviderFactory {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl DataProviderFactory {
+    /// Locations starting with `http://` and `https://` are handled by the `HttpDataProvider`
+    /// and locations starting with `s3://` by the `S3DataProvider`. Locations starting with
+    /// `memory://` refer to registered `MemoryDataProvider`s. Other functions can be registered
+    /// for these prefixes instead.
     pub fn new() -> Self {
         Self {
             data_provider_fn_map: FnvHashMap::default(),
+            cache_size_bytes: DEFAULT_CACHE_SIZE_BYTES,
+            verify_checksums: false,
+            memory_map: false,
         }
+        .register("http://", http_data_provider)
+        .register("https://", http_data_provider)
+        .register(S3_PREFIX, s3_data_provider)
+        .register(MEMORY_PREFIX, memory_data_provider)
     }
 
     pub fn register(
@@ -27,11 +52,53 @@ impl DataProviderFactory {
         self
     }
 
+    /// The maximum size of each cache created for locations with the `cache+` prefix.
+    pub fn cache_size_bytes(mut self, cache_size_bytes: usize) -> DataProviderFactory {
+        self.cache_size_bytes = cache_size_bytes;
+        self
+    }
+
+    /// Wraps the created data providers in a `VerifyingDataProvider`, which fails to read nodes
+    /// whose data doesn't match the checksums in the meta. With the `cache+` prefix, data is
+    /// verified before it is cached.
+    pub fn verify_checksums(mut self, verify_checksums: bool) -> DataProviderFactory {
+        self.verify_checksums = verify_checksums;
+        self
+    }
+
+    /// Point cloud directories and packed files are read by memory-mapping them, see
+    /// `OnDiskDataProvider::memory_map` and `PackedDataProvider::memory_map`.
+    pub fn memory_map(mut self, memory_map: bool) -> DataProviderFactory {
+        self.memory_map = memory_map;
+        self
+    }
+
+    /// Locations with the `cache+` prefix are wrapped in a `CachingDataProvider`, e.g.
+    /// `cache+/path/to/octree`. The rest of the location is resolved as usual.
     pub fn generate_data_provider(
         &self,
         data_provider_argument: impl AsRef<str>,
     ) -> DataProviderFactoryResult {
         let data_provider_argument = data_provider_argument.as_ref();
+        if let Some(location) = data_provider_argument.strip_prefix(CACHE_PREFIX) {
+            let data_provider = self.generate_data_provider(location)?;
+            return Ok(Box::new(CachingDataProvider::new(
+                data_provider,
+                self.cache_size_bytes,
+            )));
+        }
+        let data_provider = self.generate_uncached_data_provider(data_provider_argument)?;
+        if self.verify_checksums {
+            Ok(Box::new(VerifyingDataProvider::new(data_provider)?))
+        } else {
+            Ok(data_provider)
+        }
+    }
+
+    fn generate_uncached_data_provider(
+        &self,
+        data_provider_argument: &str,
+    ) -> DataProviderFactoryResult {
         for (prefix, data_provider_factory_function) in &self.data_provider_fn_map {
             if !data_provider_argument.starts_with(prefix) {
                 continue;
@@ -39,11 +106,18 @@ impl DataProviderFactory {
             return data_provider_factory_function(data_provider_argument);
         }
 
+        let path = Path::new(data_provider_argument);
+        if path.extension() == Some(OsStr::new(PACKED_EXTENSION)) && path.is_file() {
+            return Ok(Box::new(
+                PackedDataProvider::open(path)?.memory_map(self.memory_map)?,
+            ));
+        }
+
         // If no data provider was generated, create it from disk
-        if Path::new(data_provider_argument).exists() {
-            Ok(Box::new(OnDiskDataProvider {
-                directory: data_provider_argument.into(),
-            }))
+        if path.exists() {
+            Ok(Box::new(
+                OnDiskDataProvider::new(data_provider_argument).memory_map(self.memory_map),
+            ))
         } else {
             Err(format!(
                 "Directory '{}' for creating an OnDiskDataProvider doesn't exist.",
@@ -0,0 +1,154 @@
+use crate::attribute_extension;
+use crate::data_provider::{DataProvider, DataProviderFactoryResult};
+use crate::errors::*;
+use crate::proto;
+use crate::META_FILENAME;
+use protobuf::Message;
+use std::collections::HashMap;
+use std::io::{self, Cursor, Read};
+use std::time::Duration;
+
+/// Reads point clouds from a web server that serves the same files as the
+/// `OnDiskDataProvider` reads, relative to a base URL.
+pub struct HttpDataProvider {
+    base_url: String,
+    agent: ureq::Agent,
+    num_retries: usize,
+    retry_delay: Duration,
+    timeout: Duration,
+}
+
+/// Factory function for locations starting with `http://` or `https://`.
+pub fn http_data_provider(location: &str) -> DataProviderFactoryResult {
+    Ok(Box::new(HttpDataProvider::new(location)))
+}
+
+impl HttpDataProvider {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        let base_url = base_url.into();
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            agent: ureq::Agent::new(),
+            num_retries: 3,
+            retry_delay: Duration::from_millis(100),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// How often a request is repeated after a connection error or a server error (5xx).
+    pub fn num_retries(mut self, num_retries: usize) -> Self {
+        self.num_retries = num_retries;
+        self
+    }
+
+    /// The delay before the first retry. It doubles with each further retry.
+    pub fn retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// The timeout of a single request, including reading the response.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn url(&self, file_name: &str) -> String {
+        format!("{}/{}", self.base_url, file_name)
+    }
+
+    fn get(&self, file_name: &str) -> Result<Vec<u8>> {
+        let url = self.url(file_name);
+        let request = || self.agent.get(&url).timeout(self.timeout).call();
+        match send_with_retries(&url, self.num_retries, self.retry_delay, request, read_body)? {
+            (404, _) => Err(ErrorKind::NodeNotFound.into()),
+            (status, data) if (200..300).contains(&status) => Ok(data),
+            (status, _) => Err(format!("GET {} failed with status {}.", url, status).into()),
+        }
+    }
+}
+
+/// Sends a request until it returns a response that is worth looking at, i.e. anything but a
+/// connection error, a server error (5xx) or "Too Many Requests". Returns the status and what
+/// `read` extracts from the response, e.g. its body with `read_body`.
+pub(crate) fn send_with_retries<T>(
+    url: &str,
+    num_retries: usize,
+    mut retry_delay: Duration,
+    send: impl Fn() -> ureq::Response,
+    read: impl Fn(ureq::Response) -> io::Result<T>,
+) -> Result<(u16, T)> {
+    let mut num_tries = 0;
+    loop {
+        num_tries += 1;
+        let response = send();
+        let status = response.status();
+        let error = if response.synthetic() {
+            response
+                .synthetic_error()
+                .as_ref()
+                .map_or_else(|| "unknown error".to_string(), |e| e.to_string())
+        } else if status < 500 && status != 429 {
+            match read(response) {
+                Ok(result) => return Ok((status, result)),
+                Err(e) => e.to_string(),
+            }
+        } else {
+            format!("status {}", status)
+        };
+        if num_tries > num_retries {
+            return Err(format!(
+                "Request to {} failed after {} tries: {}",
+                url, num_tries, error
+            )
+            .into());
+        }
+        std::thread::sleep(retry_delay);
+        retry_delay *= 2;
+    }
+}
+
+pub(crate) fn read_body(response: ureq::Response) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    response.into_reader().read_to_end(&mut data)?;
+    Ok(data)
+}
+
+impl DataProvider for HttpDataProvider {
+    fn meta_proto(&self) -> Result<proto::Meta> {
+        let data = self.get(META_FILENAME)?;
+        proto::Meta::parse_from_bytes(&data)
+            .chain_err(|| format!("Could not parse {}", self.url(META_FILENAME)))
+    }
+
+    fn data(
+        &self,
+        node_id: &str,
+        node_attributes: &[&str],
+    ) -> Result<HashMap<String, Box<dyn Read + Send>>> {
+        // The attributes are fetched concurrently, since each request mostly waits.
+        let results = crossbeam::scope(|s| {
+            let handles: Vec<_> = node_attributes
+                .iter()
+                .map(|node_attribute| {
+                    s.spawn(move |_| {
+                        let file_name =
+                            format!("{}.{}", node_id, attribute_extension(node_attribute));
+                        self.get(&file_name)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("HTTP request thread panicked."))
+                .collect::<Vec<_>>()
+        })
+        .expect("HTTP request thread panicked.");
+
+        let mut readers = HashMap::<String, Box<dyn Read + Send>>::new();
+        for (node_attribute, data) in node_attributes.iter().zip(results) {
+            readers.insert((*node_attribute).to_string(), Box::new(Cursor::new(data?)));
+        }
+        Ok(readers)
+    }
+}
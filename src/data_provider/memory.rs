@@ -0,0 +1,232 @@
+use crate::data_provider::{blob_name, DataProvider, DataProviderFactoryResult, DataSink};
+use crate::errors::*;
+use crate::proto;
+use crate::read_write::OpenMode;
+use crate::META_FILENAME;
+use lazy_static::lazy_static;
+use protobuf::Message;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Cursor, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Point clouds registered with `MemoryDataProvider::register` are available as
+/// `memory://<name>` to the `DataProviderFactory`.
+pub const MEMORY_PREFIX: &str = "memory://";
+
+lazy_static! {
+    static ref REGISTRY: Mutex<HashMap<String, MemoryDataProvider>> = Mutex::new(HashMap::new());
+}
+
+/// File contents that can be shared with readers without copying them.
+#[derive(Clone, Default)]
+struct Blob(Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for Blob {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Keeps the files of a point cloud directory in memory, keyed by file name. Cloning is cheap,
+/// clones share the files. As a `DataSink`, it is the target of node writers, e.g.
+/// `S2Splitter::with_data_sink`.
+#[derive(Clone, Default)]
+pub struct MemoryDataProvider {
+    files: Arc<RwLock<HashMap<String, Blob>>>,
+}
+
+/// Factory function for locations starting with `memory://`.
+pub fn memory_data_provider(location: &str) -> DataProviderFactoryResult {
+    let name = &location[MEMORY_PREFIX.len()..];
+    let data_provider = MemoryDataProvider::registered(name).ok_or_else(|| {
+        ErrorKind::InvalidInput(format!("No point cloud is registered as {}.", location))
+    })?;
+    Ok(Box::new(data_provider))
+}
+
+impl MemoryDataProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads all files of a point cloud directory. Subdirectories are ignored.
+    pub fn from_directory(directory: impl AsRef<Path>) -> Result<Self> {
+        let data_provider = Self::new();
+        for entry in fs::read_dir(directory)? {
+            let path = entry?.path();
+            if path.is_file() {
+                let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+                data_provider.insert(file_name, fs::read(&path)?);
+            }
+        }
+        Ok(data_provider)
+    }
+
+    /// Makes this point cloud available as `memory://<name>`, replacing any point cloud that
+    /// was registered with the same name before.
+    pub fn register(&self, name: impl Into<String>) {
+        REGISTRY.lock().unwrap().insert(name.into(), self.clone());
+    }
+
+    pub fn registered(name: &str) -> Option<Self> {
+        REGISTRY.lock().unwrap().get(name).cloned()
+    }
+
+    /// Frees the point cloud once all other clones are dropped.
+    pub fn unregister(name: &str) -> Option<Self> {
+        REGISTRY.lock().unwrap().remove(name)
+    }
+
+    pub fn insert(&self, file_name: impl Into<String>, data: Vec<u8>) {
+        self.files
+            .write()
+            .unwrap()
+            .insert(file_name.into(), Blob(Arc::new(data)));
+    }
+
+    pub fn file_names(&self) -> Vec<String> {
+        self.files.read().unwrap().keys().cloned().collect()
+    }
+
+    /// The total size of all files.
+    pub fn num_bytes(&self) -> usize {
+        self.files.read().unwrap().values().map(|b| b.0.len()).sum()
+    }
+
+    fn get(&self, file_name: &str) -> Result<Blob> {
+        self.files
+            .read()
+            .unwrap()
+            .get(file_name)
+            .cloned()
+            .ok_or_else(|| ErrorKind::NodeNotFound.into())
+    }
+
+    fn len(&self, file_name: &str) -> Option<usize> {
+        self.files.read().unwrap().get(file_name).map(|b| b.0.len())
+    }
+
+    fn append(&self, file_name: &str, data: &[u8]) {
+        let mut files = self.files.write().unwrap();
+        let blob = files.entry(file_name.to_string()).or_default();
+        // Readers keep their copy.
+        Arc::make_mut(&mut blob.0).extend_from_slice(data);
+    }
+}
+
+impl DataProvider for MemoryDataProvider {
+    fn meta_proto(&self) -> Result<proto::Meta> {
+        proto::Meta::parse_from_bytes(self.get(META_FILENAME)?.as_ref())
+            .chain_err(|| format!("Could not parse {}", META_FILENAME))
+    }
+
+    fn data(
+        &self,
+        node_id: &str,
+        node_attributes: &[&str],
+    ) -> Result<HashMap<String, Box<dyn Read + Send>>> {
+        let mut readers = HashMap::<String, Box<dyn Read + Send>>::new();
+        for node_attribute in node_attributes {
+            let blob = self.get(&blob_name(node_id, node_attribute))?;
+            readers.insert((*node_attribute).to_string(), Box::new(Cursor::new(blob)));
+        }
+        Ok(readers)
+    }
+}
+
+impl DataSink for MemoryDataProvider {
+    fn open_blob(&self, file_name: &str, open_mode: OpenMode) -> Result<Box<dyn Write + Send>> {
+        if open_mode == OpenMode::Truncate {
+            self.insert(file_name, Vec::new());
+        }
+        Ok(Box::new(MemoryBlobWriter {
+            data_provider: self.clone(),
+            file_name: file_name.to_string(),
+        }))
+    }
+
+    fn blob_size(&self, file_name: &str) -> Result<Option<u64>> {
+        Ok(self.len(file_name).map(|len| len as u64))
+    }
+
+    fn remove_blob(&self, file_name: &str) -> Result<()> {
+        self.files.write().unwrap().remove(file_name);
+        Ok(())
+    }
+}
+
+struct MemoryBlobWriter {
+    data_provider: MemoryDataProvider,
+    file_name: String,
+}
+
+impl Write for MemoryBlobWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data_provider.append(&self.file_name, buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_write::{Compression, DataWriter};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_memory_data_provider() {
+        let data_provider = MemoryDataProvider::new();
+        data_provider.register("test_memory_data_provider");
+        data_provider.write_meta(&proto::Meta::new()).unwrap();
+        let data_sink: Arc<dyn DataSink> = Arc::new(data_provider);
+        {
+            let mut writer = DataWriter::from_data_sink(
+                &data_sink,
+                "r0.xyz",
+                OpenMode::Truncate,
+                Compression::Uncompressed,
+            )
+            .unwrap();
+            writer.write_all(b"positions").unwrap();
+            // Empty blobs are removed, like files on disk.
+            DataWriter::from_data_sink(
+                &data_sink,
+                "r0.rgb",
+                OpenMode::Truncate,
+                Compression::Uncompressed,
+            )
+            .unwrap();
+        }
+        let mut writer = DataWriter::from_data_sink(
+            &data_sink,
+            "r0.xyz",
+            OpenMode::Append,
+            Compression::Uncompressed,
+        )
+        .unwrap();
+        assert_eq!(writer.bytes_written(), 9);
+        writer.write_all(b"!").unwrap();
+        drop(writer);
+
+        let data_provider = MemoryDataProvider::unregister("test_memory_data_provider").unwrap();
+        assert_eq!(data_provider.meta_proto().unwrap(), proto::Meta::new());
+        let mut bytes = Vec::new();
+        let mut data = data_provider.data("r0", &["position"]).unwrap();
+        data.get_mut("position")
+            .unwrap()
+            .read_to_end(&mut bytes)
+            .unwrap();
+        assert_eq!(bytes, b"positions!");
+        match data_provider.data("r0", &["color"]) {
+            Err(Error(ErrorKind::NodeNotFound, _)) => (),
+            _ => panic!("Expected NodeNotFound."),
+        }
+        assert!(MemoryDataProvider::registered("test_memory_data_provider").is_none());
+    }
+}
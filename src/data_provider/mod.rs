@@ -1,7 +1,23 @@
+mod caching;
+mod checksums;
 mod common;
+mod data_sink;
 mod factory;
+mod http;
+mod memory;
 mod on_disk;
+mod packed;
+mod s3;
 
-pub use common::DataProvider;
+pub use caching::{CacheStats, CachingDataProvider, CACHE_PREFIX, DEFAULT_CACHE_SIZE_BYTES};
+pub use checksums::{checksum, ChecksummingDataSink, VerifyingDataProvider};
+pub use common::{DataProvider, MappedBlob};
+pub use data_sink::{blob_name, DataSink};
 pub use factory::{DataProviderFactory, DataProviderFactoryResult};
+pub use http::{http_data_provider, HttpDataProvider};
+pub use memory::{memory_data_provider, MemoryDataProvider, MEMORY_PREFIX};
 pub use on_disk::OnDiskDataProvider;
+pub use packed::{pack_directory, PackedDataProvider, PackedWriter, PACKED_EXTENSION};
+pub use s3::{
+    s3_data_provider, S3Client, S3Config, S3DataProvider, S3Location, S3Writer, S3_PREFIX,
+};
@@ -1,23 +1,54 @@
 use crate::attribute_extension;
-use crate::data_provider::DataProvider;
+use crate::data_provider::{DataProvider, DataSink, MappedBlob};
 use crate::errors::*;
 use crate::proto;
+use crate::read_write::OpenMode;
 use crate::META_FILENAME;
+use memmap::Mmap;
 use std::collections::HashMap;
-use std::fs::{self, File};
-use std::io::{Cursor, Read};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Read, Write};
 use std::path::PathBuf;
 
+/// Reads and writes a point cloud directory.
 pub struct OnDiskDataProvider {
     pub directory: PathBuf,
+    memory_map: bool,
 }
 
 impl OnDiskDataProvider {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            memory_map: false,
+        }
+    }
+
+    /// Reads nodes by memory-mapping their files, which avoids copying them. The files must not
+    /// be modified while they are being read: A file that is truncated meanwhile crashes the
+    /// process with SIGBUS, so don't use this while the point cloud may be rewritten in place.
+    pub fn memory_map(mut self, memory_map: bool) -> Self {
+        self.memory_map = memory_map;
+        self
+    }
+
     /// Returns the path on disk where the data for this node is saved.
     pub fn stem(&self, node_id: &str) -> PathBuf {
         self.directory.join(node_id)
     }
 
+    fn open(&self, node_id: &str, node_attribute: &str) -> Result<File> {
+        let path = self
+            .stem(node_id)
+            .with_extension(attribute_extension(node_attribute));
+        match File::open(&path) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                Err(ErrorKind::NodeNotFound.into())
+            }
+            file => Ok(file?),
+        }
+    }
+
     // Get number of points from the file size of the color data.
     // Color data is required and always present.
     pub fn number_of_points(&self, node_id: &str) -> Result<i64> {
@@ -53,17 +84,70 @@ impl DataProvider for OnDiskDataProvider {
         node_id: &str,
         node_attributes: &[&str],
     ) -> Result<HashMap<String, Box<dyn Read + Send>>> {
-        let stem = self.stem(node_id);
         let mut readers = HashMap::<String, Box<dyn Read + Send>>::new();
         for node_attribute in node_attributes {
-            let file = match File::open(&stem.with_extension(attribute_extension(node_attribute))) {
-                Err(ref err) if err.kind() == ::std::io::ErrorKind::NotFound => {
-                    return Err(ErrorKind::NodeNotFound.into());
-                }
-                e => e,
-            }?;
+            let file = self.open(node_id, node_attribute)?;
             readers.insert((*node_attribute).to_string(), Box::new(file));
         }
         Ok(readers)
     }
+
+    fn mapped_data(
+        &self,
+        node_id: &str,
+        node_attributes: &[&str],
+    ) -> Result<Option<HashMap<String, MappedBlob>>> {
+        if !self.memory_map {
+            return Ok(None);
+        }
+        let mut blobs = HashMap::<String, MappedBlob>::new();
+        for node_attribute in node_attributes {
+            let file = self.open(node_id, node_attribute)?;
+            // Empty files can't be mapped.
+            let mmap = if file.metadata()?.len() > 0 {
+                Some(unsafe { Mmap::map(&file)? })
+            } else {
+                None
+            };
+            blobs.insert((*node_attribute).to_string(), Box::new(MappedFile(mmap)));
+        }
+        Ok(Some(blobs))
+    }
+}
+
+struct MappedFile(Option<Mmap>);
+
+impl AsRef<[u8]> for MappedFile {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref().map_or(&[], |mmap| &mmap[..])
+    }
+}
+
+impl DataSink for OnDiskDataProvider {
+    fn open_blob(&self, file_name: &str, open_mode: OpenMode) -> Result<Box<dyn Write + Send>> {
+        // Ignore errors, maybe directory is already there.
+        let _ = fs::create_dir(&self.directory);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(open_mode == OpenMode::Append)
+            .truncate(open_mode == OpenMode::Truncate)
+            .write(true)
+            .open(self.directory.join(file_name))?;
+        Ok(Box::new(file))
+    }
+
+    fn blob_size(&self, file_name: &str) -> Result<Option<u64>> {
+        match fs::metadata(self.directory.join(file_name)) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn remove_blob(&self, file_name: &str) -> Result<()> {
+        match fs::remove_file(self.directory.join(file_name)) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => Ok(result?),
+        }
+    }
 }
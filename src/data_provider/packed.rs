@@ -0,0 +1,351 @@
+//! A point cloud in a single file, instead of one file per node and attribute.
+//!
+//! The file starts with a header, followed by an index of all files of the point cloud
+//! directory, followed by their contents. All numbers are little endian.
+//!
+//! - header: the magic bytes `PVPACK`, the format version as u16 and the number of index
+//!   entries as u64
+//! - index entry: the length of the file name as u16, the file name, and the offset and length
+//!   of the file's contents in the packed file as two u64
+
+use crate::data_provider::{blob_name, DataProvider, DataSink, MappedBlob, OnDiskDataProvider};
+use crate::errors::*;
+use crate::proto;
+use crate::read_write::OpenMode;
+use crate::META_FILENAME;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use fnv::FnvHashMap;
+use memmap::Mmap;
+use protobuf::Message;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Locations with this extension are opened as `PackedDataProvider` by the
+/// `DataProviderFactory`.
+pub const PACKED_EXTENSION: &str = "pvpack";
+
+const MAGIC: &[u8; 6] = b"PVPACK";
+const FORMAT_VERSION: u16 = 1;
+
+/// Packs all files of a point cloud directory, e.g. an octree or S2 cells, into one file.
+/// Subdirectories are ignored.
+pub fn pack_directory(directory: impl AsRef<Path>, output_file: impl AsRef<Path>) -> Result<()> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            let file_name = entry.file_name().into_string().map_err(|name| {
+                ErrorKind::InvalidInput(format!("File name {:?} is not valid UTF-8.", name))
+            })?;
+            if u16::try_from(file_name.len()).is_err() {
+                return Err(ErrorKind::InvalidInput(format!(
+                    "File name {} is too long.",
+                    file_name
+                ))
+                .into());
+            }
+            files.push((file_name, entry.path(), metadata.len()));
+        }
+    }
+    // Nodes with similar ids are often read together.
+    files.sort();
+
+    let index_size: u64 = files
+        .iter()
+        .map(|(file_name, _, _)| 2 + file_name.len() as u64 + 16)
+        .sum();
+    let mut offset = MAGIC.len() as u64 + 2 + 8 + index_size;
+    let mut writer = BufWriter::new(File::create(output_file)?);
+    writer.write_all(MAGIC)?;
+    writer.write_u16::<LittleEndian>(FORMAT_VERSION)?;
+    writer.write_u64::<LittleEndian>(files.len() as u64)?;
+    for (file_name, _, len) in &files {
+        writer.write_u16::<LittleEndian>(file_name.len() as u16)?;
+        writer.write_all(file_name.as_bytes())?;
+        writer.write_u64::<LittleEndian>(offset)?;
+        writer.write_u64::<LittleEndian>(*len)?;
+        offset += len;
+    }
+    for (file_name, path, len) in &files {
+        let num_copied = io::copy(&mut File::open(path)?, &mut writer)?;
+        if num_copied != *len {
+            return Err(format!("{} changed while packing it.", file_name).into());
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes a point cloud as a packed file. Blobs are collected in a staging directory next to the
+/// output file, which is packed and removed when the sink is finalized.
+pub struct PackedWriter {
+    output_file: PathBuf,
+    staging: OnDiskDataProvider,
+}
+
+impl PackedWriter {
+    pub fn new(output_file: impl Into<PathBuf>) -> Self {
+        let output_file = output_file.into();
+        let staging = OnDiskDataProvider::new(output_file.with_extension("staging"));
+        Self {
+            output_file,
+            staging,
+        }
+    }
+}
+
+impl DataSink for PackedWriter {
+    fn open_blob(&self, file_name: &str, open_mode: OpenMode) -> Result<Box<dyn Write + Send>> {
+        self.staging.open_blob(file_name, open_mode)
+    }
+
+    fn blob_size(&self, file_name: &str) -> Result<Option<u64>> {
+        self.staging.blob_size(file_name)
+    }
+
+    fn remove_blob(&self, file_name: &str) -> Result<()> {
+        self.staging.remove_blob(file_name)
+    }
+
+    fn finalize(&self) -> Result<()> {
+        pack_directory(&self.staging.directory, &self.output_file)?;
+        fs::remove_dir_all(&self.staging.directory)?;
+        Ok(())
+    }
+}
+
+/// Reads a point cloud from a file written by `pack_directory`.
+pub struct PackedDataProvider {
+    path: PathBuf,
+    // Only set if the file is read by memory-mapping it.
+    mmap: Option<Arc<Mmap>>,
+    index: FnvHashMap<String, Range<u64>>,
+}
+
+impl PackedDataProvider {
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let file = File::open(&path)?;
+        let file_len = file.metadata()?.len();
+        let index = read_index(BufReader::new(file), file_len)
+            .chain_err(|| format!("Could not read packed point cloud {}", path.display()))?;
+        Ok(Self {
+            path,
+            mmap: None,
+            index,
+        })
+    }
+
+    /// Reads nodes by memory-mapping the file, which avoids copying them. The file must not be
+    /// modified while it is being read: If it is truncated meanwhile, the process crashes with
+    /// SIGBUS.
+    pub fn memory_map(mut self, memory_map: bool) -> Result<Self> {
+        self.mmap = if memory_map {
+            let file = File::open(&self.path)?;
+            Some(Arc::new(unsafe { Mmap::map(&file)? }))
+        } else {
+            None
+        };
+        Ok(self)
+    }
+
+    fn range(&self, file_name: &str) -> Result<Range<u64>> {
+        self.index
+            .get(file_name)
+            .cloned()
+            .ok_or_else(|| ErrorKind::NodeNotFound.into())
+    }
+
+    fn blob(&self, file_name: &str) -> Result<Box<dyn Read + Send>> {
+        let range = self.range(file_name)?;
+        match &self.mmap {
+            Some(mmap) => Ok(Box::new(Cursor::new(MmapSlice::new(mmap, range)))),
+            None => {
+                let mut file = File::open(&self.path)?;
+                file.seek(SeekFrom::Start(range.start))?;
+                Ok(Box::new(file.take(range.end - range.start)))
+            }
+        }
+    }
+}
+
+fn read_index(mut reader: impl Read, file_len: u64) -> Result<FnvHashMap<String, Range<u64>>> {
+    let mut magic = [0; 6];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(ErrorKind::InvalidInput("Not a packed point cloud.".to_string()).into());
+    }
+    let version = reader.read_u16::<LittleEndian>()?;
+    if version != FORMAT_VERSION {
+        return Err(ErrorKind::InvalidInput(format!(
+            "Unsupported packed format version {}.",
+            version
+        ))
+        .into());
+    }
+    let num_entries = reader.read_u64::<LittleEndian>()?;
+    let mut index = FnvHashMap::default();
+    for _ in 0..num_entries {
+        let mut file_name = vec![0; reader.read_u16::<LittleEndian>()? as usize];
+        reader.read_exact(&mut file_name)?;
+        let file_name = String::from_utf8(file_name)
+            .map_err(|_| ErrorKind::InvalidInput("Invalid file name.".to_string()))?;
+        let offset = reader.read_u64::<LittleEndian>()?;
+        let len = reader.read_u64::<LittleEndian>()?;
+        let end = match offset.checked_add(len) {
+            Some(end) if end <= file_len => end,
+            _ => {
+                return Err(ErrorKind::InvalidInput(format!("{} is truncated.", file_name)).into())
+            }
+        };
+        index.insert(file_name, offset..end);
+    }
+    Ok(index)
+}
+
+/// A part of the memory-mapped file that can be read without copying it first.
+struct MmapSlice {
+    mmap: Arc<Mmap>,
+    range: Range<usize>,
+}
+
+impl MmapSlice {
+    fn new(mmap: &Arc<Mmap>, range: Range<u64>) -> Self {
+        Self {
+            mmap: Arc::clone(mmap),
+            range: range.start as usize..range.end as usize,
+        }
+    }
+}
+
+impl AsRef<[u8]> for MmapSlice {
+    fn as_ref(&self) -> &[u8] {
+        &self.mmap[self.range.clone()]
+    }
+}
+
+impl DataProvider for PackedDataProvider {
+    fn meta_proto(&self) -> Result<proto::Meta> {
+        let mut data = Vec::new();
+        self.blob(META_FILENAME)?.read_to_end(&mut data)?;
+        proto::Meta::parse_from_bytes(&data)
+            .chain_err(|| format!("Could not parse {}", META_FILENAME))
+    }
+
+    fn data(
+        &self,
+        node_id: &str,
+        node_attributes: &[&str],
+    ) -> Result<HashMap<String, Box<dyn Read + Send>>> {
+        let mut readers = HashMap::<String, Box<dyn Read + Send>>::new();
+        for node_attribute in node_attributes {
+            let blob = self.blob(&blob_name(node_id, node_attribute))?;
+            readers.insert((*node_attribute).to_string(), blob);
+        }
+        Ok(readers)
+    }
+
+    fn mapped_data(
+        &self,
+        node_id: &str,
+        node_attributes: &[&str],
+    ) -> Result<Option<HashMap<String, MappedBlob>>> {
+        let mmap = match &self.mmap {
+            Some(mmap) => mmap,
+            None => return Ok(None),
+        };
+        let mut blobs = HashMap::<String, MappedBlob>::new();
+        for node_attribute in node_attributes {
+            let range = self.range(&blob_name(node_id, node_attribute))?;
+            blobs.insert(
+                (*node_attribute).to_string(),
+                Box::new(MmapSlice::new(mmap, range)),
+            );
+        }
+        Ok(Some(blobs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_pack_directory() {
+        let directory = TempDir::new("point_cloud").unwrap();
+        fs::write(directory.path().join(META_FILENAME), b"").unwrap();
+        fs::write(directory.path().join("r0.xyz"), b"positions").unwrap();
+        fs::write(directory.path().join("r0.rgb"), b"colors").unwrap();
+        fs::write(directory.path().join("r01.xyz"), b"").unwrap();
+        fs::create_dir(directory.path().join("ignored")).unwrap();
+        let packed_file = directory
+            .path()
+            .join("packed")
+            .with_extension(PACKED_EXTENSION);
+        pack_directory(directory.path(), &packed_file).unwrap();
+
+        for memory_map in &[false, true] {
+            let data_provider = PackedDataProvider::open(&packed_file)
+                .unwrap()
+                .memory_map(*memory_map)
+                .unwrap();
+            assert_eq!(data_provider.meta_proto().unwrap(), proto::Meta::new());
+            assert_eq!(
+                data_provider
+                    .mapped_data("r0", &["position"])
+                    .unwrap()
+                    .is_some(),
+                *memory_map
+            );
+            let mut data = data_provider.data("r0", &["position", "color"]).unwrap();
+            let mut read = |attribute| {
+                let mut bytes = Vec::new();
+                data.get_mut(attribute)
+                    .unwrap()
+                    .read_to_end(&mut bytes)
+                    .unwrap();
+                bytes
+            };
+            assert_eq!(read("position"), b"positions");
+            assert_eq!(read("color"), b"colors");
+            let mut data = data_provider.data("r01", &["position"]).unwrap();
+            let reader = data.get_mut("position").unwrap();
+            assert_eq!(reader.read(&mut [0; 4]).unwrap(), 0);
+            match data_provider.data("r02", &["position"]) {
+                Err(Error(ErrorKind::NodeNotFound, _)) => (),
+                _ => panic!("Expected NodeNotFound."),
+            }
+        }
+    }
+
+    #[test]
+    fn test_packed_writer() {
+        let directory = TempDir::new("point_cloud").unwrap();
+        let packed_file = directory.path().join("octree.pvpack");
+        let packed_writer = PackedWriter::new(&packed_file);
+        packed_writer.write_meta(&proto::Meta::new()).unwrap();
+        packed_writer.write_blob("r0.xyz", b"positions").unwrap();
+        packed_writer.write_blob("r1.xyz", b"removed").unwrap();
+        packed_writer.remove_blob("r1.xyz").unwrap();
+        packed_writer.finalize().unwrap();
+        assert!(!directory.path().join("octree.staging").exists());
+
+        let data_provider = PackedDataProvider::open(&packed_file).unwrap();
+        assert_eq!(data_provider.meta_proto().unwrap(), proto::Meta::new());
+        let mut bytes = Vec::new();
+        let mut data = data_provider.data("r0", &["position"]).unwrap();
+        data.get_mut("position")
+            .unwrap()
+            .read_to_end(&mut bytes)
+            .unwrap();
+        assert_eq!(bytes, b"positions");
+        assert!(data_provider.data("r1", &["position"]).is_err());
+    }
+}
@@ -0,0 +1,233 @@
+//! Removal of duplicate points when querying several overlapping point clouds together.
+
+use crate::errors::*;
+use crate::{AttributeData, PointsBatch};
+use fnv::{FnvHashMap, FnvHasher};
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// The default for `Deduplication::max_num_keys`. Every key takes roughly 64 bytes, so this
+/// limits the memory of a query to about 1 GB.
+pub const DEFAULT_MAX_NUM_KEYS: usize = 16_000_000;
+
+fn default_max_num_keys() -> usize {
+    DEFAULT_MAX_NUM_KEYS
+}
+
+/// The number of independently locked parts of the map of seen keys, so that the threads of a
+/// query rarely wait for each other.
+const NUM_SHARDS: usize = 64;
+
+/// Decides which points of different point clouds are duplicates of each other.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum DeduplicationKey {
+    /// Points with the same value of this integer attribute, e.g. a unique point id, are
+    /// duplicates. The attribute needs to be specified as query attribute.
+    Attribute(String),
+    /// Points falling into the same cube of this edge length (in meters) are duplicates.
+    Quantization(f64),
+}
+
+/// Every distinct key that is read is remembered until the query is done, so the memory needed
+/// grows with the number of points returned. Queries that would remember more than
+/// `max_num_keys` keys fail instead.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Deduplication {
+    pub key: DeduplicationKey,
+    /// The priority of each point cloud, in the order they are queried. Of several duplicates,
+    /// a point of the point cloud with the highest priority is kept. If empty, all point clouds
+    /// have the same priority, and the point that is read first is kept.
+    #[serde(default)]
+    pub priorities: Vec<i32>,
+    /// The maximum number of distinct keys of a query.
+    #[serde(default = "default_max_num_keys")]
+    pub max_num_keys: usize,
+}
+
+impl Deduplication {
+    pub fn new(key: DeduplicationKey) -> Self {
+        Self {
+            key,
+            priorities: Vec::new(),
+            max_num_keys: DEFAULT_MAX_NUM_KEYS,
+        }
+    }
+
+    pub fn priorities(mut self, priorities: Vec<i32>) -> Self {
+        self.priorities = priorities;
+        self
+    }
+
+    pub fn max_num_keys(mut self, max_num_keys: usize) -> Self {
+        self.max_num_keys = max_num_keys;
+        self
+    }
+
+    /// Groups the point cloud indices by priority, highest priority first. Point clouds of a
+    /// group need to be read completely before the ones of the next group.
+    pub(crate) fn priority_levels(&self, num_point_clouds: usize) -> Result<Vec<Vec<usize>>> {
+        if self.priorities.is_empty() {
+            return Ok(vec![(0..num_point_clouds).collect()]);
+        }
+        if self.priorities.len() != num_point_clouds {
+            return Err(ErrorKind::InvalidInput(format!(
+                "Got {} deduplication priorities for {} point clouds.",
+                self.priorities.len(),
+                num_point_clouds
+            ))
+            .into());
+        }
+        let mut priorities = self.priorities.clone();
+        priorities.sort_unstable_by(|a, b| b.cmp(a));
+        priorities.dedup();
+        Ok(priorities
+            .into_iter()
+            .map(|priority| {
+                (0..num_point_clouds)
+                    .filter(|i| self.priorities[*i] == priority)
+                    .collect()
+            })
+            .collect())
+    }
+
+    pub(crate) fn validate(&self, attributes: &[&str]) -> Result<()> {
+        match &self.key {
+            DeduplicationKey::Attribute(name) if !attributes.contains(&name.as_str()) => {
+                Err(ErrorKind::InvalidInput(format!(
+                    "Deduplication attribute '{}' needs to be specified as query attribute.",
+                    name
+                ))
+                .into())
+            }
+            DeduplicationKey::Quantization(tolerance) if *tolerance <= 0.0 => {
+                Err(ErrorKind::InvalidInput(format!(
+                    "Deduplication tolerance needs to be positive, but is {}.",
+                    tolerance
+                ))
+                .into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+enum Key {
+    Id(i128),
+    Cell(i64, i64, i64),
+}
+
+impl Key {
+    fn shard(&self) -> usize {
+        let mut hasher = FnvHasher::default();
+        self.hash(&mut hasher);
+        hasher.finish() as usize % NUM_SHARDS
+    }
+}
+
+/// Shared between the threads of a query. Remembers for every key which point cloud returned
+/// it first.
+pub(crate) struct Deduplicator<'a> {
+    key: &'a DeduplicationKey,
+    max_num_keys: usize,
+    num_keys: AtomicUsize,
+    seen: Vec<Mutex<FnvHashMap<Key, usize>>>,
+}
+
+impl<'a> Deduplicator<'a> {
+    pub fn new(deduplication: &'a Deduplication) -> Self {
+        Self {
+            key: &deduplication.key,
+            max_num_keys: deduplication.max_num_keys,
+            num_keys: AtomicUsize::new(0),
+            seen: (0..NUM_SHARDS)
+                .map(|_| Mutex::new(FnvHashMap::default()))
+                .collect(),
+        }
+    }
+
+    fn keys(&self, batch: &PointsBatch) -> Result<Vec<Key>> {
+        match self.key {
+            DeduplicationKey::Attribute(name) => {
+                let data = batch.attributes.get(name).ok_or_else(|| {
+                    format!(
+                        "Deduplication attribute '{}' needs to be specified as query attribute.",
+                        name
+                    )
+                })?;
+                macro_rules! rhs {
+                    ($dtype:ident, $data:ident) => {
+                        $data
+                            .iter()
+                            .map(|v| v.to_i128().map(Key::Id))
+                            .collect::<Option<Vec<Key>>>()
+                    };
+                }
+                match data {
+                    AttributeData::U8Vec3(_) | AttributeData::F64Vec3(_) => None,
+                    data => match_1d_attr_data!(data, rhs),
+                }
+                .ok_or_else(|| {
+                    ErrorKind::InvalidInput(format!(
+                        "Deduplication attribute '{}' does not contain integers.",
+                        name
+                    ))
+                    .into()
+                })
+            }
+            DeduplicationKey::Quantization(tolerance) => Ok(batch
+                .position
+                .iter()
+                .map(|p| {
+                    let cell = p.coords / *tolerance;
+                    Key::Cell(
+                        cell.x.floor() as i64,
+                        cell.y.floor() as i64,
+                        cell.z.floor() as i64,
+                    )
+                })
+                .collect()),
+        }
+    }
+
+    /// Removes the points from the batch that were already returned by another point cloud.
+    /// Duplicates within the same point cloud are kept.
+    pub fn retain_unique(
+        &self,
+        point_cloud_index: usize,
+        mut batch: PointsBatch,
+    ) -> Result<PointsBatch> {
+        let keys = self.keys(&batch)?;
+        let mut shards = vec![Vec::new(); NUM_SHARDS];
+        for (i, key) in keys.iter().enumerate() {
+            shards[key.shard()].push(i);
+        }
+        let mut keep = vec![false; keys.len()];
+        let mut num_new_keys = 0;
+        for (shard, indices) in shards.iter().enumerate() {
+            if indices.is_empty() {
+                continue;
+            }
+            let mut seen = self.seen[shard].lock().unwrap();
+            for i in indices {
+                let len = seen.len();
+                keep[*i] = *seen.entry(keys[*i]).or_insert(point_cloud_index) == point_cloud_index;
+                num_new_keys += seen.len() - len;
+            }
+        }
+        if self.num_keys.fetch_add(num_new_keys, Ordering::SeqCst) + num_new_keys
+            > self.max_num_keys
+        {
+            return Err(ErrorKind::InvalidInput(format!(
+                "Deduplication needs more than the maximum of {} keys.",
+                self.max_num_keys
+            ))
+            .into());
+        }
+        batch.retain(&keep);
+        Ok(batch)
+    }
+}
@@ -0,0 +1,240 @@
+//! Reduction of the points returned by a query to at most one point per voxel.
+
+use crate::errors::*;
+use crate::{AttributeData, PointsBatch};
+use fnv::FnvHashMap;
+use nalgebra::{Point3, Vector3};
+use serde::{Deserialize, Serialize};
+
+/// Which point represents all points falling into the same voxel.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum VoxelRepresentative {
+    /// The first point of the voxel, with all its attributes.
+    First,
+    /// The centroid of the points in the voxel. Floating point and color attributes are
+    /// averaged, integer attributes (e.g. ids or classes) are taken from the first point.
+    Centroid,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct VoxelDownsampling {
+    /// Edge length of the voxels in meters. The voxel grid is aligned to the origin of the
+    /// query's output frame.
+    pub voxel_size: f64,
+    pub representative: VoxelRepresentative,
+}
+
+impl VoxelDownsampling {
+    pub fn new(voxel_size: f64, representative: VoxelRepresentative) -> Self {
+        assert!(voxel_size > 0.0, "The voxel size needs to be positive.");
+        Self {
+            voxel_size,
+            representative,
+        }
+    }
+
+    /// Checks the voxel size, since deserialized queries bypass the check in `new`.
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.voxel_size > 0.0 {
+            Ok(())
+        } else {
+            Err(ErrorKind::InvalidInput(format!(
+                "The voxel size needs to be positive, but is {}.",
+                self.voxel_size
+            ))
+            .into())
+        }
+    }
+
+    /// Returns one point per voxel that contains points of the batch, in the order in which the
+    /// voxels were first encountered.
+    pub fn downsample(&self, batch: &PointsBatch) -> PointsBatch {
+        let mut voxel_indices = FnvHashMap::default();
+        // For every point the index of its voxel, and for every voxel its first point.
+        let mut voxel_of_point = Vec::with_capacity(batch.position.len());
+        let mut first_points = Vec::new();
+        for (i, p) in batch.position.iter().enumerate() {
+            let voxel = (p.coords / self.voxel_size).map(|c| c.floor() as i64);
+            let voxel_index = *voxel_indices.entry(voxel).or_insert_with(|| {
+                first_points.push(i);
+                first_points.len() - 1
+            });
+            voxel_of_point.push(voxel_index);
+        }
+
+        let (position, attributes) = match self.representative {
+            VoxelRepresentative::First => (
+                first_points.iter().map(|i| batch.position[*i]).collect(),
+                batch
+                    .attributes
+                    .iter()
+                    .map(|(name, data)| (name.clone(), data.select(&first_points)))
+                    .collect(),
+            ),
+            VoxelRepresentative::Centroid => {
+                let mut counts = vec![0.0; first_points.len()];
+                for voxel_index in &voxel_of_point {
+                    counts[*voxel_index] += 1.0;
+                }
+                let sums = sum_per_voxel(
+                    batch.position.iter().map(|p| p.coords),
+                    &voxel_of_point,
+                    first_points.len(),
+                );
+                let position = sums
+                    .iter()
+                    .zip(&counts)
+                    .map(|(sum, count)| Point3::from(sum / *count))
+                    .collect();
+                let attributes = batch
+                    .attributes
+                    .iter()
+                    .map(|(name, data)| {
+                        let data = average(data, &voxel_of_point, &first_points, &counts);
+                        (name.clone(), data)
+                    })
+                    .collect();
+                (position, attributes)
+            }
+        };
+        PointsBatch {
+            position,
+            attributes,
+        }
+    }
+}
+
+fn sum_per_voxel<I, T>(values: I, voxel_of_point: &[usize], num_voxels: usize) -> Vec<T>
+where
+    I: Iterator<Item = T>,
+    T: num_traits::Zero + std::ops::AddAssign + Clone,
+{
+    let mut sums = vec![T::zero(); num_voxels];
+    for (value, voxel_index) in values.zip(voxel_of_point) {
+        sums[*voxel_index] += value;
+    }
+    sums
+}
+
+fn average(
+    data: &AttributeData,
+    voxel_of_point: &[usize],
+    first_points: &[usize],
+    counts: &[f64],
+) -> AttributeData {
+    let num_voxels = first_points.len();
+    match data {
+        AttributeData::F32(data) => {
+            let sums = sum_per_voxel(
+                data.iter().map(|v| f64::from(*v)),
+                voxel_of_point,
+                num_voxels,
+            );
+            AttributeData::F32(
+                sums.iter()
+                    .zip(counts)
+                    .map(|(sum, count)| (sum / count) as f32)
+                    .collect(),
+            )
+        }
+        AttributeData::F64(data) => {
+            let sums = sum_per_voxel(data.iter().copied(), voxel_of_point, num_voxels);
+            AttributeData::F64(
+                sums.iter()
+                    .zip(counts)
+                    .map(|(sum, count)| sum / count)
+                    .collect(),
+            )
+        }
+        AttributeData::U8Vec3(data) => {
+            let sums = sum_per_voxel(
+                data.iter().map(|v| v.map(f64::from)),
+                voxel_of_point,
+                num_voxels,
+            );
+            AttributeData::U8Vec3(
+                sums.iter()
+                    .zip(counts)
+                    .map(|(sum, count)| (sum / *count).map(|c| c.round() as u8))
+                    .collect(),
+            )
+        }
+        AttributeData::F64Vec3(data) => {
+            let sums: Vec<Vector3<f64>> =
+                sum_per_voxel(data.iter().copied(), voxel_of_point, num_voxels);
+            AttributeData::F64Vec3(
+                sums.iter()
+                    .zip(counts)
+                    .map(|(sum, count)| sum / *count)
+                    .collect(),
+            )
+        }
+        _ => data.select(first_points),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_batch() -> PointsBatch {
+        PointsBatch {
+            position: vec![
+                Point3::new(0.01, 0.01, 0.01),
+                Point3::new(0.03, 0.03, 0.03),
+                Point3::new(0.07, 0.01, 0.01),
+                Point3::new(-0.01, 0.01, 0.01),
+            ],
+            attributes: vec![
+                ("class".to_string(), AttributeData::U8(vec![1, 2, 3, 4])),
+                (
+                    "intensity".to_string(),
+                    AttributeData::F32(vec![1.0, 2.0, 3.0, 4.0]),
+                ),
+                (
+                    "color".to_string(),
+                    AttributeData::U8Vec3(vec![
+                        Vector3::new(0, 0, 0),
+                        Vector3::new(255, 100, 1),
+                        Vector3::new(1, 1, 1),
+                        Vector3::new(2, 2, 2),
+                    ]),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        }
+    }
+
+    #[test]
+    fn test_downsample_first() {
+        let downsampling = VoxelDownsampling::new(0.05, VoxelRepresentative::First);
+        let batch = downsampling.downsample(&test_batch());
+        assert_eq!(
+            batch.position,
+            vec![
+                Point3::new(0.01, 0.01, 0.01),
+                Point3::new(0.07, 0.01, 0.01),
+                Point3::new(-0.01, 0.01, 0.01),
+            ]
+        );
+        assert_eq!(batch.get_attribute_vec::<u8>("class").unwrap(), &[1, 3, 4]);
+    }
+
+    #[test]
+    fn test_downsample_centroid() {
+        let downsampling = VoxelDownsampling::new(0.05, VoxelRepresentative::Centroid);
+        let batch = downsampling.downsample(&test_batch());
+        assert_eq!(batch.position.len(), 3);
+        assert!((batch.position[0] - Point3::new(0.02, 0.02, 0.02)).norm() < 1e-9);
+        assert_eq!(batch.get_attribute_vec::<u8>("class").unwrap(), &[1, 3, 4]);
+        assert_eq!(
+            batch.get_attribute_vec::<f32>("intensity").unwrap(),
+            &[1.5, 3.0, 4.0]
+        );
+        assert_eq!(
+            batch.get_attribute_vec::<Vector3<u8>>("color").unwrap()[0],
+            Vector3::new(128, 50, 1)
+        );
+    }
+}
@@ -44,5 +44,15 @@ error_chain! {
             display("{}", msg)
         }
 
+        NodeReadFailed(point_cloud_index: usize, node_id: String) {
+            description("Reading the points of a node failed")
+            display("Could not read node {} of point cloud {}", node_id, point_cloud_index)
+        }
+
+        CorruptNode(node_id: String, attribute: String) {
+            description("The data of a node does not match its checksum")
+            display("The {} data of node {} does not match its checksum", attribute, node_id)
+        }
+
     }
 }
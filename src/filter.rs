@@ -0,0 +1,534 @@
+//! Filters on point attributes, to be used in queries against the point cloud.
+//!
+//! A filter can be given as text, e.g. on the command line:
+//! `classification in {2, 6, 9} && !(intensity < 0.5) || color[0] in [200, )`.
+//! Intervals can be closed `[a, b]`, open `(a, b)`, half-open or unbounded on one side
+//! (`[a, )`), sets are written as `{a, b, c}`. Channels of vector attributes are selected with
+//! `name[channel]`. `!` binds stronger than `&&`, which binds stronger than `||`.
+
+use crate::attributes::AttributeData;
+use crate::errors::*;
+use crate::math::ClosedInterval;
+use crate::PointsBatch;
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use std::ops::Bound;
+use std::str::FromStr;
+
+/// An attribute, or a single channel of a vector attribute like `color`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AttributeSelector {
+    pub name: String,
+    pub channel: Option<usize>,
+}
+
+impl AttributeSelector {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            channel: None,
+        }
+    }
+
+    pub fn with_channel(name: impl Into<String>, channel: usize) -> Self {
+        Self {
+            name: name.into(),
+            channel: Some(channel),
+        }
+    }
+}
+
+/// An interval whose bounds can each be inclusive, exclusive or missing.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Interval {
+    pub lower: Bound<f64>,
+    pub upper: Bound<f64>,
+}
+
+impl Interval {
+    pub fn contains(&self, value: f64) -> bool {
+        let above_lower = match self.lower {
+            Bound::Included(lower) => lower <= value,
+            Bound::Excluded(lower) => lower < value,
+            Bound::Unbounded => true,
+        };
+        let below_upper = match self.upper {
+            Bound::Included(upper) => value <= upper,
+            Bound::Excluded(upper) => value < upper,
+            Bound::Unbounded => true,
+        };
+        above_lower && below_upper
+    }
+}
+
+impl From<ClosedInterval<f64>> for Interval {
+    fn from(interval: ClosedInterval<f64>) -> Self {
+        Self {
+            lower: Bound::Included(interval.lower_bound()),
+            upper: Bound::Included(interval.upper_bound()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum AttributeFilter {
+    InInterval(AttributeSelector, Interval),
+    InSet(AttributeSelector, Vec<f64>),
+    Not(Box<AttributeFilter>),
+    And(Vec<AttributeFilter>),
+    Or(Vec<AttributeFilter>),
+}
+
+impl AttributeFilter {
+    /// The names of all attributes this filter needs, without duplicates.
+    pub fn attributes(&self) -> Vec<&str> {
+        let mut attributes = Vec::new();
+        self.collect_attributes(&mut attributes);
+        attributes
+    }
+
+    fn collect_attributes<'a>(&'a self, attributes: &mut Vec<&'a str>) {
+        match self {
+            AttributeFilter::InInterval(selector, _) | AttributeFilter::InSet(selector, _) => {
+                if !attributes.contains(&selector.name.as_str()) {
+                    attributes.push(&selector.name);
+                }
+            }
+            AttributeFilter::Not(filter) => filter.collect_attributes(attributes),
+            AttributeFilter::And(filters) | AttributeFilter::Or(filters) => {
+                for filter in filters {
+                    filter.collect_attributes(attributes);
+                }
+            }
+        }
+    }
+
+    /// Returns for every point in the batch whether it passes the filter.
+    /// All attributes used by the filter need to be present in the batch.
+    pub fn evaluate(&self, batch: &PointsBatch) -> Result<Vec<bool>> {
+        match self {
+            AttributeFilter::InInterval(selector, interval) => {
+                evaluate_predicate(batch, selector, |v| interval.contains(v))
+            }
+            AttributeFilter::InSet(selector, values) => {
+                evaluate_predicate(batch, selector, |v| values.contains(&v))
+            }
+            AttributeFilter::Not(filter) => {
+                let mut keep = filter.evaluate(batch)?;
+                keep.iter_mut().for_each(|k| *k = !*k);
+                Ok(keep)
+            }
+            AttributeFilter::And(filters) => {
+                let mut keep = vec![true; batch.position.len()];
+                for filter in filters {
+                    for (k, f) in keep.iter_mut().zip(filter.evaluate(batch)?) {
+                        *k &= f;
+                    }
+                }
+                Ok(keep)
+            }
+            AttributeFilter::Or(filters) => {
+                let mut keep = vec![false; batch.position.len()];
+                for filter in filters {
+                    for (k, f) in keep.iter_mut().zip(filter.evaluate(batch)?) {
+                        *k |= f;
+                    }
+                }
+                Ok(keep)
+            }
+        }
+    }
+}
+
+fn evaluate_predicate<P>(
+    batch: &PointsBatch,
+    selector: &AttributeSelector,
+    predicate: P,
+) -> Result<Vec<bool>>
+where
+    P: Fn(f64) -> bool,
+{
+    let data = batch.attributes.get(&selector.name).ok_or_else(|| {
+        format!(
+            "Filter attribute '{}' needs to be specified as query attribute.",
+            selector.name
+        )
+    })?;
+    macro_rules! rhs {
+        ($dtype:ident, $data:ident, $predicate:ident) => {
+            $data
+                .iter()
+                .map(|v| v.to_f64().map_or(false, &$predicate))
+                .collect()
+        };
+    }
+    let keep = match (data, selector.channel) {
+        (AttributeData::U8Vec3(data), Some(channel)) if channel < 3 => data
+            .iter()
+            .map(|v| predicate(f64::from(v[channel])))
+            .collect(),
+        (AttributeData::F64Vec3(data), Some(channel)) if channel < 3 => {
+            data.iter().map(|v| predicate(v[channel])).collect()
+        }
+        (AttributeData::U8Vec3(_), _) | (AttributeData::F64Vec3(_), _) => {
+            return Err(ErrorKind::InvalidInput(format!(
+                "Filter on vector attribute '{}' needs a channel between 0 and 2.",
+                selector.name
+            ))
+            .into());
+        }
+        (_, Some(_)) => {
+            return Err(ErrorKind::InvalidInput(format!(
+                "Attribute '{}' has no channels to filter on.",
+                selector.name
+            ))
+            .into());
+        }
+        (data, None) => match_1d_attr_data!(data, rhs, predicate),
+    };
+    Ok(keep)
+}
+
+impl FromStr for AttributeFilter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parser = Parser { input: s, pos: 0 };
+        let filter = parser.parse_or()?;
+        parser.skip_whitespace();
+        if parser.pos != s.len() {
+            return Err(parser.error("end of filter"));
+        }
+        Ok(filter)
+    }
+}
+
+/// Recursive descent parser for the filter syntax described in the module documentation.
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn error(&self, expected: &str) -> Error {
+        ErrorKind::InvalidInput(format!(
+            "Could not parse filter '{}': expected {} at position {}.",
+            self.input, expected, self.pos
+        ))
+        .into()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    /// Consumes `token` if the remaining input starts with it.
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<()> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("'{}'", token)))
+        }
+    }
+
+    /// Consumes the longest prefix of characters matching `predicate`.
+    fn take_while<P: Fn(char) -> bool>(&mut self, predicate: P) -> &'a str {
+        self.skip_whitespace();
+        let rest = self.rest();
+        let len = rest.find(|c| !predicate(c)).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    fn parse_or(&mut self) -> Result<AttributeFilter> {
+        let mut filters = vec![self.parse_and()?];
+        while self.eat("||") {
+            filters.push(self.parse_and()?);
+        }
+        Ok(if filters.len() == 1 {
+            filters.pop().unwrap()
+        } else {
+            AttributeFilter::Or(filters)
+        })
+    }
+
+    fn parse_and(&mut self) -> Result<AttributeFilter> {
+        let mut filters = vec![self.parse_unary()?];
+        while self.eat("&&") {
+            filters.push(self.parse_unary()?);
+        }
+        Ok(if filters.len() == 1 {
+            filters.pop().unwrap()
+        } else {
+            AttributeFilter::And(filters)
+        })
+    }
+
+    fn parse_unary(&mut self) -> Result<AttributeFilter> {
+        if self.eat("!") {
+            Ok(AttributeFilter::Not(Box::new(self.parse_unary()?)))
+        } else if self.eat("(") {
+            let filter = self.parse_or()?;
+            self.expect(")")?;
+            Ok(filter)
+        } else {
+            self.parse_predicate()
+        }
+    }
+
+    fn parse_predicate(&mut self) -> Result<AttributeFilter> {
+        let selector = self.parse_selector()?;
+        // Two-character operators need to be tried first.
+        let interval = |lower, upper| Interval { lower, upper };
+        if self.eat("<=") {
+            let v = self.parse_number()?;
+            Ok(AttributeFilter::InInterval(
+                selector,
+                interval(Bound::Unbounded, Bound::Included(v)),
+            ))
+        } else if self.eat(">=") {
+            let v = self.parse_number()?;
+            Ok(AttributeFilter::InInterval(
+                selector,
+                interval(Bound::Included(v), Bound::Unbounded),
+            ))
+        } else if self.eat("==") {
+            Ok(AttributeFilter::InSet(selector, vec![self.parse_number()?]))
+        } else if self.eat("!=") {
+            let equal = AttributeFilter::InSet(selector, vec![self.parse_number()?]);
+            Ok(AttributeFilter::Not(Box::new(equal)))
+        } else if self.eat("<") {
+            let v = self.parse_number()?;
+            Ok(AttributeFilter::InInterval(
+                selector,
+                interval(Bound::Unbounded, Bound::Excluded(v)),
+            ))
+        } else if self.eat(">") {
+            let v = self.parse_number()?;
+            Ok(AttributeFilter::InInterval(
+                selector,
+                interval(Bound::Excluded(v), Bound::Unbounded),
+            ))
+        } else if self.take_while(|c| c.is_ascii_alphabetic()) == "in" {
+            if self.eat("{") {
+                self.parse_set(selector)
+            } else {
+                self.parse_interval(selector)
+            }
+        } else {
+            Err(self.error("a comparison or 'in'"))
+        }
+    }
+
+    fn parse_selector(&mut self) -> Result<AttributeSelector> {
+        let name = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+        if name.is_empty() {
+            return Err(self.error("an attribute name"));
+        }
+        let channel = if self.eat("[") {
+            let channel = self
+                .take_while(|c| c.is_ascii_digit())
+                .parse()
+                .map_err(|_| self.error("a channel index"))?;
+            self.expect("]")?;
+            Some(channel)
+        } else {
+            None
+        };
+        Ok(AttributeSelector {
+            name: name.to_string(),
+            channel,
+        })
+    }
+
+    fn parse_number(&mut self) -> Result<f64> {
+        self.try_parse_number()?
+            .ok_or_else(|| self.error("a number"))
+    }
+
+    /// Returns `None` if there is no number at the current position.
+    fn try_parse_number(&mut self) -> Result<Option<f64>> {
+        let number = self.take_while(|c| c.is_ascii_digit() || "+-.eE".contains(c));
+        if number.is_empty() {
+            return Ok(None);
+        }
+        number.parse().map(Some).map_err(|_| self.error("a number"))
+    }
+
+    fn parse_set(&mut self, selector: AttributeSelector) -> Result<AttributeFilter> {
+        let mut values = Vec::new();
+        if !self.eat("}") {
+            loop {
+                values.push(self.parse_number()?);
+                if self.eat("}") {
+                    break;
+                }
+                self.expect(",")?;
+            }
+        }
+        Ok(AttributeFilter::InSet(selector, values))
+    }
+
+    fn parse_interval(&mut self, selector: AttributeSelector) -> Result<AttributeFilter> {
+        let lower_inclusive = if self.eat("[") {
+            true
+        } else if self.eat("(") {
+            false
+        } else {
+            return Err(self.error("'[', '(' or '{'"));
+        };
+        let lower = self.try_parse_number()?;
+        self.expect(",")?;
+        let upper = self.try_parse_number()?;
+        let upper_inclusive = if self.eat("]") {
+            true
+        } else if self.eat(")") {
+            false
+        } else {
+            return Err(self.error("']' or ')'"));
+        };
+        let bound = |value: Option<f64>, inclusive| match value {
+            Some(v) if inclusive => Bound::Included(v),
+            Some(v) => Bound::Excluded(v),
+            None => Bound::Unbounded,
+        };
+        Ok(AttributeFilter::InInterval(
+            selector,
+            Interval {
+                lower: bound(lower, lower_inclusive),
+                upper: bound(upper, upper_inclusive),
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::{Point3, Vector3};
+
+    fn test_batch() -> PointsBatch {
+        PointsBatch {
+            position: vec![Point3::origin(); 4],
+            attributes: vec![
+                (
+                    "classification".to_string(),
+                    AttributeData::U8(vec![2, 3, 6, 9]),
+                ),
+                (
+                    "intensity".to_string(),
+                    AttributeData::F32(vec![0.1, 0.5, 1.0, 2.0]),
+                ),
+                (
+                    "color".to_string(),
+                    AttributeData::U8Vec3(vec![
+                        Vector3::new(255, 0, 0),
+                        Vector3::new(0, 255, 0),
+                        Vector3::new(0, 0, 255),
+                        Vector3::new(200, 200, 200),
+                    ]),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        }
+    }
+
+    fn evaluate(filter: &str) -> Vec<bool> {
+        filter
+            .parse::<AttributeFilter>()
+            .unwrap()
+            .evaluate(&test_batch())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let filter: AttributeFilter = "classification in {2, 6} && !(intensity < 0.5)"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            filter,
+            AttributeFilter::And(vec![
+                AttributeFilter::InSet(AttributeSelector::new("classification"), vec![2.0, 6.0]),
+                AttributeFilter::Not(Box::new(AttributeFilter::InInterval(
+                    AttributeSelector::new("intensity"),
+                    Interval {
+                        lower: Bound::Unbounded,
+                        upper: Bound::Excluded(0.5),
+                    }
+                ))),
+            ])
+        );
+        assert_eq!(filter.attributes(), vec!["classification", "intensity"]);
+
+        let filter: AttributeFilter = "color[1] in (-1e2, 10]".parse().unwrap();
+        assert_eq!(
+            filter,
+            AttributeFilter::InInterval(
+                AttributeSelector::with_channel("color", 1),
+                Interval {
+                    lower: Bound::Excluded(-100.0),
+                    upper: Bound::Included(10.0),
+                }
+            )
+        );
+
+        assert!("intensity in [1, 2".parse::<AttributeFilter>().is_err());
+        assert!("intensity".parse::<AttributeFilter>().is_err());
+        assert!("intensity > 1 garbage".parse::<AttributeFilter>().is_err());
+    }
+
+    #[test]
+    fn test_evaluate() {
+        assert_eq!(
+            evaluate("classification in {2, 6, 9}"),
+            vec![true, false, true, true]
+        );
+        assert_eq!(
+            evaluate("intensity in [0.5, 2.0)"),
+            vec![false, true, true, false]
+        );
+        assert_eq!(
+            evaluate("intensity in (0.5, )"),
+            vec![false, false, true, true]
+        );
+        assert_eq!(
+            evaluate("classification == 3 || color[2] >= 200"),
+            vec![false, true, true, true]
+        );
+        assert_eq!(
+            evaluate("!(classification != 2) || intensity > 1.5 && color[0] == 200"),
+            vec![true, false, false, true]
+        );
+    }
+
+    #[test]
+    fn test_evaluate_errors() {
+        let batch = test_batch();
+        for filter in &[
+            "timestamp > 0",
+            "color > 0",
+            "color[3] > 0",
+            "intensity[0] > 0",
+        ] {
+            let filter: AttributeFilter = filter.parse().unwrap();
+            assert!(filter.evaluate(&batch).is_err());
+        }
+    }
+}
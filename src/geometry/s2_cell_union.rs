@@ -3,9 +3,10 @@ pub use s2::cellunion::CellUnion;
 
 use crate::geometry::Aabb;
 use crate::math::base::{HasAabbIntersector, IntersectAabb, PointCulling};
-use crate::math::sat::ConvexPolyhedron;
+use crate::math::sat::{ConvexPolyhedron, Intersector};
 use crate::math::FromPoint3;
-use nalgebra::Point3;
+use arrayvec::ArrayVec;
+use nalgebra::{Point3, Unit, Vector3};
 use s2::{cell::Cell, cellid::CellID, region::Region};
 
 /// Checks for an intersection between a list of cells and a polyhedron.
@@ -42,3 +43,76 @@ impl<'a> HasAabbIntersector<'a> for CellUnion {
         self.0.iter().map(Cell::from).collect()
     }
 }
+
+/// A convex polyhedron containing the part of an S2 cell between two distances from the earth's
+/// center. Its sides are the planes through the cell's edges and the center of the earth, and
+/// its inner and outer faces are perpendicular to the cell's center.
+#[derive(Debug, Clone)]
+pub struct S2CellVolume {
+    /// The directions of the cell's vertices, scaled so that they lie on the outer face.
+    outer_corners: [Vector3<f64>; 4],
+    /// The ratio between the distances of the inner and of the outer face to the earth's center.
+    inner_scale: f64,
+    center: Unit<Vector3<f64>>,
+}
+
+impl S2CellVolume {
+    pub fn new(cell: &Cell, min_radius: f64, max_radius: f64) -> Self {
+        let to_vector = |p: s2::point::Point| Vector3::new(p.0.x, p.0.y, p.0.z);
+        let center = Unit::new_normalize(to_vector(cell.center()));
+        let vertices = cell.vertices();
+        let cosines: Vec<f64> = vertices
+            .iter()
+            .map(|v| to_vector(*v).dot(&center))
+            .collect();
+        // Points of the cell are at most as far from its center as its farthest vertex.
+        let min_cosine = cosines.iter().copied().fold(1.0, f64::min);
+        let outer_corner = |i: usize| to_vector(vertices[i]) * max_radius / cosines[i];
+        Self {
+            outer_corners: [
+                outer_corner(0),
+                outer_corner(1),
+                outer_corner(2),
+                outer_corner(3),
+            ],
+            inner_scale: min_radius * min_cosine / max_radius,
+            center,
+        }
+    }
+}
+
+impl ConvexPolyhedron for S2CellVolume {
+    fn compute_corners(&self) -> [Point3<f64>; 8] {
+        let outer = |i: usize| Point3::from(self.outer_corners[i]);
+        let inner = |i: usize| Point3::from(self.outer_corners[i] * self.inner_scale);
+        [
+            inner(0),
+            inner(1),
+            inner(2),
+            inner(3),
+            outer(0),
+            outer(1),
+            outer(2),
+            outer(3),
+        ]
+    }
+
+    fn intersector(&self) -> Intersector {
+        let c = &self.outer_corners;
+        let mut edges = ArrayVec::new();
+        let mut face_normals = ArrayVec::new();
+        face_normals.push(self.center);
+        for i in 0..4 {
+            let next = (i + 1) % 4;
+            // The inner and outer face are parallel, so their edges are too.
+            edges.push(Unit::new_normalize(c[next] - c[i]));
+            edges.push(Unit::new_normalize(c[i]));
+            face_normals.push(Unit::new_normalize(c[i].cross(&c[next])));
+        }
+        Intersector {
+            corners: self.compute_corners(),
+            edges,
+            face_normals,
+        }
+    }
+}
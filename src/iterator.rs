@@ -1,12 +1,19 @@
+use crate::deduplication::{Deduplication, Deduplicator};
+use crate::downsampling::VoxelDownsampling;
 use crate::errors::*;
+use crate::filter::AttributeFilter;
 use crate::geometry::{Aabb, CellUnion, Frustum, Obb, WebMercatorRect};
-use crate::math::{AllPoints, ClosedInterval, PointCulling};
+use crate::math::{local_frame_from_lat_lng, AllPoints, PointCulling};
+use crate::ordering::OutputOrder;
 use crate::read_write::{Encoding, NodeIterator};
-use crate::{match_1d_attr_data, AttributeData, PointsBatch};
+use crate::PointsBatch;
 use crossbeam::deque::{Injector, Steal, Worker};
-use num_traits::ToPrimitive;
+use nalgebra::Isometry3;
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
 
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -38,6 +45,26 @@ impl PointLocation {
     }
 }
 
+/// The frame that the positions of a query result are expressed in. Point clouds are stored in
+/// the global frame, usually ECEF.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum OutputFrame {
+    /// Positions are transformed by this isometry, i.e. it is `output_from_global`.
+    Isometry(Isometry3<f64>),
+    /// The local east-north-up frame at the given latitude and longitude in degrees, for point
+    /// clouds in ECEF.
+    LocalEnu { lat: f64, lng: f64 },
+}
+
+impl OutputFrame {
+    pub fn output_from_global(&self) -> Isometry3<f64> {
+        match self {
+            OutputFrame::Isometry(output_from_global) => *output_from_global,
+            OutputFrame::LocalEnu { lat, lng } => local_frame_from_lat_lng(*lat, *lng),
+        }
+    }
+}
+
 /// This macro is an alternative to `get_point_culling()`, to be used where
 /// performance is important (i.e. in an inner loop). This can make a difference
 /// of 5-10 % in queries measuered by the `point_cloud_test` crate.
@@ -62,31 +89,130 @@ macro_rules! dispatch_point_location {
     }
 }
 
+/// A handle to stop a running query, e.g. from another thread. Clones share the same state.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    parent: Option<Box<CancellationToken>>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A new token that is also cancelled when this one is, but cancelling it does not affect
+    /// this one.
+    pub fn child(&self) -> Self {
+        Self {
+            cancelled: Arc::default(),
+            parent: Some(Box::new(self.clone())),
+        }
+    }
+
+    /// No more nodes will be read once this was called, and the query returns as soon as
+    /// possible.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+            || self
+                .parent
+                .as_ref()
+                .map_or(false, |parent| parent.is_cancelled())
+    }
+}
+
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct PointQuery<'a> {
     #[serde(borrow)]
     pub attributes: Vec<&'a str>,
     pub location: PointLocation,
-    #[serde(borrow)]
-    pub filter_intervals: HashMap<&'a str, ClosedInterval<f64>>,
+    /// Only points passing this filter are returned. All attributes it uses need to be
+    /// contained in `attributes`.
+    #[serde(default)]
+    pub filter: Option<AttributeFilter>,
+    /// If set, at most this many points are returned.
+    #[serde(default)]
+    pub max_points: Option<usize>,
+    /// If set, the points of each node are reduced to one point per voxel of the output frame.
+    /// Voxels that are cut by node boundaries can contain one point per node.
+    #[serde(default)]
+    pub voxel_downsampling: Option<VoxelDownsampling>,
+    /// If set, positions are returned in this frame instead of the frame of the point cloud.
+    /// The location is still given in the frame of the point cloud.
+    #[serde(default)]
+    pub output_frame: Option<OutputFrame>,
+    /// If set, the output is deterministic: Nodes are still read in parallel, but returned in a
+    /// fixed order. The nodes of each point cloud are sorted by node id, and the point clouds of
+    /// the same deduplication priority take turns, one node at a time. Duplicates are removed
+    /// in this order as well, so the first of them in the output is kept.
+    #[serde(default)]
+    pub order: Option<OutputOrder>,
+    /// If set, only nodes on this level or coarser ones are read. Octree nodes and the cells of
+    /// S2 cell pyramids hold a subsample of their children's points, so this reduces the level
+    /// of detail. Flat S2 cells on a finer level are left out completely.
+    #[serde(default)]
+    pub max_level: Option<u64>,
+    #[serde(skip)]
+    pub cancellation_token: CancellationToken,
+}
+
+impl<'a> PointQuery<'a> {
+    /// A copy of this query, but with different attributes. Also useful to get a query that
+    /// does not borrow anything, by passing no attributes.
+    pub fn with_attributes<'b>(&self, attributes: Vec<&'b str>) -> PointQuery<'b> {
+        PointQuery {
+            attributes,
+            location: self.location.clone(),
+            filter: self.filter.clone(),
+            max_points: self.max_points,
+            voxel_downsampling: self.voxel_downsampling,
+            output_frame: self.output_frame.clone(),
+            order: self.order.clone(),
+            max_level: self.max_level,
+            cancellation_token: self.cancellation_token.clone(),
+        }
+    }
 }
 
 /// Iterator over the points of a point cloud node within the specified PointCulling
 /// Essentially a specialized version of the Filter iterator adapter
 pub struct FilteredIterator<'a, Culling: PointCulling> {
     pub culling: Culling,
-    pub filter_intervals: &'a HashMap<&'a str, ClosedInterval<f64>>,
+    pub filter: Option<&'a AttributeFilter>,
     pub node_iterator: NodeIterator,
 }
 
-fn update_keep<T>(keep: &mut [bool], data: &[T], interval: &ClosedInterval<f64>)
-where
-    T: ToPrimitive,
-{
-    for (k, v) in keep.iter_mut().zip(data) {
-        if let Some(v) = v.to_f64() {
-            *k &= interval.contains(v);
+impl<'a, Culling: PointCulling> FilteredIterator<'a, Culling> {
+    fn filter_batch(&self, mut batch: PointsBatch) -> Result<PointsBatch> {
+        let culling = &self.culling;
+        let mut keep: Vec<bool> = batch
+            .position
+            .iter()
+            .map(|pos| culling.contains(&pos))
+            .collect();
+        if let Some(filter) = self.filter {
+            for (k, f) in keep.iter_mut().zip(filter.evaluate(&batch)?) {
+                *k &= f;
+            }
         }
+        batch.retain(&keep);
+        Ok(batch)
+    }
+
+    /// Like `try_for_each()`, but errors while reading the node are returned instead of
+    /// panicking.
+    pub fn try_for_each_batch<F>(mut self, mut func: F) -> Result<()>
+    where
+        F: FnMut(PointsBatch) -> Result<()>,
+    {
+        while let Some(batch) = self.node_iterator.try_next()? {
+            func(self.filter_batch(batch)?)?;
+        }
+        Ok(())
     }
 }
 
@@ -94,28 +220,11 @@ impl<'a, Culling: PointCulling> Iterator for FilteredIterator<'a, Culling> {
     type Item = PointsBatch;
 
     fn next(&mut self) -> Option<PointsBatch> {
-        let culling = &self.culling;
-        self.node_iterator.next().map(|mut batch| {
-            let mut keep: Vec<bool> = batch
-                .position
-                .iter()
-                .map(|pos| culling.contains(&pos))
-                .collect();
-            macro_rules! rhs {
-                ($dtype:ident, $data:ident, $interval:expr) => {
-                    update_keep(&mut keep, $data, $interval)
-                };
-            }
-            for (attrib, interval) in self.filter_intervals {
-                let attr_data = batch
-                    .attributes
-                    .get(*attrib)
-                    .expect("Filter attribute needs to be specified as query attribute.");
-                match_1d_attr_data!(attr_data, rhs, interval)
-            }
-            batch.retain(&keep);
-            batch
-        })
+        let batch = self.node_iterator.next()?;
+        Some(
+            self.filter_batch(batch)
+                .expect("Couldn't evaluate the attribute filter."),
+        )
     }
 }
 
@@ -168,7 +277,8 @@ where
 // TODO(nnmm): Move this somewhere else
 pub trait PointCloud: Sync {
     type Id: ToString + Send + Copy;
-    fn nodes_in_location(&self, location: &PointLocation) -> Vec<Self::Id>;
+    /// The nodes that can contain points in this location, only up to `max_level` if it is set.
+    fn nodes_in_location(&self, location: &PointLocation, max_level: Option<u64>) -> Vec<Self::Id>;
     fn encoding_for_node(&self, id: Self::Id) -> Encoding;
     /// Return all points in the selected node.
     fn points_in_node(
@@ -192,23 +302,50 @@ pub trait PointCloud: Sync {
     where
         F: FnMut(PointsBatch) -> Result<()>,
     {
-        let filter_intervals = &query.filter_intervals;
+        let filter = query.filter.as_ref();
         let node_iterator = self.points_in_node(&query.attributes, node_id, batch_size)?;
 
-        dispatch_point_location!(
-            stream,
-            &query.location,
-            filter_intervals,
-            node_iterator,
-            callback
-        )
+        let output_from_global = query
+            .output_frame
+            .as_ref()
+            .map(OutputFrame::output_from_global);
+        let to_output_frame = |mut batch: PointsBatch| {
+            if let Some(output_from_global) = &output_from_global {
+                for p in &mut batch.position {
+                    *p = output_from_global.transform_point(p);
+                }
+            }
+            batch
+        };
+
+        let mut callback = callback;
+        match &query.voxel_downsampling {
+            None => {
+                let callback = |batch| callback(to_output_frame(batch));
+                dispatch_point_location!(stream, &query.location, filter, node_iterator, callback)
+            }
+            Some(voxel_downsampling) => {
+                voxel_downsampling.validate()?;
+                // The whole node needs to be read before it can be downsampled.
+                let mut node_points = PointsBatch {
+                    position: Vec::new(),
+                    attributes: BTreeMap::new(),
+                };
+                let collect = |batch| Ok(node_points.append(&mut to_output_frame(batch))?);
+                dispatch_point_location!(stream, &query.location, filter, node_iterator, collect)?;
+                if node_points.position.is_empty() {
+                    return Ok(());
+                }
+                callback(voxel_downsampling.downsample(&node_points))
+            }
+        }
     }
 }
 
 // TODO(nnmm): Instead of having this helper function, make stream_points_for_query_in_node
 // accept a T: PointCulling, so we can dispatch to this function directly
 fn stream<'a, T: PointCulling + Clone, F: FnMut(PointsBatch) -> Result<()>>(
-    intv: &'a HashMap<&'a str, ClosedInterval<f64>>,
+    filter: Option<&'a AttributeFilter>,
     itr: NodeIterator,
     callback: F,
     culling: &T,
@@ -216,10 +353,42 @@ fn stream<'a, T: PointCulling + Clone, F: FnMut(PointsBatch) -> Result<()>>(
     let culling: T = culling.clone();
     FilteredIterator {
         culling,
-        filter_intervals: intv,
+        filter,
         node_iterator: itr,
     }
-    .try_for_each(callback)
+    .try_for_each_batch(callback)
+}
+
+/// How often waiting threads check the cancellation token, which can't wake them up.
+const CANCELLATION_CHECK_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Lets the threads of a query wait for each other without busy waiting. Every thread that
+/// changes state another thread may wait for has to call `notify`.
+#[derive(Default)]
+struct Progress {
+    mutex: Mutex<()>,
+    changed: Condvar,
+}
+
+impl Progress {
+    fn notify(&self) {
+        // Taking the lock ensures that no thread is between checking its condition and starting
+        // to wait, which would miss the notification.
+        drop(self.mutex.lock().unwrap());
+        self.changed.notify_all();
+    }
+
+    /// Blocks as long as `condition` holds.
+    fn wait_while(&self, mut condition: impl FnMut() -> bool) {
+        let mut guard = self.mutex.lock().unwrap();
+        while condition() {
+            guard = self
+                .changed
+                .wait_timeout(guard, CANCELLATION_CHECK_INTERVAL)
+                .unwrap()
+                .0;
+        }
+    }
 }
 
 /// Iterator on point batches
@@ -229,6 +398,9 @@ pub struct ParallelIterator<'a, C> {
     batch_size: usize,
     num_threads: usize,
     buffer_size: usize,
+    skip_failed_nodes: bool,
+    failed_nodes: Vec<Error>,
+    deduplication: Option<&'a Deduplication>,
 }
 
 impl<'a, C> ParallelIterator<'a, C>
@@ -248,77 +420,295 @@ where
             batch_size,
             num_threads,
             buffer_size,
+            skip_failed_nodes: false,
+            failed_nodes: Vec::new(),
+            deduplication: None,
         }
     }
 
+    /// By default, the first node that cannot be read aborts the iteration and its error is
+    /// returned. If `skip_failed_nodes` is set, such nodes are skipped instead and their errors
+    /// can be retrieved with `failed_nodes()` afterwards. Points that were read from a node before
+    /// it failed may still have been passed on.
+    pub fn skip_failed_nodes(mut self, skip_failed_nodes: bool) -> Self {
+        self.skip_failed_nodes = skip_failed_nodes;
+        self
+    }
+
+    /// Removes duplicate points of different point clouds. Point clouds with a lower priority
+    /// are only read once all point clouds with a higher priority have been read.
+    pub fn deduplication(mut self, deduplication: &'a Deduplication) -> Self {
+        self.deduplication = Some(deduplication);
+        self
+    }
+
+    /// The errors of the nodes that were skipped in the last call to `try_for_each_batch`.
+    /// Each of them has the kind `ErrorKind::NodeReadFailed`.
+    pub fn failed_nodes(&self) -> &[Error] {
+        &self.failed_nodes
+    }
+
+    pub fn into_failed_nodes(self) -> Vec<Error> {
+        self.failed_nodes
+    }
+
     /// compute a function while iterating on a batch of points
-    pub fn try_for_each_batch<F>(&mut self, func: F) -> Result<()>
+    /// Stops early without an error if the query's `max_points` are reached or its cancellation
+    /// token is cancelled.
+    pub fn try_for_each_batch<F>(&mut self, mut func: F) -> Result<()>
     where
         F: FnMut(PointsBatch) -> Result<()>,
     {
-        // get thread safe fifo
-        let jobs = Injector::<(&C, C::Id)>::new();
-        let mut number_of_jobs = 0;
-        self.point_clouds
-            .iter()
-            .flat_map(|point_cloud| {
-                std::iter::repeat(point_cloud)
-                    .zip(point_cloud.nodes_in_location(&self.point_query.location))
-            })
-            .for_each(|(node_id, point_cloud)| {
-                jobs.push((node_id, point_cloud));
-                number_of_jobs += 1;
-            });
+        let priority_levels = match self.deduplication {
+            Some(deduplication) => {
+                deduplication.validate(&self.point_query.attributes)?;
+                deduplication.priority_levels(self.point_clouds.len())?
+            }
+            None => vec![(0..self.point_clouds.len()).collect()],
+        };
+        let deduplicator = self.deduplication.map(Deduplicator::new);
+        let order = self.point_query.order.as_ref();
+        if let Some(order) = order {
+            order.validate(&self.point_query.attributes)?;
+        }
+        if let Some(voxel_downsampling) = &self.point_query.voxel_downsampling {
+            voxel_downsampling.validate()?;
+        }
+
+        // get thread safe fifo, one per priority level
+        // The nodes of the point clouds are interleaved, so that all point clouds are read from
+        // at the same time instead of one after the other. Every node gets a sequence number,
+        // which is the order in which nodes are returned if the output is ordered.
+        let mut jobs = Vec::with_capacity(priority_levels.len());
+        // The point cloud of each sequence number.
+        let mut job_point_clouds = Vec::new();
+        // The number of nodes per priority level that have not been read completely yet.
+        let mut num_pending_jobs = Vec::with_capacity(priority_levels.len());
+        let mut num_jobs = 0;
+        for point_cloud_indices in priority_levels {
+            let level_jobs = Injector::<(usize, usize, C::Id)>::new();
+            let mut num_level_jobs = 0;
+            let mut nodes_per_point_cloud: Vec<_> = point_cloud_indices
+                .into_iter()
+                .map(|point_cloud_index| {
+                    let mut nodes = self.point_clouds[point_cloud_index]
+                        .nodes_in_location(&self.point_query.location, self.point_query.max_level);
+                    if order.is_some() {
+                        nodes.sort_by_cached_key(|node_id| node_id.to_string());
+                    }
+                    (point_cloud_index, nodes.into_iter())
+                })
+                .collect();
+            let mut any_nodes_left = true;
+            while any_nodes_left {
+                any_nodes_left = false;
+                for (point_cloud_index, nodes) in &mut nodes_per_point_cloud {
+                    if let Some(node_id) = nodes.next() {
+                        level_jobs.push((num_jobs, *point_cloud_index, node_id));
+                        job_point_clouds.push(*point_cloud_index);
+                        num_jobs += 1;
+                        num_level_jobs += 1;
+                        any_nodes_left = true;
+                    }
+                }
+            }
+            jobs.push(level_jobs);
+            num_pending_jobs.push(AtomicUsize::new(num_level_jobs));
+        }
+
+        // Set as soon as the iteration should stop, either because a node failed or because the
+        // consumer returned an error.
+        let cancelled = AtomicBool::new(false);
+        let cancellation_token = &self.point_query.cancellation_token;
+        let max_points = self.point_query.max_points.unwrap_or(usize::MAX);
+        // Counts the points read by all workers, which can be more than the consumer receives.
+        // If the output is ordered, the workers can't know which points will be returned, so
+        // only the consumer side limits the number of points.
+        let num_points_read = AtomicUsize::new(0);
+        let worker_max_points = if order.is_some() {
+            usize::MAX
+        } else {
+            max_points
+        };
+        // If the output is ordered, the sequence number of the next node to be returned. Nodes
+        // are only read if their sequence number is less than `reorder_window` ahead of it, which
+        // bounds the number of nodes waiting to be reordered.
+        let num_jobs_returned = AtomicUsize::new(0);
+        let reorder_window = self.num_threads + self.buffer_size;
+        let first_error: Mutex<Option<Error>> = Mutex::new(None);
+        let failed_nodes: Mutex<Vec<Error>> = Mutex::new(Vec::new());
+        // Notified whenever a priority level is done, the ordered output moves on or a thread
+        // stops early.
+        let progress = Progress::default();
 
         // operate on nodes with limited number of threads
-        crossbeam::scope(|s| {
-            let (tx, rx) = crossbeam::channel::bounded::<PointsBatch>(self.buffer_size);
+        let result = crossbeam::scope(|s| {
+            let (tx, rx) = crossbeam::channel::bounded::<(usize, PointsBatch)>(self.buffer_size);
             for curr_thread in 0..self.num_threads {
                 let tx = tx.clone();
+                let point_clouds = self.point_clouds;
                 let point_query = &self.point_query;
                 let batch_size = self.batch_size;
+                let skip_failed_nodes = self.skip_failed_nodes;
                 let worker = Worker::new_fifo();
                 let jobs = &jobs;
+                let num_pending_jobs = &num_pending_jobs;
+                let deduplicator = deduplicator.as_ref();
+                let cancelled = &cancelled;
+                let num_points_read = &num_points_read;
+                let num_jobs_returned = &num_jobs_returned;
+                let first_error = &first_error;
+                let failed_nodes = &failed_nodes;
+                let progress = &progress;
 
                 s.spawn(move |_| {
-                    let send_func = |batch: PointsBatch| match tx.send(batch) {
-                        Ok(_) => Ok(()),
-                        Err(e) => Err(ErrorKind::Channel(format!(
-                            "Thread {}: sending operation failed, nothing more to do {:?}",
-                            curr_thread, e,
-                        ))
-                        .into()),
+                    let send = |sequence_number: usize, batch: PointsBatch| {
+                        if cancelled.load(Ordering::SeqCst) || cancellation_token.is_cancelled() {
+                            return Err(ErrorKind::Channel(format!(
+                                "Thread {}: iteration was cancelled",
+                                curr_thread
+                            ))
+                            .into());
+                        }
+                        match tx.send((sequence_number, batch)) {
+                            Ok(_) => Ok(()),
+                            Err(e) => Err(ErrorKind::Channel(format!(
+                                "Thread {}: sending operation failed, nothing more to do {:?}",
+                                curr_thread, e,
+                            ))
+                            .into()),
+                        }
+                    };
+                    // Without ordering, the sequence numbers are not needed.
+                    let send_func = |batch: PointsBatch| send(0, batch);
+                    let cancel_with = |error: Error| {
+                        cancelled.store(true, Ordering::SeqCst);
+                        first_error.lock().unwrap().get_or_insert(error);
+                        progress.notify();
                     };
 
                     // One `PointStream` per thread vs one per node allows to send more full point batches
                     let mut point_stream = PointStream::new(batch_size, &send_func);
 
-                    while let Some((point_cloud, node_id)) = worker.pop().or_else(|| {
-                        std::iter::repeat_with(|| jobs.steal_batch_and_pop(&worker))
-                            .find(|task| !task.is_retry())
-                            .and_then(Steal::success)
-                    }) {
-                        // executing on the available next task if the function still requires it
-                        match point_cloud.stream_points_for_query_in_node(
-                            &point_query,
-                            node_id,
-                            batch_size,
-                            |batch| point_stream.push_points_and_callback(batch),
-                        ) {
-                            Ok(_) => continue,
-                            Err(ref e) => {
-                                match e.kind() {
-                                    ErrorKind::Channel(ref _s) => break, // done with the function computation
-                                    _ => panic!("ParallelIterator: Thread error {}", e), //some other error
+                    let stop = || {
+                        cancelled.load(Ordering::SeqCst)
+                            || cancellation_token.is_cancelled()
+                            || num_points_read.load(Ordering::SeqCst) >= worker_max_points
+                    };
+
+                    'levels: for (level_jobs, num_pending) in jobs.iter().zip(num_pending_jobs) {
+                        while let Some((sequence_number, point_cloud_index, node_id)) =
+                            worker.pop().or_else(|| {
+                                std::iter::repeat_with(|| level_jobs.steal_batch_and_pop(&worker))
+                                    .find(|task| !task.is_retry())
+                                    .and_then(Steal::success)
+                            })
+                        {
+                            if order.is_some() {
+                                progress.wait_while(|| {
+                                    sequence_number
+                                        >= num_jobs_returned.load(Ordering::SeqCst) + reorder_window
+                                        && !stop()
+                                });
+                            }
+                            if stop() {
+                                break 'levels;
+                            }
+                            // If the output is ordered, the whole node is sent at once.
+                            let mut node_points = order.map(|_| PointsBatch {
+                                position: Vec::new(),
+                                attributes: BTreeMap::new(),
+                            });
+                            // executing on the available next task if the function still requires it
+                            let result = point_clouds[point_cloud_index]
+                                .stream_points_for_query_in_node(
+                                    &point_query,
+                                    node_id,
+                                    batch_size,
+                                    |batch| {
+                                        // If the output is ordered, duplicates are removed
+                                        // in that order by the consumer side.
+                                        let mut batch = match deduplicator {
+                                            Some(deduplicator) if order.is_none() => {
+                                                deduplicator
+                                                    .retain_unique(point_cloud_index, batch)?
+                                            }
+                                            _ => batch,
+                                        };
+                                        let num_points = batch.position.len();
+                                        match &mut node_points {
+                                            Some(node_points) => node_points.append(&mut batch)?,
+                                            None => point_stream.push_points_and_callback(batch)?,
+                                        }
+                                        if num_points_read.fetch_add(num_points, Ordering::SeqCst)
+                                            + num_points
+                                            >= worker_max_points
+                                        {
+                                            return Err(ErrorKind::Channel(format!(
+                                                "Thread {}: maximum number of points reached",
+                                                curr_thread
+                                            ))
+                                            .into());
+                                        }
+                                        Ok(())
+                                    },
+                                );
+                            if num_pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+                                progress.notify();
+                            }
+                            let result = match (result, node_points, order) {
+                                (Ok(_), Some(mut node_points), Some(order)) => order
+                                    .sort_node_points(&mut node_points)
+                                    .and_then(|_| send(sequence_number, node_points)),
+                                (result, _, _) => result,
+                            };
+                            match result {
+                                Ok(_) => (),
+                                // done with the function computation
+                                Err(Error(ErrorKind::Channel(_), _)) => break 'levels,
+                                Err(e) => {
+                                    let e = Error::with_chain(
+                                        e,
+                                        ErrorKind::NodeReadFailed(
+                                            point_cloud_index,
+                                            node_id.to_string(),
+                                        ),
+                                    );
+                                    if !skip_failed_nodes {
+                                        cancel_with(e);
+                                        break 'levels;
+                                    }
+                                    failed_nodes.lock().unwrap().push(e);
+                                    // The node is skipped, but its sequence number is needed to
+                                    // continue with the next node.
+                                    if order.is_some() {
+                                        let empty = PointsBatch {
+                                            position: Vec::new(),
+                                            attributes: BTreeMap::new(),
+                                        };
+                                        if send(sequence_number, empty).is_err() {
+                                            break 'levels;
+                                        }
+                                    }
                                 }
                             }
                         }
+                        // The next priority level may only be started once the other threads
+                        // are done with this one, so that the deduplicator knows all points of
+                        // higher priority.
+                        progress.wait_while(|| num_pending.load(Ordering::SeqCst) > 0 && !stop());
+                        if num_pending.load(Ordering::SeqCst) > 0 {
+                            break 'levels;
+                        }
                     }
+                    // If this thread stopped early, the jobs it left will never be done, so threads
+                    // waiting for them have to check whether to stop.
+                    progress.notify();
                     // last batch of points: calling callback
-                    if let Err(ref e) = point_stream.callback() {
+                    if let Err(e) = point_stream.callback() {
                         match e.kind() {
                             ErrorKind::Channel(ref _s) => (), // done with the function computation
-                            _ => panic!("ParallelIterator: Thread error {}", e), //some other error
+                            _ => cancel_with(e),
                         }
                     }
                 });
@@ -327,8 +717,82 @@ where
             drop(tx);
 
             // receiver collects all the messages
-            rx.iter().try_for_each(func)
+            let mut num_points_left = max_points;
+            // Passes a batch on to `func`, returns whether more points are wanted.
+            let mut emit = |mut batch: PointsBatch| -> Result<bool> {
+                if batch.position.len() > num_points_left {
+                    batch.split_off(num_points_left);
+                }
+                num_points_left -= batch.position.len();
+                func(batch)?;
+                Ok(num_points_left > 0)
+            };
+            let mut receive = || -> Result<()> {
+                if max_points == 0 {
+                    return Ok(());
+                }
+                // Nodes that arrived before their predecessors, by sequence number.
+                let mut reorder_buffer = BTreeMap::new();
+                // Points of ordered nodes that don't fill a batch yet.
+                let mut ordered_points = PointsBatch {
+                    position: Vec::new(),
+                    attributes: BTreeMap::new(),
+                };
+                for (sequence_number, batch) in rx.iter() {
+                    if cancelled.load(Ordering::SeqCst) || cancellation_token.is_cancelled() {
+                        return Ok(());
+                    }
+                    if order.is_none() {
+                        if !emit(batch)? {
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                    reorder_buffer.insert(sequence_number, batch);
+                    let mut next = num_jobs_returned.load(Ordering::SeqCst);
+                    while let Some(mut batch) = reorder_buffer.remove(&next) {
+                        next += 1;
+                        num_jobs_returned.store(next, Ordering::SeqCst);
+                        progress.notify();
+                        // Empty nodes don't have any attributes.
+                        if batch.position.is_empty() {
+                            continue;
+                        }
+                        if let Some(deduplicator) = &deduplicator {
+                            batch =
+                                deduplicator.retain_unique(job_point_clouds[next - 1], batch)?;
+                        }
+                        ordered_points.append(&mut batch)?;
+                        while ordered_points.position.len() >= self.batch_size {
+                            let rest = ordered_points.split_off(self.batch_size);
+                            if !emit(std::mem::replace(&mut ordered_points, rest))? {
+                                return Ok(());
+                            }
+                        }
+                    }
+                }
+                if !ordered_points.position.is_empty()
+                    && !cancelled.load(Ordering::SeqCst)
+                    && !cancellation_token.is_cancelled()
+                {
+                    emit(ordered_points)?;
+                }
+                Ok(())
+            };
+            let result = receive();
+            cancelled.store(true, Ordering::SeqCst);
+            progress.notify();
+            result
         })
-        .expect("ParallelIterator: Panic in try_for_each_batch child thread")
+        .unwrap_or_else(|_| {
+            Err("ParallelIterator: Panic in try_for_each_batch child thread".into())
+        });
+
+        self.failed_nodes = failed_nodes.into_inner().unwrap();
+        result?;
+        match first_error.into_inner().unwrap() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
     }
 }
@@ -21,18 +21,22 @@ pub mod math;
 pub mod attributes;
 pub mod color;
 pub mod data_provider;
+pub mod deduplication;
+pub mod downsampling;
 // Workaround for https://github.com/rust-lang-nursery/error-chain/issues/254
 #[allow(deprecated)]
 pub mod errors;
+pub mod filter;
 pub mod geometry;
 #[macro_use]
 pub mod iterator;
 pub mod octree;
+pub mod ordering;
 pub mod read_write;
 pub mod s2_cells;
 pub mod utils;
 
-use errors::Result;
+use errors::{ErrorKind, Result};
 use nalgebra::Point3;
 use std::collections::{BTreeMap, HashMap};
 use std::convert::{TryFrom, TryInto};
@@ -45,9 +49,33 @@ use std::convert::{TryFrom, TryInto};
 // We are able to convert the proto on read, so the tools can still read version 9/10/11.
 // Version 12 -> 13: Change back bounding box from OctreeMeta to Meta.
 // We are able to convert the proto on read, so the tools can still read version 9/10/11/12.
-pub const CURRENT_VERSION: i32 = 13;
+// Version 13 -> 14: Node blobs can be compressed (Meta.attribute_compression) and attributes can be
+// encoded (Meta.attribute_encoding), which older tools would read as raw data. Version 13 point
+// clouds are never compressed or encoded, so they can still be read.
+pub const CURRENT_VERSION: i32 = 14;
 pub const META_FILENAME: &str = "meta.pb";
 
+/// Version 13 point clouds with compressed nodes or encoded attributes would be misread by the
+/// tools of that version.
+pub(crate) fn check_node_format_version(meta: &proto::Meta) -> Result<()> {
+    let compressed = meta
+        .get_attribute_compression()
+        .values()
+        .any(|compression| *compression != proto::Compression::UNCOMPRESSED);
+    let encoded = meta
+        .get_attribute_encoding()
+        .values()
+        .any(|encoding| encoding.has_fixed_point() || encoding.delta);
+    if meta.version < 14 && (compressed || encoded) {
+        return Err(ErrorKind::InvalidInput(format!(
+            "Compressed nodes and encoded attributes are not supported with version {}.",
+            meta.version
+        ))
+        .into());
+    }
+    Ok(())
+}
+
 /// size for batch
 pub const NUM_POINTS_PER_BATCH: usize = 500_000;
 
@@ -81,6 +109,8 @@ pub fn attribute_extension(attribute: &str) -> &str {
 
 trait PointCloudMeta {
     fn attribute_data_types(&self) -> &HashMap<String, AttributeDataType>;
+    fn attribute_compression(&self) -> &read_write::AttributeCompression;
+    fn attribute_encodings(&self) -> &read_write::AttributeEncodings;
     fn attribute_data_types_for(
         &self,
         attributes: &[&str],
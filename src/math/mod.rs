@@ -89,6 +89,16 @@ where
     }
 }
 
+impl<T: Copy> ClosedInterval<T> {
+    pub fn lower_bound(&self) -> T {
+        self.lower_bound
+    }
+
+    pub fn upper_bound(&self) -> T {
+        self.upper_bound
+    }
+}
+
 impl<T> FromStr for ClosedInterval<T>
 where
     T: std::str::FromStr,
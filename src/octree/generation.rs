@@ -12,27 +12,25 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::data_provider::OnDiskDataProvider;
+use crate::data_provider::{blob_name, DataSink, OnDiskDataProvider};
 use crate::errors::*;
 use crate::geometry::{Aabb, Cube};
 use crate::octree::{self, to_meta_proto, to_node_proto, ChildIndex, NodeId, OctreeMeta};
 use crate::proto;
 use crate::read_write::{
-    attempt_increasing_rlimit_to_max, Encoding, NodeIterator, NodeWriter, OpenMode, PlyIterator,
-    PositionEncoding, RawNodeWriter,
+    attempt_increasing_rlimit_to_max, AttributeCompression, AttributeEncodings, Encoding,
+    NodeIterator, NodeWriter, OpenMode, PlyIterator, PositionEncoding, RawNodeWriter,
 };
 use crate::utils::create_progress_bar;
-use crate::META_FILENAME;
 use crate::{AttributeDataType, NumberOfPoints, PointCloudMeta, PointsBatch, NUM_POINTS_PER_BATCH};
 use fnv::{FnvHashMap, FnvHashSet};
-use protobuf::Message;
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use rayon::Scope;
 use std::cmp;
 use std::collections::HashMap;
-use std::fs::{self, File};
-use std::io::BufWriter;
+use std::fs;
 use std::path::Path;
+use std::sync::Arc;
 
 const MAX_POINTS_PER_NODE: i64 = 100_000;
 
@@ -115,8 +113,10 @@ where
         }
         let c = c.unwrap();
         let child_id = node_id.get_child_id(octree::ChildIndex::from_u8(child_index as u8));
+        let num_written = c.num_written();
+        c.finish().unwrap();
 
-        if should_split_node(&child_id, c.num_written(), octree_meta) {
+        if should_split_node(&child_id, num_written, octree_meta) {
             split_nodes.push(child_id);
         } else {
             leaf_nodes.push(child_id);
@@ -167,6 +167,8 @@ fn split_node<'a, P>(
             let stream = NodeIterator::from_data_provider(
                 octree_data_provider,
                 attribute_data_types,
+                &AttributeCompression::default(),
+                &AttributeEncodings::default(),
                 octree_meta.encoding_for_node(child_id),
                 &child_id,
                 octree_data_provider
@@ -192,15 +194,30 @@ fn split_node<'a, P>(
     }
 }
 
+// Nodes are finished once their parent was subsampled, so they are moved from the working
+// directory into the data sink. The root is written into the data sink directly. Only the data
+// sink is compressed and encoded, since the working directory derives the number of points from
+// file sizes.
 fn subsample_children_into(
     octree_data_provider: &OnDiskDataProvider,
+    data_sink: &Arc<dyn DataSink>,
     octree_meta: &octree::OctreeMeta,
     attribute_data_types: &HashMap<String, AttributeDataType>,
     node_id: &octree::NodeId,
     nodes_sender: &crossbeam::channel::Sender<(octree::NodeId, i64)>,
 ) -> Result<()> {
-    let mut parent_writer =
-        RawNodeWriter::from_data_provider(octree_data_provider, octree_meta, node_id);
+    let mut parent_writer = if node_id.level() == 0 {
+        RawNodeWriter::with_data_sink(
+            Arc::clone(data_sink),
+            &node_id.to_string(),
+            octree_meta.encoding_for_node(*node_id),
+            OpenMode::Truncate,
+            octree_meta.attribute_compression().clone(),
+            octree_meta.attribute_encodings().clone(),
+        )
+    } else {
+        RawNodeWriter::from_data_provider(octree_data_provider, octree_meta, node_id)
+    };
     for i in 0..8 {
         let child_id = node_id.get_child_id(octree::ChildIndex::from_u8(i));
         let num_points = match octree_data_provider.number_of_points(&child_id.to_string()) {
@@ -211,16 +228,23 @@ fn subsample_children_into(
         let mut node_iterator = NodeIterator::from_data_provider(
             octree_data_provider,
             attribute_data_types,
+            &AttributeCompression::default(),
+            &AttributeEncodings::default(),
             octree_meta.encoding_for_node(child_id),
             &child_id,
             num_points as usize,
             NUM_POINTS_PER_BATCH,
         )?;
 
-        // We read all points into memory, because the new node writer will rewrite this child's
-        // file(s).
+        // We read all points into memory, because the working directory and the data sink might
+        // be the same, in which case the new node writer rewrites this child's file(s).
         let mut batch = node_iterator.next().unwrap();
         node_iterator.for_each(|mut b| batch.append(&mut b).unwrap());
+        let child_name = child_id.to_string();
+        for attribute in std::iter::once("position").chain(batch.attributes.keys().map(|a| &a[..]))
+        {
+            octree_data_provider.remove_blob(&blob_name(&child_name, attribute))?;
+        }
         let (keep_parent, keep_child): (Vec<bool>, Vec<bool>) = (0..batch.position.len())
             .map(|i| {
                 let in_parent = i % 8 == 0;
@@ -232,15 +256,21 @@ fn subsample_children_into(
         let mut child_batch = batch;
         child_batch.retain(&keep_child);
 
-        let mut child_writer =
-            RawNodeWriter::from_data_provider(octree_data_provider, octree_meta, &child_id);
+        let mut child_writer = RawNodeWriter::with_data_sink(
+            Arc::clone(data_sink),
+            &child_name,
+            octree_meta.encoding_for_node(child_id),
+            OpenMode::Truncate,
+            octree_meta.attribute_compression().clone(),
+            octree_meta.attribute_encodings().clone(),
+        );
         parent_writer.write(&parent_batch)?;
         child_writer.write(&child_batch)?;
+        let num_child_points = child_writer.num_written();
+        child_writer.finish()?;
 
         // Update child.
-        nodes_sender
-            .send((child_id, child_writer.num_written()))
-            .unwrap();
+        nodes_sender.send((child_id, num_child_points)).unwrap();
     }
 
     // Make sure the root node is also tracked as an existing node.
@@ -249,6 +279,7 @@ fn subsample_children_into(
             .send((*node_id, parent_writer.num_written()))
             .unwrap();
     }
+    parent_writer.finish()?;
     Ok(())
 }
 
@@ -286,25 +317,81 @@ pub fn build_octree_from_file(
     )
 }
 
+/// Like `build_octree_from_file`, but writes the octree into the data sink. See
+/// `build_octree_into`.
+pub fn build_octree_from_file_into(
+    data_sink: Arc<dyn DataSink>,
+    working_directory: impl AsRef<Path>,
+    resolution: f64,
+    filename: impl AsRef<Path>,
+    attributes: &[&str],
+    attribute_compression: AttributeCompression,
+    attribute_encodings: AttributeEncodings,
+) {
+    let bounding_box = find_bounding_box(filename.as_ref());
+    let stream = PlyIterator::from_file(filename, NUM_POINTS_PER_BATCH).unwrap();
+    build_octree_into(
+        data_sink,
+        working_directory,
+        resolution,
+        bounding_box,
+        stream,
+        attributes,
+        attribute_compression,
+        attribute_encodings,
+    )
+}
+
 pub fn build_octree(
     output_directory: impl AsRef<Path>,
     resolution: f64,
     bounding_box: Aabb,
     input: impl Iterator<Item = PointsBatch> + NumberOfPoints + Send,
     attributes: &[&str],
+) {
+    let data_sink = Arc::new(OnDiskDataProvider::new(
+        output_directory.as_ref().to_path_buf(),
+    ));
+    build_octree_into(
+        data_sink,
+        output_directory,
+        resolution,
+        bounding_box,
+        input,
+        attributes,
+        AttributeCompression::default(),
+        AttributeEncodings::default(),
+    )
+}
+
+/// Builds the octree and writes it into the data sink. Nodes are split in the working directory
+/// and moved into the data sink once they are finished. The working directory may be the
+/// output directory of an `OnDiskDataProvider` data sink. The blobs in the data sink are
+/// compressed and encoded as given, which is recorded in the meta.
+#[allow(clippy::too_many_arguments)]
+pub fn build_octree_into(
+    data_sink: Arc<dyn DataSink>,
+    working_directory: impl AsRef<Path>,
+    resolution: f64,
+    bounding_box: Aabb,
+    input: impl Iterator<Item = PointsBatch> + NumberOfPoints + Send,
+    attributes: &[&str],
+    attribute_compression: AttributeCompression,
+    attribute_encodings: AttributeEncodings,
 ) {
     attempt_increasing_rlimit_to_max();
 
     let octree_meta =
-        &octree::OctreeMeta::new_with_standard_attributes(resolution, bounding_box.clone());
+        &octree::OctreeMeta::new_with_standard_attributes(resolution, bounding_box.clone())
+            .with_attribute_compression(attribute_compression)
+            .with_attribute_encodings(attribute_encodings);
     let attribute_data_types = &octree_meta.attribute_data_types_for(attributes).unwrap();
-    let octree_data_provider = OnDiskDataProvider {
-        directory: output_directory.as_ref().to_path_buf(),
-    };
+    let octree_data_provider = OnDiskDataProvider::new(working_directory.as_ref().to_path_buf());
     let octree_data_provider = &octree_data_provider;
+    let data_sink = &data_sink;
 
     // Ignore errors, maybe directory is already there.
-    let _ = fs::create_dir(output_directory.as_ref());
+    let _ = fs::create_dir(working_directory.as_ref());
 
     eprintln!("Creating octree structure.");
 
@@ -368,6 +455,7 @@ pub fn build_octree(
             parent_ids.par_iter().for_each(|id| {
                 subsample_children_into(
                     octree_data_provider,
+                    data_sink,
                     octree_meta,
                     attribute_data_types,
                     id,
@@ -396,8 +484,6 @@ pub fn build_octree(
         })
         .collect();
     let meta = to_meta_proto(&octree_meta, nodes);
-
-    let mut buf_writer =
-        BufWriter::new(File::create(&output_directory.as_ref().join(META_FILENAME)).unwrap());
-    meta.write_to_writer(&mut buf_writer).unwrap();
+    data_sink.write_meta(&meta).unwrap();
+    data_sink.finalize().unwrap();
 }
@@ -19,8 +19,10 @@ use crate::math::base::{HasAabbIntersector, IntersectAabb};
 use crate::math::sat::{ConvexPolyhedron, Relation};
 use crate::math::AllPoints;
 use crate::proto;
-use crate::read_write::{Encoding, NodeIterator, PositionEncoding};
-use crate::{AttributeDataType, PointCloudMeta, CURRENT_VERSION};
+use crate::read_write::{
+    AttributeCompression, AttributeEncodings, Encoding, NodeIterator, PositionEncoding,
+};
+use crate::{check_node_format_version, AttributeDataType, PointCloudMeta, CURRENT_VERSION};
 use fnv::FnvHashMap;
 use nalgebra::{Matrix4, Point3};
 use num::clamp;
@@ -29,7 +31,9 @@ use std::collections::{BinaryHeap, HashMap};
 use std::io::{BufReader, Read};
 
 mod generation;
-pub use self::generation::{build_octree, build_octree_from_file};
+pub use self::generation::{
+    build_octree, build_octree_from_file, build_octree_from_file_into, build_octree_into,
+};
 
 mod node;
 pub use self::node::{to_node_proto, ChildIndex, Node, NodeId, NodeMeta};
@@ -37,6 +41,8 @@ pub use self::node::{to_node_proto, ChildIndex, Node, NodeId, NodeMeta};
 mod octree_iterator;
 pub use self::octree_iterator::NodeIdsIterator;
 
+mod removal;
+
 #[cfg(test)]
 mod tests;
 
@@ -45,12 +51,22 @@ pub struct OctreeMeta {
     pub resolution: f64,
     pub bounding_box: Aabb,
     attribute_data_types: HashMap<String, AttributeDataType>,
+    attribute_compression: AttributeCompression,
+    attribute_encodings: AttributeEncodings,
 }
 
 impl PointCloudMeta for OctreeMeta {
     fn attribute_data_types(&self) -> &HashMap<String, AttributeDataType> {
         &self.attribute_data_types
     }
+
+    fn attribute_compression(&self) -> &AttributeCompression {
+        &self.attribute_compression
+    }
+
+    fn attribute_encodings(&self) -> &AttributeEncodings {
+        &self.attribute_encodings
+    }
 }
 
 impl OctreeMeta {
@@ -70,9 +86,24 @@ impl OctreeMeta {
             resolution,
             bounding_box,
             attribute_data_types,
+            attribute_compression: AttributeCompression::default(),
+            attribute_encodings: AttributeEncodings::default(),
         }
     }
 
+    pub fn with_attribute_compression(
+        mut self,
+        attribute_compression: AttributeCompression,
+    ) -> Self {
+        self.attribute_compression = attribute_compression;
+        self
+    }
+
+    pub fn with_attribute_encodings(mut self, attribute_encodings: AttributeEncodings) -> Self {
+        self.attribute_encodings = attribute_encodings;
+        self
+    }
+
     pub fn encoding_for_node(&self, id: NodeId) -> Encoding {
         let bounding_cube = id.find_bounding_cube(&Cube::bounding(&self.bounding_box));
         let position_encoding = PositionEncoding::new(&bounding_cube, self.resolution);
@@ -95,6 +126,8 @@ pub fn to_meta_proto(octree_meta: &OctreeMeta, nodes: Vec<proto::OctreeNode>) ->
     meta.set_version(CURRENT_VERSION);
     meta.set_bounding_box(proto::AxisAlignedCuboid::from(&octree_meta.bounding_box));
     meta.set_octree(octree_proto);
+    meta.set_attribute_compression(octree_meta.attribute_compression.to_proto());
+    meta.set_attribute_encoding(octree_meta.attribute_encodings.to_proto());
     meta
 }
 
@@ -174,10 +207,11 @@ impl Octree {
                     meta_proto.get_deprecated_nodes(),
                 )
             }
-            12 | CURRENT_VERSION => {
+            12 | 13 | CURRENT_VERSION => {
                 if !meta_proto.has_octree() {
                     return Err(ErrorKind::InvalidInput("No octree meta found".to_string()).into());
                 }
+                check_node_format_version(&meta_proto)?;
                 let octree_meta = meta_proto.get_octree();
                 let bounding_box = Aabb::from(if meta_proto.version == 12 {
                     octree_meta.get_deprecated_bounding_box()
@@ -186,7 +220,13 @@ impl Octree {
                 });
                 (
                     bounding_box.clone(),
-                    OctreeMeta::new_with_standard_attributes(octree_meta.resolution, bounding_box),
+                    OctreeMeta::new_with_standard_attributes(octree_meta.resolution, bounding_box)
+                        .with_attribute_compression(AttributeCompression::from_proto(
+                            meta_proto.get_attribute_compression(),
+                        ))
+                        .with_attribute_encodings(AttributeEncodings::from_proto(
+                            meta_proto.get_attribute_encoding(),
+                        )?),
                     octree_meta.get_nodes(),
                 )
             }
@@ -282,6 +322,7 @@ impl Octree {
         visible
     }
 
+    /// Returns the raw data of the node, decompressed if it was stored compressed.
     pub fn get_node_data(&self, node_id: &NodeId) -> Result<NodeData> {
         // TODO(hrapp): If we'd randomize the points while writing, we could just read the
         // first N points instead of reading everything and skipping over a few.
@@ -289,9 +330,15 @@ impl Octree {
             .data_provider
             .data(&node_id.to_string(), &["position", "color"])?;
 
+        let attribute_compression = &self.meta.attribute_compression;
         let mut get_data = |node_attribute: &str, err: &str| -> Result<Vec<u8>> {
-            let mut reader =
-                BufReader::new(position_color_reads.remove(node_attribute).ok_or(err)?);
+            let reader = position_color_reads.remove(node_attribute).ok_or(err)?;
+            let mut reader = BufReader::new(
+                attribute_compression
+                    .get(node_attribute)
+                    .decoder(reader)
+                    .chain_err(|| err)?,
+            );
             let mut all_data = Vec::new();
             reader.read_to_end(&mut all_data).chain_err(|| err)?;
             Ok(all_data)
@@ -308,6 +355,7 @@ impl Octree {
 
     fn nodes_in_location_impl<'a, T: HasAabbIntersector<'a>>(
         &self,
+        max_level: Option<u64>,
         location: &'a T,
     ) -> Vec<NodeId> {
         // TODO(nnmm): Once intersection tests use Relation, this function can traverse the octree
@@ -316,6 +364,9 @@ impl Octree {
         // function instead.
         let isec = location.aabb_intersector();
         NodeIdsIterator::new(&self, |node_id, octree| {
+            if max_level.map_or(false, |max_level| u64::from(node_id.level()) > max_level) {
+                return false;
+            }
             let aabb = octree.nodes[&node_id].bounding_cube.to_aabb();
             isec.intersect_aabb(&aabb)
         })
@@ -326,8 +377,8 @@ impl Octree {
 impl PointCloud for Octree {
     type Id = NodeId;
 
-    fn nodes_in_location(&self, location: &PointLocation) -> Vec<Self::Id> {
-        dispatch_point_location!(Octree::nodes_in_location_impl, location, &self)
+    fn nodes_in_location(&self, location: &PointLocation, max_level: Option<u64>) -> Vec<Self::Id> {
+        dispatch_point_location!(Octree::nodes_in_location_impl, location, &self, max_level)
     }
 
     fn encoding_for_node(&self, id: Self::Id) -> Encoding {
@@ -343,6 +394,8 @@ impl PointCloud for Octree {
         let node_iterator = NodeIterator::from_data_provider(
             &*self.data_provider,
             &self.meta.attribute_data_types_for(&attributes)?,
+            &self.meta.attribute_compression,
+            &self.meta.attribute_encodings,
             self.meta.encoding_for_node(node_id),
             &node_id,
             self.nodes[&node_id].num_points as usize,
@@ -0,0 +1,278 @@
+// Copyright 2020 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::data_provider::{ChecksummingDataSink, DataSink};
+use crate::errors::*;
+use crate::filter::AttributeFilter;
+use crate::iterator::{PointCloud, PointLocation};
+use crate::math::PointCulling;
+use crate::octree::{to_meta_proto, to_node_proto, ChildIndex, NodeId, NodeMeta, Octree};
+use crate::read_write::{NodeIterator, RawNodeWriter};
+use crate::{PointCloudMeta, PointsBatch, CURRENT_VERSION};
+use fnv::{FnvHashMap, FnvHashSet};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+impl Octree {
+    /// Removes the points in the location that pass the filter, or all points in the location if
+    /// there is no filter. Only the nodes intersecting the location are rewritten: The remaining
+    /// points are subsampled into the ancestors of changed nodes again, nodes without points and
+    /// children are dropped and the meta is updated. Returns the number of removed points.
+    ///
+    /// The data sink has to write to where this octree is read from. Rewritten nodes are kept in
+    /// memory until their parent was rewritten, so that they are never read back. This octree is
+    /// outdated afterwards and should be reopened.
+    pub fn remove_points(
+        &self,
+        data_sink: Arc<dyn DataSink>,
+        location: &PointLocation,
+        filter: Option<&AttributeFilter>,
+    ) -> Result<usize> {
+        let meta_proto = self.data_provider.meta_proto()?;
+        if meta_proto.version != CURRENT_VERSION {
+            return Err(ErrorKind::InvalidInput(format!(
+                "Points can only be removed from octrees of version {}, use `upgrade_octree`.",
+                CURRENT_VERSION
+            ))
+            .into());
+        }
+        let data_sink = ChecksummingDataSink::for_rewriting(data_sink, &meta_proto);
+        let mut removal = Removal {
+            octree: self,
+            data_sink,
+            attributes: self.stored_attributes()?,
+            nodes: self.nodes.clone(),
+            culling: location.get_point_culling(),
+            filter,
+            unfiltered: self.nodes_in_location(location, None).into_iter().collect(),
+            rewritten: FnvHashMap::default(),
+        };
+
+        // Like in octree generation, parents are subsampled from their children level by level,
+        // starting at the deepest one. Nodes are filtered when they are read first, which for
+        // parents of changed nodes is before their points are split between them and their
+        // children again.
+        let mut num_removed = 0;
+        let mut changed_nodes = FnvHashSet::default();
+        let deepest_level = removal.unfiltered.iter().map(NodeId::level).max();
+        for level in (0..=deepest_level.unwrap_or(0)).rev() {
+            let unfiltered: Vec<NodeId> = removal
+                .unfiltered
+                .iter()
+                .filter(|id| id.level() == level)
+                .copied()
+                .collect();
+            for node_id in unfiltered {
+                let (batch, num_node_removed) = removal.read_node(node_id)?;
+                if num_node_removed == 0 {
+                    continue;
+                }
+                num_removed += num_node_removed;
+                if level == 0 {
+                    removal.write_node(node_id, &batch)?;
+                } else {
+                    // The node is written once its parent is subsampled.
+                    removal.rewritten.insert(node_id, batch);
+                    changed_nodes.insert(node_id);
+                }
+            }
+            if level == 0 {
+                break;
+            }
+
+            let mut children_by_parent: FnvHashMap<NodeId, Vec<NodeId>> = FnvHashMap::default();
+            for node_id in changed_nodes.iter().filter(|id| id.level() == level) {
+                // Unwrap is safe, since the root is on level 0.
+                children_by_parent
+                    .entry(node_id.parent_id().unwrap())
+                    .or_default()
+                    .push(*node_id);
+            }
+            changed_nodes.retain(|id| id.level() != level);
+            for (parent_id, child_ids) in children_by_parent {
+                num_removed += removal.subsample_children(parent_id, &child_ids)?;
+                changed_nodes.insert(parent_id);
+            }
+        }
+
+        let nodes = removal
+            .nodes
+            .iter()
+            .map(|(id, node_meta)| {
+                to_node_proto(id, node_meta.num_points, &node_meta.position_encoding)
+            })
+            .collect();
+        let mut meta = to_meta_proto(&self.meta, nodes);
+        meta.set_checksums(meta_proto.get_checksums().clone());
+        removal.data_sink.write_meta(&meta)?;
+        removal.data_sink.finalize()?;
+        Ok(num_removed)
+    }
+
+    /// The attributes of the nodes. Color is always stored, the other standard attributes only
+    /// if the root has them.
+    fn stored_attributes(&self) -> Result<Vec<String>> {
+        let root = NodeId::from_level_index(0, 0).to_string();
+        let mut attributes = Vec::new();
+        for attribute in self.meta.attribute_data_types().keys() {
+            if attribute == "color" {
+                attributes.push(attribute.clone());
+                continue;
+            }
+            match self.data_provider.data(&root, &[attribute]) {
+                Ok(_) => attributes.push(attribute.clone()),
+                Err(Error(ErrorKind::NodeNotFound, _)) => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(attributes)
+    }
+}
+
+/// The state of `Octree::remove_points`, with the current nodes.
+struct Removal<'a> {
+    octree: &'a Octree,
+    data_sink: Arc<dyn DataSink>,
+    attributes: Vec<String>,
+    nodes: FnvHashMap<NodeId, NodeMeta>,
+    culling: Box<dyn PointCulling>,
+    filter: Option<&'a AttributeFilter>,
+    /// The nodes in the location that were not read yet.
+    unfiltered: FnvHashSet<NodeId>,
+    /// Nodes that changed since the octree was opened and that will be read once more. Reading
+    /// them back from the data provider could fail if it verifies the checksums it was opened with.
+    rewritten: FnvHashMap<NodeId, PointsBatch>,
+}
+
+impl<'a> Removal<'a> {
+    /// Returns the current points of the node, and how many of them were removed now because it
+    /// is in the location and was not read before.
+    fn read_node(&mut self, node_id: NodeId) -> Result<(PointsBatch, usize)> {
+        let mut batch = match self.rewritten.remove(&node_id) {
+            Some(batch) => batch,
+            None => self.read_stored_node(node_id)?,
+        };
+        if !self.unfiltered.remove(&node_id) {
+            return Ok((batch, 0));
+        }
+        let mut keep: Vec<bool> = batch
+            .position
+            .iter()
+            .map(|pos| !self.culling.contains(pos))
+            .collect();
+        if let Some(filter) = self.filter {
+            for (k, f) in keep.iter_mut().zip(filter.evaluate(&batch)?) {
+                *k |= !f;
+            }
+        }
+        let num_kept = keep.iter().filter(|k| **k).count();
+        if num_kept < keep.len() {
+            batch.retain(&keep);
+        }
+        Ok((batch, keep.len() - num_kept))
+    }
+
+    fn read_stored_node(&self, node_id: NodeId) -> Result<PointsBatch> {
+        let attributes: Vec<&str> = self.attributes.iter().map(String::as_str).collect();
+        let meta = &self.octree.meta;
+        let num_points = self.nodes[&node_id].num_points as usize;
+        let mut node_iterator = NodeIterator::from_data_provider(
+            &*self.octree.data_provider,
+            &meta.attribute_data_types_for(&attributes)?,
+            meta.attribute_compression(),
+            meta.attribute_encodings(),
+            meta.encoding_for_node(node_id),
+            &node_id,
+            num_points,
+            std::cmp::max(num_points, 1),
+        )?;
+        // The whole node is read into memory, so that it can be rewritten afterwards.
+        Ok(node_iterator.try_next()?.unwrap_or_else(|| PointsBatch {
+            position: Vec::new(),
+            attributes: BTreeMap::new(),
+        }))
+    }
+
+    /// Writes the node, or removes its blobs if it has no points.
+    fn write_node(&mut self, node_id: NodeId, batch: &PointsBatch) -> Result<()> {
+        let attributes: Vec<&str> = self.attributes.iter().map(String::as_str).collect();
+        RawNodeWriter::write_or_remove(
+            &self.data_sink,
+            &node_id.to_string(),
+            self.octree.meta.encoding_for_node(node_id),
+            &self.octree.meta,
+            &attributes,
+            batch,
+        )?;
+        // Unwrap is safe, since only existing nodes are written.
+        self.nodes.get_mut(&node_id).unwrap().num_points = batch.position.len() as i64;
+        Ok(())
+    }
+
+    /// For each child, the points of the parent in the child's cube and the child's points are
+    /// split between them again, as in octree generation. Children without points and children
+    /// are dropped. Returns the number of points removed from the parent.
+    fn subsample_children(&mut self, parent_id: NodeId, child_ids: &[NodeId]) -> Result<usize> {
+        let (parent, num_removed) = self.read_node(parent_id)?;
+        let parent_cube = &self.nodes[&parent_id].bounding_cube;
+        let child_indices: Vec<NodeId> = parent
+            .position
+            .iter()
+            .map(|pos| parent_id.get_child_id(ChildIndex::from_bounding_cube(parent_cube, pos)))
+            .collect();
+        let mut new_parent = parent.clone();
+        let keep: Vec<bool> = child_indices
+            .iter()
+            .map(|id| !child_ids.contains(id))
+            .collect();
+        new_parent.retain(&keep);
+
+        for child_id in child_ids {
+            let mut points = parent.clone();
+            let keep: Vec<bool> = child_indices.iter().map(|id| id == child_id).collect();
+            points.retain(&keep);
+            append(&mut points, self.read_node(*child_id)?.0)?;
+
+            let (keep_parent, keep_child): (Vec<bool>, Vec<bool>) = (0..points.position.len())
+                .map(|i| {
+                    let in_parent = i % 8 == 0;
+                    (in_parent, !in_parent)
+                })
+                .unzip();
+            let mut parent_points = points.clone();
+            parent_points.retain(&keep_parent);
+            append(&mut new_parent, parent_points)?;
+            points.retain(&keep_child);
+            self.write_node(*child_id, &points)?;
+
+            let has_children = (0..8).any(|i| {
+                self.nodes
+                    .contains_key(&child_id.get_child_id(ChildIndex::from_u8(i)))
+            });
+            if points.position.is_empty() && !has_children {
+                self.nodes.remove(child_id);
+            }
+        }
+        self.write_node(parent_id, &new_parent)?;
+        self.rewritten.insert(parent_id, new_parent);
+        Ok(num_removed)
+    }
+}
+
+fn append(batch: &mut PointsBatch, mut other: PointsBatch) -> Result<()> {
+    if !other.position.is_empty() {
+        batch.append(&mut other)?;
+    }
+    Ok(())
+}
@@ -1,10 +1,16 @@
-use crate::data_provider::OnDiskDataProvider;
-use crate::errors::Result;
+use crate::data_provider::{DataProvider, DataSink, OnDiskDataProvider};
+use crate::deduplication::{Deduplication, DeduplicationKey};
+use crate::downsampling::{VoxelDownsampling, VoxelRepresentative};
+use crate::errors::{ErrorKind, Result};
 use crate::geometry::Aabb;
-use crate::iterator::{ParallelIterator, PointQuery};
-use crate::octree::{build_octree, Octree};
-use crate::{AttributeData, NumberOfPoints, PointsBatch};
-use nalgebra::{Point3, Vector3};
+use crate::iterator::{CancellationToken, OutputFrame, ParallelIterator, PointCloud, PointQuery};
+use crate::octree::{build_octree, build_octree_into, Octree};
+use crate::ordering::OutputOrder;
+use crate::read_write::{AttributeCompression, AttributeEncoding, AttributeEncodings};
+use crate::{AttributeData, AttributeDataType, NumberOfPoints, PointsBatch};
+use nalgebra::{Isometry3, Point3, Vector3};
+use std::path::Path;
+use std::sync::Arc;
 use tempdir::TempDir;
 
 const NUM_POINTS: usize = 100_001;
@@ -16,11 +22,21 @@ impl NumberOfPoints for std::vec::IntoIter<PointsBatch> {
 }
 
 fn build_test_octree() -> Octree {
+    build_colored_test_octree(Vector3::new(255, 0, 0))
+}
+
+fn build_colored_test_octree(color: Vector3<u8>) -> Octree {
+    let tmp_dir = TempDir::new("octree").unwrap();
+    build_test_octree_in(tmp_dir.path(), color);
+    Octree::from_data_provider(Box::new(OnDiskDataProvider::new(tmp_dir.into_path()))).unwrap()
+}
+
+fn build_test_octree_in(directory: &Path, color: Vector3<u8>) {
     let mut batch = PointsBatch {
         position: vec![Point3::new(0.0, 0.0, 0.0); NUM_POINTS],
         attributes: vec![(
             "color".to_string(),
-            AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); NUM_POINTS]),
+            AttributeData::U8Vec3(vec![color; NUM_POINTS]),
         )]
         .into_iter()
         .collect(),
@@ -30,19 +46,13 @@ fn build_test_octree() -> Octree {
 
     let bounding_box = Aabb::new(batch.position[0], batch.position[NUM_POINTS - 1]);
 
-    let tmp_dir = TempDir::new("octree").unwrap();
-
     build_octree(
-        &tmp_dir,
+        directory,
         1.0,
         bounding_box,
         vec![batch].into_iter(),
         &["color"],
     );
-    Octree::from_data_provider(Box::new(OnDiskDataProvider {
-        directory: tmp_dir.into_path(),
-    }))
-    .unwrap()
 }
 
 struct Consumer {
@@ -134,3 +144,324 @@ fn test_batch_iterator_more_points() {
         .expect("Iterator errored even though callback should not have errored.");
     assert_eq!(c.num_received_points, NUM_POINTS);
 }
+
+#[test]
+fn test_batch_iterator_node_error() {
+    let tmp_dir = TempDir::new("octree").unwrap();
+    build_test_octree_in(tmp_dir.path(), Vector3::new(255, 0, 0));
+    std::fs::remove_file(tmp_dir.path().join("r.xyz")).unwrap();
+    let octree =
+        Octree::from_data_provider(Box::new(OnDiskDataProvider::new(tmp_dir.path()))).unwrap();
+    let location = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    let octree_slice: &[Octree] = std::slice::from_ref(&octree);
+
+    let mut parallel_iterator = ParallelIterator::new(octree_slice, &location, 5000, 2, 2);
+    let err = parallel_iterator
+        .try_for_each_batch(|_| Ok(()))
+        .expect_err("Iterator did not error even though a node is missing.");
+    match err.kind() {
+        ErrorKind::NodeReadFailed(0, node_id) => assert_eq!(node_id, "r"),
+        _ => panic!("Unexpected error: {}", err),
+    }
+
+    let mut parallel_iterator =
+        ParallelIterator::new(octree_slice, &location, 5000, 2, 2).skip_failed_nodes(true);
+    let mut num_received_points = 0;
+    parallel_iterator
+        .try_for_each_batch(|points_batch| {
+            num_received_points += points_batch.position.len();
+            Ok(())
+        })
+        .expect("Iterator errored even though failed nodes should be skipped.");
+    assert_eq!(parallel_iterator.failed_nodes().len(), 1);
+    assert!(num_received_points > 0);
+    assert!(num_received_points < NUM_POINTS);
+}
+
+#[test]
+fn test_batch_iterator_max_points() {
+    let batch_size = 5000;
+    let max_num_points = 13_000;
+    let octree = build_test_octree();
+    let location = PointQuery {
+        attributes: vec!["color"],
+        max_points: Some(max_num_points),
+        ..Default::default()
+    };
+    let octree_slice: &[Octree] = std::slice::from_ref(&octree);
+    let mut parallel_iterator = ParallelIterator::new(octree_slice, &location, batch_size, 2, 2);
+
+    let mut num_received_points = 0;
+    parallel_iterator
+        .try_for_each_batch(|points_batch| {
+            num_received_points += points_batch.position.len();
+            Ok(())
+        })
+        .expect("Iterator errored even though the point limit is not an error.");
+    assert_eq!(num_received_points, max_num_points);
+}
+
+#[test]
+fn test_batch_iterator_cancellation() {
+    let batch_size = 5000;
+    let octree = build_test_octree();
+    let cancellation_token = CancellationToken::new();
+    let location = PointQuery {
+        attributes: vec!["color"],
+        cancellation_token: cancellation_token.clone(),
+        ..Default::default()
+    };
+    let octree_slice: &[Octree] = std::slice::from_ref(&octree);
+    let mut parallel_iterator = ParallelIterator::new(octree_slice, &location, batch_size, 2, 2);
+
+    let mut num_received_callbacks = 0;
+    parallel_iterator
+        .try_for_each_batch(|_| {
+            num_received_callbacks += 1;
+            cancellation_token.cancel();
+            Ok(())
+        })
+        .expect("Iterator errored even though cancelling is not an error.");
+    assert_eq!(num_received_callbacks, 1);
+}
+
+#[test]
+fn test_batch_iterator_deduplication() {
+    let red = Vector3::new(255, 0, 0);
+    let blue = Vector3::new(0, 0, 255);
+    let octrees = vec![
+        build_colored_test_octree(red),
+        build_colored_test_octree(blue),
+    ];
+    let location = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+
+    let mut num_received_points = 0;
+    let deduplication = Deduplication::new(DeduplicationKey::Quantization(0.01));
+    ParallelIterator::new(&octrees, &location, 5000, 2, 2)
+        .deduplication(&deduplication)
+        .try_for_each_batch(|points_batch| {
+            num_received_points += points_batch.position.len();
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(num_received_points, NUM_POINTS);
+
+    // The blue point cloud has the higher priority, so only blue points remain.
+    let mut num_received_points = 0;
+    let deduplication = deduplication.priorities(vec![0, 1]);
+    ParallelIterator::new(&octrees, &location, 5000, 2, 2)
+        .deduplication(&deduplication)
+        .try_for_each_batch(|points_batch| {
+            let colors: &Vec<Vector3<u8>> = points_batch.get_attribute_vec("color")?;
+            assert!(colors.iter().all(|color| *color == blue));
+            num_received_points += points_batch.position.len();
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(num_received_points, NUM_POINTS);
+
+    // All points but one fall into the same cube.
+    let deduplication = Deduplication::new(DeduplicationKey::Quantization(0.01)).max_num_keys(1);
+    assert!(ParallelIterator::new(&octrees, &location, 5000, 2, 2)
+        .deduplication(&deduplication)
+        .try_for_each_batch(|_| Ok(()))
+        .is_err());
+
+    let deduplication = Deduplication::new(DeduplicationKey::Attribute("id".to_string()));
+    assert!(ParallelIterator::new(&octrees, &location, 5000, 2, 2)
+        .deduplication(&deduplication)
+        .try_for_each_batch(|_| Ok(()))
+        .is_err());
+}
+
+#[test]
+fn test_batch_iterator_voxel_downsampling() {
+    let octree = build_test_octree();
+    let location = PointQuery {
+        attributes: vec!["color"],
+        voxel_downsampling: Some(VoxelDownsampling::new(0.1, VoxelRepresentative::Centroid)),
+        ..Default::default()
+    };
+    let octree_slice: &[Octree] = std::slice::from_ref(&octree);
+    let mut parallel_iterator = ParallelIterator::new(octree_slice, &location, 5000, 2, 2);
+
+    let mut positions = Vec::new();
+    parallel_iterator
+        .try_for_each_batch(|mut points_batch| {
+            positions.append(&mut points_batch.position);
+            Ok(())
+        })
+        .unwrap();
+    // All points but one are at the origin, so there is one point per node containing the origin,
+    // and the far away point. Positions are only accurate up to the octree resolution.
+    let num_nodes = octree.nodes_in_location(&location.location, None).len();
+    let far_point = Point3::new(-200., -40., 30.);
+    let num_far = positions
+        .iter()
+        .filter(|p| (*p - far_point).norm() < 1.0)
+        .count();
+    let num_origin = positions.iter().filter(|p| p.coords.norm() < 1.0).count();
+    assert_eq!(num_far, 1);
+    assert!(num_origin >= 1 && num_origin < num_nodes);
+    assert_eq!(num_far + num_origin, positions.len());
+}
+
+#[test]
+fn test_batch_iterator_invalid_voxel_size() {
+    let octree = build_test_octree();
+    let location = PointQuery {
+        attributes: vec!["color"],
+        voxel_downsampling: Some(VoxelDownsampling {
+            voxel_size: 0.0,
+            representative: VoxelRepresentative::First,
+        }),
+        ..Default::default()
+    };
+    let octree_slice: &[Octree] = std::slice::from_ref(&octree);
+    assert!(ParallelIterator::new(octree_slice, &location, 5000, 2, 2)
+        .try_for_each_batch(|_| Ok(()))
+        .is_err());
+}
+
+#[test]
+fn test_batch_iterator_output_frame() {
+    let octree = build_test_octree();
+    let output_from_global = Isometry3::translation(10.0, 20.0, 30.0);
+    let location = PointQuery {
+        attributes: vec!["color"],
+        output_frame: Some(OutputFrame::Isometry(output_from_global)),
+        ..Default::default()
+    };
+    let octree_slice: &[Octree] = std::slice::from_ref(&octree);
+    let mut parallel_iterator = ParallelIterator::new(octree_slice, &location, 5000, 2, 2);
+
+    let far_point = output_from_global.transform_point(&Point3::new(-200., -40., 30.));
+    let origin = output_from_global.transform_point(&Point3::origin());
+    let mut num_received_points = 0;
+    parallel_iterator
+        .try_for_each_batch(|points_batch| {
+            // Positions are only accurate up to the octree resolution.
+            assert!(points_batch
+                .position
+                .iter()
+                .all(|p| (p - origin).norm() < 1.0 || (p - far_point).norm() < 1.0));
+            num_received_points += points_batch.position.len();
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(num_received_points, NUM_POINTS);
+}
+
+#[test]
+fn test_batch_iterator_ordered_output() {
+    let octrees = vec![
+        build_colored_test_octree(Vector3::new(255, 0, 0)),
+        build_colored_test_octree(Vector3::new(0, 0, 255)),
+    ];
+    let location = PointQuery {
+        attributes: vec!["color"],
+        order: Some(OutputOrder::MortonCode),
+        ..Default::default()
+    };
+    let query = |num_threads| {
+        let mut batches = Vec::new();
+        ParallelIterator::new(&octrees, &location, 5000, num_threads, 2)
+            .try_for_each_batch(|points_batch| {
+                let colors: &Vec<Vector3<u8>> = points_batch.get_attribute_vec("color")?;
+                batches.push((points_batch.position.clone(), colors.clone()));
+                Ok(())
+            })
+            .unwrap();
+        batches
+    };
+    let expected = query(1);
+    assert_eq!(
+        expected.iter().map(|(p, _)| p.len()).sum::<usize>(),
+        2 * NUM_POINTS
+    );
+    // Only the last batch may be smaller than the batch size.
+    assert!(expected[..expected.len() - 1]
+        .iter()
+        .all(|(p, _)| p.len() == 5000));
+    for _ in 0..3 {
+        assert_eq!(query(4), expected);
+    }
+}
+
+#[test]
+fn test_batch_iterator_ordered_deduplication() {
+    let red = Vector3::new(255, 0, 0);
+    let octrees = vec![
+        build_colored_test_octree(red),
+        build_colored_test_octree(Vector3::new(0, 0, 255)),
+    ];
+    let location = PointQuery {
+        attributes: vec!["color"],
+        order: Some(OutputOrder::NodeId),
+        ..Default::default()
+    };
+    // Both point clouds have the same priority, so the first node containing a point wins.
+    let deduplication = Deduplication::new(DeduplicationKey::Quantization(0.01));
+    for num_threads in &[1, 4, 4, 4] {
+        let mut num_received_points = 0;
+        ParallelIterator::new(&octrees, &location, 5000, *num_threads, 2)
+            .deduplication(&deduplication)
+            .try_for_each_batch(|points_batch| {
+                let colors: &Vec<Vector3<u8>> = points_batch.get_attribute_vec("color")?;
+                assert!(colors.iter().all(|color| *color == red));
+                num_received_points += points_batch.position.len();
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(num_received_points, NUM_POINTS);
+    }
+}
+
+#[test]
+fn test_encoded_octree_needs_current_version() {
+    let tmp_dir = TempDir::new("octree").unwrap();
+    let mut batch = PointsBatch {
+        position: vec![Point3::new(0.0, 0.0, 0.0); NUM_POINTS],
+        attributes: vec![(
+            "intensity".to_string(),
+            AttributeData::F32((0..NUM_POINTS).map(|i| i as f32).collect()),
+        )]
+        .into_iter()
+        .collect(),
+    };
+    batch.position[NUM_POINTS - 1] = Point3::new(-200., -40., 30.);
+    let bounding_box = Aabb::new(batch.position[0], batch.position[NUM_POINTS - 1]);
+    let data_sink = Arc::new(OnDiskDataProvider::new(tmp_dir.path()));
+    build_octree_into(
+        data_sink.clone(),
+        tmp_dir.path(),
+        1.0,
+        bounding_box,
+        vec![batch].into_iter(),
+        &["intensity"],
+        AttributeCompression::default(),
+        AttributeEncodings::default().with(
+            "intensity",
+            AttributeEncoding::fixed_point(0.0, 1.0, AttributeDataType::I32).with_delta(),
+        ),
+    );
+    let open = || Octree::from_data_provider(Box::new(OnDiskDataProvider::new(tmp_dir.path())));
+    assert!(open().is_ok());
+
+    // Tools of version 13 would read the encoded intensities as raw floats.
+    let mut meta = data_sink.meta_proto().unwrap();
+    meta.version = 13;
+    data_sink.write_meta(&meta).unwrap();
+    assert!(open().is_err());
+
+    // Version 13 point clouds without encodings can still be read.
+    meta.clear_attribute_encoding();
+    data_sink.write_meta(&meta).unwrap();
+    assert!(open().is_ok());
+}
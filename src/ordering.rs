@@ -0,0 +1,167 @@
+//! Deterministic order of query results.
+
+use crate::errors::*;
+use crate::{AttributeData, PointsBatch};
+use nalgebra::Point3;
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+
+/// Nodes are always returned ordered by point cloud and node id. Within a node, points can be
+/// reordered as well. With deduplication, points are only compared after reordering, so which
+/// of several duplicates is kept is deterministic, too.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum OutputOrder {
+    /// The points of a node are returned in the order they are stored in.
+    NodeId,
+    /// The points of a node are sorted along a Morton (Z-order) curve through the node.
+    MortonCode,
+    /// The points of a node are sorted by this 1-D attribute, e.g. a timestamp. The attribute
+    /// needs to be specified as query attribute.
+    Attribute(String),
+}
+
+impl OutputOrder {
+    pub(crate) fn validate(&self, attributes: &[&str]) -> Result<()> {
+        match self {
+            OutputOrder::Attribute(name) if !attributes.contains(&name.as_str()) => {
+                Err(ErrorKind::InvalidInput(format!(
+                    "Sort attribute '{}' needs to be specified as query attribute.",
+                    name
+                ))
+                .into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Sorts the points of a node. The sort is stable.
+    pub fn sort_node_points(&self, batch: &mut PointsBatch) -> Result<()> {
+        let mut indices: Vec<usize> = (0..batch.position.len()).collect();
+        match self {
+            OutputOrder::NodeId => return Ok(()),
+            OutputOrder::MortonCode => {
+                let codes = morton_codes(&batch.position);
+                indices.sort_by_key(|i| codes[*i]);
+            }
+            OutputOrder::Attribute(name) => {
+                let data = batch.attributes.get(name).ok_or_else(|| {
+                    format!(
+                        "Sort attribute '{}' needs to be specified as query attribute.",
+                        name
+                    )
+                })?;
+                macro_rules! rhs {
+                    ($dtype:ident, $data:ident) => {
+                        $data.iter().map(|v| v.to_f64().unwrap()).collect()
+                    };
+                }
+                let values: Vec<f64> = match data {
+                    AttributeData::U8Vec3(_) | AttributeData::F64Vec3(_) => {
+                        return Err(ErrorKind::InvalidInput(format!(
+                            "Sort attribute '{}' needs to be one-dimensional.",
+                            name
+                        ))
+                        .into());
+                    }
+                    data => match_1d_attr_data!(data, rhs),
+                };
+                // NaNs are sorted last.
+                indices.sort_by(|a, b| {
+                    let (a, b) = (values[*a], values[*b]);
+                    a.partial_cmp(&b)
+                        .unwrap_or_else(|| a.is_nan().cmp(&b.is_nan()))
+                });
+            }
+        }
+        *batch = PointsBatch {
+            position: indices.iter().map(|i| batch.position[*i]).collect(),
+            attributes: batch
+                .attributes
+                .iter()
+                .map(|(name, data)| (name.clone(), data.select(&indices)))
+                .collect(),
+        };
+        Ok(())
+    }
+}
+
+/// Spreads the lower 21 bits of `v` so that there are two zero bits between each of them.
+fn spread_bits(v: u64) -> u64 {
+    let mut v = v & 0x1f_ffff;
+    v = (v | v << 32) & 0x1f_0000_0000_ffff;
+    v = (v | v << 16) & 0x1f_0000_ff00_00ff;
+    v = (v | v << 8) & 0x100f_00f0_0f00_f00f;
+    v = (v | v << 4) & 0x10c3_0c30_c30c_30c3;
+    v = (v | v << 2) & 0x1249_2492_4924_9249;
+    v
+}
+
+/// Morton codes of the positions, quantized to 21 bits per axis within their bounding cube.
+fn morton_codes(positions: &[Point3<f64>]) -> Vec<u64> {
+    let mut min = match positions.first() {
+        Some(p) => *p,
+        None => return Vec::new(),
+    };
+    let mut max = min;
+    for p in positions {
+        min = min.inf(p);
+        max = max.sup(p);
+    }
+    let extent = (max - min).max();
+    let scale = if extent > 0.0 {
+        f64::from((1 << 21) - 1) / extent
+    } else {
+        0.0
+    };
+    positions
+        .iter()
+        .map(|p| {
+            let q = ((p - min) * scale).map(|c| c.round() as u64);
+            spread_bits(q.x) | spread_bits(q.y) << 1 | spread_bits(q.z) << 2
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spread_bits() {
+        assert_eq!(spread_bits(0b1011), 0b1_000_001_001);
+        assert_eq!(spread_bits(0x1f_ffff), 0x1249_2492_4924_9249);
+    }
+
+    #[test]
+    fn test_sort_node_points() {
+        let mut batch = PointsBatch {
+            position: vec![
+                Point3::new(1.0, 1.0, 1.0),
+                Point3::new(0.0, 0.0, 0.0),
+                Point3::new(1.0, 0.0, 0.0),
+                Point3::new(0.0, 1.0, 0.0),
+            ],
+            attributes: vec![(
+                "timestamp".to_string(),
+                AttributeData::I64(vec![3, 0, 2, 1]),
+            )]
+            .into_iter()
+            .collect(),
+        };
+        OutputOrder::MortonCode
+            .sort_node_points(&mut batch)
+            .unwrap();
+        assert_eq!(
+            batch.get_attribute_vec::<i64>("timestamp").unwrap(),
+            &[0, 2, 1, 3]
+        );
+        OutputOrder::Attribute("timestamp".to_string())
+            .sort_node_points(&mut batch)
+            .unwrap();
+        assert_eq!(
+            batch.get_attribute_vec::<i64>("timestamp").unwrap(),
+            &[0, 1, 2, 3]
+        );
+        assert_eq!(batch.position[3], Point3::new(1.0, 1.0, 1.0));
+    }
+}
@@ -0,0 +1,597 @@
+// Copyright 2020 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::errors::*;
+use crate::proto;
+use crate::{AttributeData, AttributeDataType};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+/// Stores floats as integers, with `value = offset + scale * stored value`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FixedPoint {
+    pub offset: f64,
+    pub scale: f64,
+    /// One of U8, U16, U32, I8, I16, I32 and I64. Values outside of its range are clamped.
+    pub data_type: AttributeDataType,
+}
+
+impl FixedPoint {
+    fn quantize(&self, value: f64) -> i64 {
+        let (min, max) = integer_range(self.data_type);
+        let stored = ((value - self.offset) / self.scale).round();
+        num::clamp(stored, min as f64, max as f64) as i64
+    }
+
+    fn dequantize(&self, stored: i64) -> f64 {
+        self.offset + self.scale * stored as f64
+    }
+}
+
+/// How an attribute other than the position is stored. By default, it is stored as is.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AttributeEncoding {
+    /// Quantizes an F32 or F64 attribute.
+    pub fixed_point: Option<FixedPoint>,
+    /// Stores the values of an integer attribute, or the quantized values of a float attribute,
+    /// as zigzag varints of their difference to the previous value. Monotonic values like
+    /// timestamps then mostly take one or two bytes. The first value of a node is relative to
+    /// zero, so nodes with delta encoded attributes can't be appended to.
+    pub delta: bool,
+}
+
+impl AttributeEncoding {
+    pub fn fixed_point(offset: f64, scale: f64, data_type: AttributeDataType) -> Self {
+        AttributeEncoding {
+            fixed_point: Some(FixedPoint {
+                offset,
+                scale,
+                data_type,
+            }),
+            delta: false,
+        }
+    }
+
+    pub fn delta() -> Self {
+        AttributeEncoding {
+            fixed_point: None,
+            delta: true,
+        }
+    }
+
+    pub fn with_delta(mut self) -> Self {
+        self.delta = true;
+        self
+    }
+
+    pub fn is_raw(&self) -> bool {
+        self.fixed_point.is_none() && !self.delta
+    }
+
+    /// Checks that an attribute of this data type can be stored like this.
+    pub fn check(&self, data_type: AttributeDataType) -> Result<()> {
+        let is_valid = match self.fixed_point {
+            Some(fixed_point) => {
+                (data_type == AttributeDataType::F32 || data_type == AttributeDataType::F64)
+                    && fixed_point.data_type != AttributeDataType::U64
+                    && is_integer(fixed_point.data_type)
+                    && fixed_point.scale > 0.0
+            }
+            None => !self.delta || is_integer(data_type),
+        };
+        if is_valid {
+            Ok(())
+        } else {
+            Err(ErrorKind::InvalidInput(format!(
+                "Attributes of type {:?} can't be encoded as {:?}.",
+                data_type, self
+            ))
+            .into())
+        }
+    }
+
+    pub fn from_proto(proto: &proto::AttributeEncoding) -> Result<Self> {
+        let fixed_point = if proto.has_fixed_point() {
+            let fixed_point = proto.get_fixed_point();
+            Some(FixedPoint {
+                offset: fixed_point.offset,
+                scale: fixed_point.scale,
+                data_type: AttributeDataType::from_proto(fixed_point.data_type)?,
+            })
+        } else {
+            None
+        };
+        Ok(AttributeEncoding {
+            fixed_point,
+            delta: proto.delta,
+        })
+    }
+
+    pub fn to_proto(&self) -> proto::AttributeEncoding {
+        let mut proto = proto::AttributeEncoding::new();
+        if let Some(fixed_point) = self.fixed_point {
+            let mut fixed_point_proto = proto::FixedPoint::new();
+            fixed_point_proto.set_offset(fixed_point.offset);
+            fixed_point_proto.set_scale(fixed_point.scale);
+            fixed_point_proto.set_data_type(fixed_point.data_type.to_proto());
+            proto.set_fixed_point(fixed_point_proto);
+        }
+        proto.set_delta(self.delta);
+        proto
+    }
+}
+
+/// The encoding of each attribute of a point cloud. Attributes that are not listed are stored
+/// as is.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AttributeEncodings(HashMap<String, AttributeEncoding>);
+
+impl AttributeEncodings {
+    pub fn with(mut self, attribute: &str, encoding: AttributeEncoding) -> Self {
+        if encoding.is_raw() {
+            self.0.remove(attribute);
+        } else {
+            self.0.insert(attribute.to_string(), encoding);
+        }
+        self
+    }
+
+    pub fn get(&self, attribute: &str) -> AttributeEncoding {
+        self.0.get(attribute).copied().unwrap_or_default()
+    }
+
+    /// The attributes with delta encoding, which can't be appended to.
+    pub fn delta_encoded(&self) -> impl Iterator<Item = &str> {
+        self.0
+            .iter()
+            .filter(|(_, encoding)| encoding.delta)
+            .map(|(attribute, _)| attribute.as_str())
+    }
+
+    /// The encoder for the attribute, or `None` if it is stored as is.
+    pub(crate) fn encoder(&self, attribute: &str) -> Option<AttributeEncoder> {
+        self.0.get(attribute).map(|encoding| AttributeEncoder {
+            encoding: *encoding,
+            previous: 0,
+        })
+    }
+
+    /// The decoder for the attribute, or `None` if it is stored as is.
+    pub fn decoder(
+        &self,
+        attribute: &str,
+        data_type: AttributeDataType,
+    ) -> Result<Option<AttributeDecoder>> {
+        match self.0.get(attribute) {
+            Some(encoding) => {
+                encoding.check(data_type)?;
+                Ok(Some(AttributeDecoder {
+                    encoding: *encoding,
+                    data_type,
+                    previous: 0,
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn from_proto(proto: &HashMap<String, proto::AttributeEncoding>) -> Result<Self> {
+        proto
+            .iter()
+            .try_fold(Self::default(), |encodings, (attribute, encoding)| {
+                Ok(encodings.with(attribute, AttributeEncoding::from_proto(encoding)?))
+            })
+    }
+
+    pub fn to_proto(&self) -> HashMap<String, proto::AttributeEncoding> {
+        self.0
+            .iter()
+            .map(|(attribute, encoding)| (attribute.clone(), encoding.to_proto()))
+            .collect()
+    }
+}
+
+/// Writes an attribute in its encoding. Keeps the last value for delta encoding.
+pub(crate) struct AttributeEncoder {
+    encoding: AttributeEncoding,
+    previous: i64,
+}
+
+impl AttributeEncoder {
+    pub fn write(&mut self, data: &AttributeData, writer: &mut impl Write) -> io::Result<()> {
+        self.encoding
+            .check(data.data_type())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+        let values = match (self.encoding.fixed_point, data) {
+            (Some(fixed_point), AttributeData::F32(data)) => data
+                .iter()
+                .map(|value| fixed_point.quantize(f64::from(*value)))
+                .collect(),
+            (Some(fixed_point), AttributeData::F64(data)) => data
+                .iter()
+                .map(|value| fixed_point.quantize(*value))
+                .collect(),
+            // The check above made sure that this is an integer attribute.
+            (_, data) => integers_to_i64(data),
+        };
+
+        if self.encoding.delta {
+            for value in values {
+                write_varint(zigzag(value.wrapping_sub(self.previous)), writer)?;
+                self.previous = value;
+            }
+            Ok(())
+        } else {
+            // Only fixed point encodings get here, since raw attributes have no encoder.
+            let data_type = self.encoding.fixed_point.unwrap().data_type;
+            values
+                .into_iter()
+                .try_for_each(|value| write_integer(value, data_type, writer))
+        }
+    }
+}
+
+/// Reads an attribute in its encoding. Keeps the last value for delta encoding.
+pub struct AttributeDecoder {
+    encoding: AttributeEncoding,
+    data_type: AttributeDataType,
+    previous: i64,
+}
+
+impl AttributeDecoder {
+    pub fn read(&mut self, num_points: usize, reader: &mut impl Read) -> io::Result<AttributeData> {
+        let mut values = Vec::with_capacity(num_points);
+        if self.encoding.delta {
+            for _ in 0..num_points {
+                self.previous = self.previous.wrapping_add(unzigzag(read_varint(reader)?));
+                values.push(self.previous);
+            }
+        } else {
+            let data_type = self.encoding.fixed_point.unwrap().data_type;
+            for _ in 0..num_points {
+                values.push(read_integer(data_type, reader)?);
+            }
+        }
+
+        Ok(match (self.encoding.fixed_point, self.data_type) {
+            (Some(fixed_point), AttributeDataType::F32) => AttributeData::F32(
+                values
+                    .into_iter()
+                    .map(|value| fixed_point.dequantize(value) as f32)
+                    .collect(),
+            ),
+            (Some(fixed_point), _) => AttributeData::F64(
+                values
+                    .into_iter()
+                    .map(|value| fixed_point.dequantize(value))
+                    .collect(),
+            ),
+            (None, data_type) => integers_from_i64(values, data_type),
+        })
+    }
+}
+
+fn is_integer(data_type: AttributeDataType) -> bool {
+    matches!(
+        data_type,
+        AttributeDataType::U8
+            | AttributeDataType::U16
+            | AttributeDataType::U32
+            | AttributeDataType::U64
+            | AttributeDataType::I8
+            | AttributeDataType::I16
+            | AttributeDataType::I32
+            | AttributeDataType::I64
+    )
+}
+
+fn integer_range(data_type: AttributeDataType) -> (i64, i64) {
+    match data_type {
+        AttributeDataType::U8 => (0, i64::from(u8::MAX)),
+        AttributeDataType::U16 => (0, i64::from(u16::MAX)),
+        AttributeDataType::U32 => (0, i64::from(u32::MAX)),
+        AttributeDataType::I8 => (i64::from(i8::MIN), i64::from(i8::MAX)),
+        AttributeDataType::I16 => (i64::from(i16::MIN), i64::from(i16::MAX)),
+        AttributeDataType::I32 => (i64::from(i32::MIN), i64::from(i32::MAX)),
+        _ => (i64::MIN, i64::MAX),
+    }
+}
+
+// U64 values are reinterpreted, i.e. wrap around.
+fn integers_to_i64(data: &AttributeData) -> Vec<i64> {
+    macro_rules! to_i64 {
+        ($data:ident) => {
+            $data.iter().map(|value| *value as i64).collect()
+        };
+    }
+    match data {
+        AttributeData::U8(data) => to_i64!(data),
+        AttributeData::U16(data) => to_i64!(data),
+        AttributeData::U32(data) => to_i64!(data),
+        AttributeData::U64(data) => to_i64!(data),
+        AttributeData::I8(data) => to_i64!(data),
+        AttributeData::I16(data) => to_i64!(data),
+        AttributeData::I32(data) => to_i64!(data),
+        AttributeData::I64(data) => to_i64!(data),
+        _ => unreachable!(),
+    }
+}
+
+fn integers_from_i64(values: Vec<i64>, data_type: AttributeDataType) -> AttributeData {
+    macro_rules! from_i64 {
+        ($variant:ident, $type:ty) => {
+            AttributeData::$variant(values.into_iter().map(|value| value as $type).collect())
+        };
+    }
+    match data_type {
+        AttributeDataType::U8 => from_i64!(U8, u8),
+        AttributeDataType::U16 => from_i64!(U16, u16),
+        AttributeDataType::U32 => from_i64!(U32, u32),
+        AttributeDataType::U64 => from_i64!(U64, u64),
+        AttributeDataType::I8 => from_i64!(I8, i8),
+        AttributeDataType::I16 => from_i64!(I16, i16),
+        AttributeDataType::I32 => from_i64!(I32, i32),
+        // The decoder checked that this is an integer type.
+        _ => from_i64!(I64, i64),
+    }
+}
+
+fn write_integer(
+    value: i64,
+    data_type: AttributeDataType,
+    writer: &mut impl Write,
+) -> io::Result<()> {
+    match data_type {
+        AttributeDataType::U8 => writer.write_u8(value as u8),
+        AttributeDataType::U16 => writer.write_u16::<LittleEndian>(value as u16),
+        AttributeDataType::U32 => writer.write_u32::<LittleEndian>(value as u32),
+        AttributeDataType::I8 => writer.write_i8(value as i8),
+        AttributeDataType::I16 => writer.write_i16::<LittleEndian>(value as i16),
+        AttributeDataType::I32 => writer.write_i32::<LittleEndian>(value as i32),
+        _ => writer.write_i64::<LittleEndian>(value),
+    }
+}
+
+fn read_integer(data_type: AttributeDataType, reader: &mut impl Read) -> io::Result<i64> {
+    Ok(match data_type {
+        AttributeDataType::U8 => i64::from(reader.read_u8()?),
+        AttributeDataType::U16 => i64::from(reader.read_u16::<LittleEndian>()?),
+        AttributeDataType::U32 => i64::from(reader.read_u32::<LittleEndian>()?),
+        AttributeDataType::I8 => i64::from(reader.read_i8()?),
+        AttributeDataType::I16 => i64::from(reader.read_i16::<LittleEndian>()?),
+        AttributeDataType::I32 => i64::from(reader.read_i32::<LittleEndian>()?),
+        _ => reader.read_i64::<LittleEndian>()?,
+    })
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+// LEB128, i.e. 7 bits per byte, least significant first.
+fn write_varint(mut value: u64, writer: &mut impl Write) -> io::Result<()> {
+    while value >= 0x80 {
+        writer.write_u8((value as u8) | 0x80)?;
+        value >>= 7;
+    }
+    writer.write_u8(value as u8)
+}
+
+fn read_varint(reader: &mut impl Read) -> io::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = reader.read_u8()?;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Varint is longer than 64 bits.",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_provider::{DataSink, MemoryDataProvider};
+    use crate::read_write::{Encoding, NodeIterator, NodeWriter, OpenMode, RawNodeWriter};
+    use crate::PointsBatch;
+    use nalgebra::Point3;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    fn roundtrip(encoding: AttributeEncoding, data: &AttributeData) -> (AttributeData, usize) {
+        let mut encoded = Vec::new();
+        let mut encoder = AttributeEncodings::default()
+            .with("a", encoding)
+            .encoder("a")
+            .unwrap();
+        encoder.write(data, &mut encoded).unwrap();
+        let mut decoder = AttributeEncodings::default()
+            .with("a", encoding)
+            .decoder("a", data.data_type())
+            .unwrap()
+            .unwrap();
+        let decoded = decoder
+            .read(data.len(), &mut std::io::Cursor::new(&encoded))
+            .unwrap();
+        (decoded, encoded.len())
+    }
+
+    #[test]
+    fn test_fixed_point() {
+        let intensities = AttributeData::F32(vec![0.0, 0.5, 1.0, 2.0, -1.0]);
+        let encoding = AttributeEncoding::fixed_point(0.0, 1.0 / 255.0, AttributeDataType::U8);
+        let (decoded, size) = roundtrip(encoding, &intensities);
+        assert_eq!(size, 5);
+        // Values outside of [0, 1] are clamped.
+        match decoded {
+            AttributeData::F32(decoded) => {
+                let expected = [0.0, 128.0 / 255.0, 1.0, 1.0, 0.0];
+                for (value, expected) in decoded.iter().zip(&expected) {
+                    assert!((value - expected).abs() < 1e-6);
+                }
+            }
+            _ => panic!("Decoded to the wrong type."),
+        }
+    }
+
+    #[test]
+    fn test_delta_timestamps() {
+        let timestamps: Vec<i64> = (0..1000).map(|i| 1_580_000_000_000_000 + 100 * i).collect();
+        let (decoded, size) = roundtrip(
+            AttributeEncoding::delta(),
+            &AttributeData::I64(timestamps.clone()),
+        );
+        assert_eq!(decoded, AttributeData::I64(timestamps));
+        // The first timestamp takes 8 bytes, all others one delta of 200 after zigzag.
+        assert_eq!(size, 8 + 999 * 2);
+
+        let wrapping = AttributeData::U64(vec![0, u64::MAX, 1, u64::MAX / 2]);
+        let (decoded, _) = roundtrip(AttributeEncoding::delta(), &wrapping);
+        assert_eq!(decoded, wrapping);
+    }
+
+    #[test]
+    fn test_fixed_point_delta() {
+        let seconds = AttributeData::F64((0..100).map(|i| 1000.0 + f64::from(i) * 0.01).collect());
+        let encoding =
+            AttributeEncoding::fixed_point(1000.0, 1e-3, AttributeDataType::I64).with_delta();
+        let (decoded, size) = roundtrip(encoding, &seconds);
+        assert_eq!(size, 100);
+        match (decoded, seconds) {
+            (AttributeData::F64(decoded), AttributeData::F64(seconds)) => {
+                for (value, expected) in decoded.iter().zip(&seconds) {
+                    assert!((value - expected).abs() < 1e-9);
+                }
+            }
+            _ => panic!("Decoded to the wrong type."),
+        }
+    }
+
+    #[test]
+    fn test_invalid_encodings() {
+        let encodings = AttributeEncodings::default()
+            .with("color", AttributeEncoding::delta())
+            .with(
+                "timestamp",
+                AttributeEncoding::fixed_point(0.0, 1.0, AttributeDataType::U8),
+            );
+        assert!(encodings
+            .decoder("color", AttributeDataType::U8Vec3)
+            .is_err());
+        assert!(encodings
+            .decoder("timestamp", AttributeDataType::I64)
+            .is_err());
+        let mut encoder = encodings.encoder("color").unwrap();
+        let color = AttributeData::U8Vec3(vec![nalgebra::Vector3::new(1, 2, 3)]);
+        assert!(encoder.write(&color, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_attribute_encodings_proto() {
+        let encodings = AttributeEncodings::default()
+            .with(
+                "intensity",
+                AttributeEncoding::fixed_point(-1.0, 0.01, AttributeDataType::U16),
+            )
+            .with("timestamp", AttributeEncoding::delta())
+            .with("color", AttributeEncoding::default());
+        assert!(encodings.get("color").is_raw());
+        assert_eq!(
+            AttributeEncodings::from_proto(&encodings.to_proto()).unwrap(),
+            encodings
+        );
+    }
+
+    #[test]
+    fn test_node_roundtrip() {
+        let data_provider = MemoryDataProvider::new();
+        data_provider.register("test_attribute_encoding_node_roundtrip");
+        let data_sink: Arc<dyn DataSink> = Arc::new(data_provider.clone());
+        let encodings = AttributeEncodings::default()
+            .with(
+                "intensity",
+                AttributeEncoding::fixed_point(0.0, 0.25, AttributeDataType::U8),
+            )
+            .with("timestamp", AttributeEncoding::delta());
+        let mut attributes = BTreeMap::new();
+        attributes.insert(
+            "intensity".to_string(),
+            AttributeData::F32(vec![0.25, 1.5, 10.0]),
+        );
+        attributes.insert(
+            "timestamp".to_string(),
+            AttributeData::I64(vec![100, 90, 120]),
+        );
+        let batch = PointsBatch {
+            position: vec![Point3::new(1.0, 2.0, 3.0); 3],
+            attributes,
+        };
+        let mut writer = RawNodeWriter::with_data_sink(
+            Arc::clone(&data_sink),
+            "r",
+            Encoding::Plain,
+            OpenMode::Truncate,
+            Default::default(),
+            encodings.clone(),
+        );
+        writer.write(&batch).unwrap();
+        drop(writer);
+
+        let attribute_data_types = batch
+            .attributes
+            .iter()
+            .map(|(name, data)| (name.clone(), data.data_type()))
+            .collect();
+        let batches: Vec<_> = NodeIterator::from_data_provider(
+            &data_provider,
+            &attribute_data_types,
+            &Default::default(),
+            &encodings,
+            Encoding::Plain,
+            &"r",
+            3,
+            2,
+        )
+        .unwrap()
+        .collect();
+        assert_eq!(
+            batches[0].attributes["intensity"],
+            AttributeData::F32(vec![0.25, 1.5])
+        );
+        assert_eq!(
+            batches[1].attributes["timestamp"],
+            AttributeData::I64(vec![120])
+        );
+
+        // Appending would continue the deltas from zero.
+        let mut writer = RawNodeWriter::with_data_sink(
+            data_sink,
+            "r",
+            Encoding::Plain,
+            OpenMode::Append,
+            Default::default(),
+            encodings,
+        );
+        assert!(writer.write(&batch).is_err());
+    }
+}
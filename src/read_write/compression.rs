@@ -0,0 +1,262 @@
+// Copyright 2020 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::errors::{Error, ErrorKind};
+use crate::proto;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Result, Write};
+use std::str::FromStr;
+
+/// zstd level 3 is zstd's default and a good trade-off between ratio and speed.
+const ZSTD_LEVEL: i32 = 3;
+/// LZ4 level 0 is the fast (non-HC) mode.
+const LZ4_LEVEL: u32 = 0;
+
+/// How the blob of an attribute is compressed.
+///
+/// zstd compresses better, LZ4 is faster to compress. How much is saved depends on the data: the
+/// synthetic point cloud of the `point_cloud_test` benches has random positions and is close to
+/// incompressible, so there zstd shrinks the octree by 8% and LZ4 not at all, while querying all
+/// points takes about 25% longer with either. Every writer that reopens a blob with
+/// `OpenMode::Append` adds a frame, so S2 cells written by an `S2Splitter` with more cells than
+/// it keeps writers open for are rather fragmented: on the same data, they grow by 7% (zstd) and
+/// 19% (LZ4), and querying them takes 2x (zstd) and 6x (LZ4) as long.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Uncompressed,
+    Zstd,
+    Lz4,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::Uncompressed
+    }
+}
+
+impl Compression {
+    pub fn from_proto(proto: proto::Compression) -> Self {
+        match proto {
+            proto::Compression::UNCOMPRESSED => Compression::Uncompressed,
+            proto::Compression::ZSTD => Compression::Zstd,
+            proto::Compression::LZ4 => Compression::Lz4,
+        }
+    }
+
+    pub fn to_proto(self) -> proto::Compression {
+        match self {
+            Compression::Uncompressed => proto::Compression::UNCOMPRESSED,
+            Compression::Zstd => proto::Compression::ZSTD,
+            Compression::Lz4 => proto::Compression::LZ4,
+        }
+    }
+
+    /// Wraps the reader of a blob so that it returns the uncompressed data.
+    pub fn decoder(self, reader: Box<dyn Read + Send>) -> Result<Box<dyn Read + Send>> {
+        Ok(match self {
+            Compression::Uncompressed => reader,
+            Compression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+            Compression::Lz4 => Box::new(Lz4Decoder::new(reader)?),
+        })
+    }
+
+    /// Wraps the writer of a blob so that it compresses, or returns it if uncompressed.
+    pub(crate) fn encoder<W: Write>(self, writer: W) -> Result<std::result::Result<Encoder<W>, W>> {
+        Ok(match self {
+            Compression::Uncompressed => Err(writer),
+            Compression::Zstd => Ok(Encoder::Zstd(zstd::Encoder::new(writer, ZSTD_LEVEL)?)),
+            Compression::Lz4 => Ok(Encoder::Lz4(
+                lz4::EncoderBuilder::new().level(LZ4_LEVEL).build(writer)?,
+            )),
+        })
+    }
+}
+
+impl FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(compression: &str) -> std::result::Result<Self, Error> {
+        match compression {
+            "none" => Ok(Compression::Uncompressed),
+            "zstd" => Ok(Compression::Zstd),
+            "lz4" => Ok(Compression::Lz4),
+            _ => Err(ErrorKind::InvalidInput(format!(
+                "Unknown compression '{}', expected none, zstd or lz4.",
+                compression
+            ))
+            .into()),
+        }
+    }
+}
+
+/// The compression of each attribute of a point cloud, "position" included. Attributes that are
+/// not listed are uncompressed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AttributeCompression(HashMap<String, Compression>);
+
+impl AttributeCompression {
+    /// Compresses all of the given attributes the same way.
+    pub fn uniform(attributes: &[&str], compression: Compression) -> Self {
+        attributes
+            .iter()
+            .fold(Self::default(), |attribute_compression, attribute| {
+                attribute_compression.with(attribute, compression)
+            })
+    }
+
+    pub fn with(mut self, attribute: &str, compression: Compression) -> Self {
+        if compression == Compression::Uncompressed {
+            self.0.remove(attribute);
+        } else {
+            self.0.insert(attribute.to_string(), compression);
+        }
+        self
+    }
+
+    pub fn get(&self, attribute: &str) -> Compression {
+        self.0.get(attribute).copied().unwrap_or_default()
+    }
+
+    pub fn from_proto(proto: &HashMap<String, proto::Compression>) -> Self {
+        AttributeCompression(
+            proto
+                .iter()
+                .map(|(attribute, compression)| {
+                    (attribute.clone(), Compression::from_proto(*compression))
+                })
+                .filter(|(_, compression)| *compression != Compression::Uncompressed)
+                .collect(),
+        )
+    }
+
+    pub fn to_proto(&self) -> HashMap<String, proto::Compression> {
+        self.0
+            .iter()
+            .map(|(attribute, compression)| (attribute.clone(), compression.to_proto()))
+            .collect()
+    }
+}
+
+/// Compresses everything written to it. Must be finished to write the end of the stream.
+pub(crate) enum Encoder<W: Write> {
+    Zstd(zstd::Encoder<W>),
+    Lz4(lz4::Encoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn finish(self) -> Result<W> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Lz4(encoder) => {
+                let (writer, result) = encoder.finish();
+                result.map(|_| writer)
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.write(buf),
+            Encoder::Lz4(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.flush(),
+            Encoder::Lz4(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Blobs that were appended to consist of several frames. Unlike zstd's, LZ4's decoder stops at
+/// the end of the first frame, so this starts a new decoder for each following frame.
+struct Lz4Decoder {
+    decoder: Option<lz4::Decoder<BufReader<Box<dyn Read + Send>>>>,
+}
+
+impl Lz4Decoder {
+    fn new(reader: Box<dyn Read + Send>) -> Result<Self> {
+        let decoder = lz4::Decoder::new(BufReader::new(reader))?;
+        Ok(Lz4Decoder {
+            decoder: Some(decoder),
+        })
+    }
+}
+
+impl Read for Lz4Decoder {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        while let Some(decoder) = self.decoder.as_mut() {
+            let num_read = decoder.read(buf)?;
+            if num_read > 0 || buf.is_empty() {
+                return Ok(num_read);
+            }
+            let (mut reader, result) = self.decoder.take().unwrap().finish();
+            result?;
+            if !reader.fill_buf()?.is_empty() {
+                self.decoder = Some(lz4::Decoder::new(reader)?);
+            }
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compress(compression: Compression, frames: &[&[u8]]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        for frame in frames {
+            let mut encoder = compression.encoder(Vec::new()).unwrap().ok().unwrap();
+            encoder.write_all(frame).unwrap();
+            compressed.extend(encoder.finish().unwrap());
+        }
+        compressed
+    }
+
+    #[test]
+    fn test_concatenated_frames() {
+        let first: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let second = b"appended later".to_vec();
+        for compression in &[Compression::Zstd, Compression::Lz4] {
+            let compressed = compress(*compression, &[&first, &[], &second]);
+            assert!(compressed.len() < first.len());
+            let mut decoder = compression
+                .decoder(Box::new(std::io::Cursor::new(compressed)))
+                .unwrap();
+            let mut decompressed = Vec::new();
+            decoder.read_to_end(&mut decompressed).unwrap();
+            assert_eq!(decompressed, [&first[..], &second[..]].concat());
+        }
+    }
+
+    #[test]
+    fn test_attribute_compression_proto() {
+        let attribute_compression = AttributeCompression::default()
+            .with("position", Compression::Lz4)
+            .with("color", Compression::Zstd)
+            .with("intensity", Compression::Uncompressed);
+        assert_eq!(
+            attribute_compression.get("intensity"),
+            Compression::Uncompressed
+        );
+        assert_eq!(
+            AttributeCompression::from_proto(&attribute_compression.to_proto()),
+            attribute_compression
+        );
+    }
+}
@@ -0,0 +1,284 @@
+// Copyright 2020 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::data_provider::MappedBlob;
+use crate::read_write::{decode, fixpoint_decode, Encoding, PositionEncoding};
+use crate::{AttributeData, AttributeDataType, PointsBatch};
+use byteorder::{ByteOrder, LittleEndian};
+use nalgebra::{Point3, Vector3};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, ErrorKind};
+
+/// Reads the same uncompressed, unencoded nodes as `RawNodeReader`, but decodes whole batches
+/// from blobs that are already in memory, e.g. because they are memory-mapped.
+pub struct MappedNodeReader {
+    xyz: MappedBlob,
+    attributes: HashMap<String, (AttributeDataType, MappedBlob)>,
+    encoding: Encoding,
+    num_points_read: usize,
+}
+
+impl MappedNodeReader {
+    pub fn new(
+        xyz: MappedBlob,
+        attributes: HashMap<String, (AttributeDataType, MappedBlob)>,
+        encoding: Encoding,
+    ) -> Self {
+        Self {
+            xyz,
+            attributes,
+            encoding,
+            num_points_read: 0,
+        }
+    }
+
+    pub fn read_batch(&mut self, num_points: usize) -> io::Result<PointsBatch> {
+        let bytes_per_coordinate = match &self.encoding {
+            Encoding::Plain => std::mem::size_of::<f64>(),
+            Encoding::ScaledToCube(_, _, pos_enc) => pos_enc.bytes_per_coordinate(),
+        };
+        let xyz = batch_bytes(
+            (*self.xyz).as_ref(),
+            self.num_points_read,
+            num_points,
+            3 * bytes_per_coordinate,
+        )?;
+        let position = decode_positions(xyz, &self.encoding);
+
+        let mut attributes = BTreeMap::new();
+        for (name, (data_type, blob)) in &self.attributes {
+            let bytes = batch_bytes(
+                (**blob).as_ref(),
+                self.num_points_read,
+                num_points,
+                data_type.size_of(),
+            )?;
+            attributes.insert(name.clone(), decode_attribute(bytes, *data_type));
+        }
+
+        self.num_points_read += num_points;
+        Ok(PointsBatch {
+            position,
+            attributes,
+        })
+    }
+}
+
+fn batch_bytes(
+    blob: &[u8],
+    first_point: usize,
+    num_points: usize,
+    bytes_per_point: usize,
+) -> io::Result<&[u8]> {
+    blob.get(first_point * bytes_per_point..(first_point + num_points) * bytes_per_point)
+        .ok_or_else(|| io::Error::new(ErrorKind::UnexpectedEof, "Node data is too short."))
+}
+
+macro_rules! read_into {
+    ($bytes:expr, $read_into:ident, $zero:expr) => {{
+        let mut values = vec![$zero; $bytes.len() / std::mem::size_of_val(&$zero)];
+        LittleEndian::$read_into($bytes, &mut values);
+        values
+    }};
+}
+
+fn decode_positions(xyz: &[u8], encoding: &Encoding) -> Vec<Point3<f64>> {
+    match encoding {
+        Encoding::Plain => read_into!(xyz, read_f64_into, 0f64)
+            .chunks_exact(3)
+            .map(|p| Point3::new(p[0], p[1], p[2]))
+            .collect(),
+        Encoding::ScaledToCube(min, edge_length, pos_enc) => {
+            let (min, edge_length) = (*min, *edge_length);
+            match pos_enc {
+                PositionEncoding::Uint8 => xyz
+                    .chunks_exact(3)
+                    .map(|p| {
+                        Point3::new(
+                            fixpoint_decode(p[0], min.x, edge_length),
+                            fixpoint_decode(p[1], min.y, edge_length),
+                            fixpoint_decode(p[2], min.z, edge_length),
+                        )
+                    })
+                    .collect(),
+                PositionEncoding::Uint16 => read_into!(xyz, read_u16_into, 0u16)
+                    .chunks_exact(3)
+                    .map(|p| {
+                        Point3::new(
+                            fixpoint_decode(p[0], min.x, edge_length),
+                            fixpoint_decode(p[1], min.y, edge_length),
+                            fixpoint_decode(p[2], min.z, edge_length),
+                        )
+                    })
+                    .collect(),
+                PositionEncoding::Float32 => read_into!(xyz, read_f32_into, 0f32)
+                    .chunks_exact(3)
+                    .map(|p| {
+                        Point3::new(
+                            decode(p[0], min.x, edge_length),
+                            decode(p[1], min.y, edge_length),
+                            decode(p[2], min.z, edge_length),
+                        )
+                    })
+                    .collect(),
+                PositionEncoding::Float64 => read_into!(xyz, read_f64_into, 0f64)
+                    .chunks_exact(3)
+                    .map(|p| {
+                        Point3::new(
+                            decode(p[0], min.x, edge_length),
+                            decode(p[1], min.y, edge_length),
+                            decode(p[2], min.z, edge_length),
+                        )
+                    })
+                    .collect(),
+            }
+        }
+    }
+}
+
+fn decode_attribute(bytes: &[u8], data_type: AttributeDataType) -> AttributeData {
+    match data_type {
+        AttributeDataType::U8 => AttributeData::U8(bytes.to_vec()),
+        AttributeDataType::U16 => AttributeData::U16(read_into!(bytes, read_u16_into, 0u16)),
+        AttributeDataType::U32 => AttributeData::U32(read_into!(bytes, read_u32_into, 0u32)),
+        AttributeDataType::U64 => AttributeData::U64(read_into!(bytes, read_u64_into, 0u64)),
+        AttributeDataType::I8 => AttributeData::I8(bytes.iter().map(|b| *b as i8).collect()),
+        AttributeDataType::I16 => AttributeData::I16(read_into!(bytes, read_i16_into, 0i16)),
+        AttributeDataType::I32 => AttributeData::I32(read_into!(bytes, read_i32_into, 0i32)),
+        AttributeDataType::I64 => AttributeData::I64(read_into!(bytes, read_i64_into, 0i64)),
+        AttributeDataType::F32 => AttributeData::F32(read_into!(bytes, read_f32_into, 0f32)),
+        AttributeDataType::F64 => AttributeData::F64(read_into!(bytes, read_f64_into, 0f64)),
+        AttributeDataType::U8Vec3 => AttributeData::U8Vec3(
+            bytes
+                .chunks_exact(3)
+                .map(|v| Vector3::new(v[0], v[1], v[2]))
+                .collect(),
+        ),
+        AttributeDataType::F64Vec3 => AttributeData::F64Vec3(
+            read_into!(bytes, read_f64_into, 0f64)
+                .chunks_exact(3)
+                .map(|v| Vector3::new(v[0], v[1], v[2]))
+                .collect(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_provider::{DataProvider, OnDiskDataProvider};
+    use crate::read_write::{AttributeReader, NodeWriter, OpenMode, RawNodeReader, RawNodeWriter};
+    use std::io::BufReader;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_same_as_raw_node_reader() {
+        let directory = TempDir::new("mapped").unwrap();
+        let data_provider = OnDiskDataProvider::new(directory.path()).memory_map(true);
+        let mut attributes = BTreeMap::new();
+        attributes.insert(
+            "color".to_string(),
+            AttributeData::U8Vec3((0..10).map(|i| Vector3::new(i, 2 * i, 3 * i)).collect()),
+        );
+        attributes.insert(
+            "timestamp".to_string(),
+            AttributeData::I64((0..10).map(|i| -1000 * i).collect()),
+        );
+        let batch = PointsBatch {
+            position: (0..10)
+                .map(|i| Point3::new(f64::from(i), 0.5, -f64::from(i)))
+                .collect(),
+            attributes,
+        };
+        let attribute_data_types: HashMap<_, _> = batch
+            .attributes
+            .iter()
+            .map(|(name, data)| (name.clone(), data.data_type()))
+            .collect();
+        let names = ["position", "color", "timestamp"];
+        let encodings = [
+            Encoding::Plain,
+            Encoding::ScaledToCube(
+                Point3::new(-10.0, -10.0, -10.0),
+                20.0,
+                PositionEncoding::Uint8,
+            ),
+            Encoding::ScaledToCube(
+                Point3::new(-10.0, -10.0, -10.0),
+                20.0,
+                PositionEncoding::Uint16,
+            ),
+            Encoding::ScaledToCube(
+                Point3::new(-10.0, -10.0, -10.0),
+                20.0,
+                PositionEncoding::Float32,
+            ),
+            Encoding::ScaledToCube(
+                Point3::new(-10.0, -10.0, -10.0),
+                20.0,
+                PositionEncoding::Float64,
+            ),
+        ];
+        for encoding in &encodings {
+            let mut writer = RawNodeWriter::new(
+                directory.path().join("r"),
+                encoding.clone(),
+                OpenMode::Truncate,
+            );
+            writer.write(&batch).unwrap();
+            drop(writer);
+
+            let mut readers = data_provider.data("r", &names).unwrap();
+            let attribute_readers = attribute_data_types
+                .iter()
+                .map(|(name, data_type)| {
+                    let reader = AttributeReader {
+                        data_type: *data_type,
+                        reader: BufReader::new(readers.remove(name).unwrap()),
+                        decoder: None,
+                    };
+                    (name.clone(), reader)
+                })
+                .collect();
+            let mut raw_reader = RawNodeReader::new(
+                readers.remove("position").unwrap(),
+                attribute_readers,
+                encoding.clone(),
+            )
+            .unwrap();
+
+            let mut blobs = data_provider.mapped_data("r", &names).unwrap().unwrap();
+            let mapped_attributes = attribute_data_types
+                .iter()
+                .map(|(name, data_type)| (name.clone(), (*data_type, blobs.remove(name).unwrap())))
+                .collect();
+            let mut mapped_reader = MappedNodeReader::new(
+                blobs.remove("position").unwrap(),
+                mapped_attributes,
+                encoding.clone(),
+            );
+
+            for num_points in &[4, 6] {
+                let expected = raw_reader.read_batch(*num_points).unwrap();
+                let batch = mapped_reader.read_batch(*num_points).unwrap();
+                assert_eq!(batch.position, expected.position);
+                assert_eq!(batch.attributes, expected.attributes);
+            }
+            assert_eq!(
+                mapped_reader.read_batch(1).unwrap_err().kind(),
+                ErrorKind::UnexpectedEof
+            );
+        }
+    }
+}
@@ -12,12 +12,23 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod attribute_encoding;
+pub use self::attribute_encoding::{
+    AttributeDecoder, AttributeEncoding, AttributeEncodings, FixedPoint,
+};
+
 mod codec;
 pub use self::codec::{
     decode, fixpoint_decode, fixpoint_encode, vec3_encode, vec3_fixpoint_encode, Encoding,
     PositionEncoding,
 };
 
+mod compression;
+pub use self::compression::{AttributeCompression, Compression};
+
+mod mapped;
+pub use self::mapped::MappedNodeReader;
+
 mod node_iterator;
 pub use self::node_iterator::NodeIterator;
 
@@ -38,6 +49,8 @@ use std::io::{BufReader, Read};
 pub struct AttributeReader {
     pub data_type: crate::AttributeDataType,
     pub reader: BufReader<Box<dyn Read + Send>>,
+    /// Set if the attribute is not stored as is.
+    pub decoder: Option<AttributeDecoder>,
 }
 
 /// We open a lot of files during our work. Sometimes users see errors with 'cannot open more
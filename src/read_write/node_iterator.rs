@@ -14,15 +14,23 @@
 
 use crate::data_provider::DataProvider;
 use crate::errors::*;
-use crate::read_write::{AttributeReader, Encoding, RawNodeReader};
+use crate::read_write::{
+    AttributeCompression, AttributeEncodings, AttributeReader, Compression, Encoding,
+    MappedNodeReader, RawNodeReader,
+};
 use crate::{AttributeDataType, NumberOfPoints, PointsBatch};
 use num_integer::div_ceil;
 use std::collections::HashMap;
 use std::io::BufReader;
 
+enum NodeReader {
+    Raw(RawNodeReader),
+    Mapped(MappedNodeReader),
+}
+
 /// Streams points from our data provider representation.
 pub struct NodeIterator {
-    reader: Option<RawNodeReader>,
+    reader: Option<NodeReader>,
     num_points: usize,
     point_count: usize,
     batch_size: usize,
@@ -46,16 +54,34 @@ impl NodeIterator {
         }
 
         NodeIterator {
-            reader: Some(reader),
+            reader: Some(NodeReader::Raw(reader)),
+            num_points,
+            point_count: 0,
+            batch_size,
+        }
+    }
+
+    pub fn from_mapped(reader: MappedNodeReader, num_points: usize, batch_size: usize) -> Self {
+        if num_points == 0 {
+            return NodeIterator::default();
+        }
+
+        NodeIterator {
+            reader: Some(NodeReader::Mapped(reader)),
             num_points,
             point_count: 0,
             batch_size,
         }
     }
 
+    /// Reads the node from the data provider. If the data provider supports it and all blobs are
+    /// stored as is, the node is decoded straight from memory-mapped blobs.
+    #[allow(clippy::too_many_arguments)]
     pub fn from_data_provider<Id: ToString>(
         data_provider: &dyn DataProvider,
         attribute_data_types: &HashMap<String, AttributeDataType>,
+        attribute_compression: &AttributeCompression,
+        attribute_encodings: &AttributeEncodings,
         encoding: Encoding,
         id: &Id,
         num_points: usize,
@@ -66,20 +92,56 @@ impl NodeIterator {
         }
 
         let attributes: Vec<&str> = attribute_data_types.keys().map(String::as_str).collect();
-        let mut all_reads =
-            data_provider.data(&id.to_string(), &[&["position"], &attributes[..]].concat())?;
+        let all_attributes = [&["position"], &attributes[..]].concat();
+        let is_raw = all_attributes.iter().all(|attribute| {
+            attribute_compression.get(attribute) == Compression::Uncompressed
+                && attribute_encodings.get(attribute).is_raw()
+        });
+        if is_raw {
+            if let Some(mut blobs) = data_provider.mapped_data(&id.to_string(), &all_attributes)? {
+                // Unwrapping all removals is safe, as the data provider would already have
+                // errored on unavailability.
+                let xyz = blobs.remove("position").unwrap();
+                let attributes = attribute_data_types
+                    .iter()
+                    .map(|(attribute, data_type)| {
+                        (
+                            attribute.clone(),
+                            (*data_type, blobs.remove(attribute).unwrap()),
+                        )
+                    })
+                    .collect();
+                return Ok(Self::from_mapped(
+                    MappedNodeReader::new(xyz, attributes, encoding),
+                    num_points,
+                    batch_size,
+                ));
+            }
+        }
+
+        let mut all_reads = data_provider.data(&id.to_string(), &all_attributes)?;
         // Unwrapping all following removals is safe,
         // as the data provider would already have errored on unavailability.
-        let position_reader = all_reads.remove("position").unwrap();
+        let position_reader = attribute_compression
+            .get("position")
+            .decoder(all_reads.remove("position").unwrap())?;
         let attribute_readers = attribute_data_types
             .iter()
             .map(|(attribute, data_type)| {
                 let data_type = *data_type;
-                let reader = BufReader::new(all_reads.remove(attribute).unwrap());
-                let attribute_reader = AttributeReader { data_type, reader };
-                (attribute.clone(), attribute_reader)
+                let reader = attribute_compression
+                    .get(attribute)
+                    .decoder(all_reads.remove(attribute).unwrap())?;
+                let reader = BufReader::new(reader);
+                let decoder = attribute_encodings.decoder(attribute, data_type)?;
+                let attribute_reader = AttributeReader {
+                    data_type,
+                    reader,
+                    decoder,
+                };
+                Ok((attribute.clone(), attribute_reader))
             })
-            .collect();
+            .collect::<Result<_>>()?;
 
         Ok(Self::new(
             RawNodeReader::new(position_reader, attribute_readers, encoding)?,
@@ -87,6 +149,23 @@ impl NodeIterator {
             batch_size,
         ))
     }
+
+    /// Like `next()`, but returns read errors instead of panicking on them.
+    pub fn try_next(&mut self) -> Result<Option<PointsBatch>> {
+        if let Some(reader) = &mut self.reader {
+            if self.point_count < self.num_points {
+                let num_points_to_read =
+                    std::cmp::min(self.batch_size, self.num_points - self.point_count);
+                let res = match reader {
+                    NodeReader::Raw(reader) => reader.read_batch(num_points_to_read)?,
+                    NodeReader::Mapped(reader) => reader.read_batch(num_points_to_read)?,
+                };
+                self.point_count += num_points_to_read;
+                return Ok(Some(res));
+            }
+        }
+        Ok(None)
+    }
 }
 
 impl NumberOfPoints for NodeIterator {
@@ -103,17 +182,6 @@ impl Iterator for NodeIterator {
         (num_batches, Some(num_batches))
     }
     fn next(&mut self) -> Option<PointsBatch> {
-        if let Some(reader) = &mut self.reader {
-            if self.point_count < self.num_points {
-                let num_points_to_read =
-                    std::cmp::min(self.batch_size, self.num_points - self.point_count);
-                let res = reader
-                    .read_batch(num_points_to_read)
-                    .expect("Couldn't read from node.");
-                self.point_count += num_points_to_read;
-                return Some(res);
-            }
-        }
-        None
+        self.try_next().expect("Couldn't read from node.")
     }
 }
@@ -13,13 +13,18 @@
 // limitations under the License.
 
 use crate::color::Color;
-use crate::read_write::{vec3_encode, vec3_fixpoint_encode, Encoding, PositionEncoding};
+use crate::data_provider::DataSink;
+use crate::read_write::compression::Encoder;
+use crate::read_write::{
+    vec3_encode, vec3_fixpoint_encode, Compression, Encoding, PositionEncoding,
+};
 use crate::AttributeData;
 use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
 use nalgebra::{Point3, Vector3};
 use std::fs::{remove_file, File, OpenOptions};
-use std::io::{BufWriter, Result, Seek, SeekFrom, Write};
+use std::io::{BufWriter, Error, ErrorKind, Result, Seek, SeekFrom, Write};
 use std::path::PathBuf;
+use std::sync::Arc;
 
 #[derive(Clone, Copy, PartialEq)]
 pub enum OpenMode {
@@ -27,37 +32,179 @@ pub enum OpenMode {
     Append,
 }
 
+/// Where a `DataWriter` writes to.
+enum Destination {
+    File(File, PathBuf),
+    Blob(Box<dyn Write + Send>, Arc<dyn DataSink>, String),
+}
+
+impl Write for Destination {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            Destination::File(file, _) => file.write(buf),
+            Destination::Blob(blob, _, _) => blob.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Destination::File(file, _) => file.flush(),
+            Destination::Blob(blob, _, _) => blob.flush(),
+        }
+    }
+}
+
+impl Seek for Destination {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        match self {
+            Destination::File(file, _) => file.seek(pos),
+            Destination::Blob(..) => Err(Error::new(
+                ErrorKind::Other,
+                "Blobs of a data sink can only be appended to.",
+            )),
+        }
+    }
+}
+
+impl Destination {
+    fn remove(self) {
+        // We are ignoring deletion errors here in case the file is already gone.
+        match self {
+            Destination::File(file, path) => {
+                drop(file);
+                let _ = remove_file(path);
+            }
+            Destination::Blob(blob, data_sink, file_name) => {
+                drop(blob);
+                let _ = data_sink.remove_blob(&file_name);
+            }
+        }
+    }
+}
+
+enum Inner {
+    Plain(BufWriter<Destination>),
+    Compressed(BufWriter<Encoder<Destination>>),
+}
+
+impl Inner {
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Inner::Plain(writer) => writer,
+            Inner::Compressed(writer) => writer,
+        }
+    }
+
+    /// Flushes all data and, if compressed, writes the end of the stream.
+    fn finish(self) -> Result<Destination> {
+        match self {
+            Inner::Plain(writer) => writer.into_inner().map_err(|err| err.into()),
+            Inner::Compressed(writer) => writer
+                .into_inner()
+                .map_err(Error::from)
+                .and_then(Encoder::finish),
+        }
+    }
+}
+
+/// Writes a file on disk, or a blob of a `DataSink`.
 pub struct DataWriter {
-    inner: BufWriter<File>,
+    // Only `None` once finished.
+    inner: Option<Inner>,
     bytes_written: u64,
-    path: PathBuf,
+    // Whether a compressed blob had data before it was appended to.
+    had_data: bool,
 }
 
 impl DataWriter {
     pub fn new(path: impl Into<PathBuf>, open_mode: OpenMode) -> Result<Self> {
         let path = path.into();
-        let mut inner = OpenOptions::new()
+        let file = OpenOptions::new()
             .write(true)
             .create(true)
             .truncate(open_mode == OpenMode::Truncate)
-            .open(&path)
-            .map(BufWriter::new)?;
+            .open(&path)?;
+        let mut inner = BufWriter::new(Destination::File(file, path));
         let bytes_written = inner.seek(SeekFrom::End(0))?;
         Ok(DataWriter {
-            inner,
+            inner: Some(Inner::Plain(inner)),
             bytes_written,
-            path,
+            had_data: false,
         })
     }
 
+    /// Writes the blob `file_name` of the data sink. Such writers can't seek.
+    ///
+    /// Compressed blobs are written as one frame per writer, i.e. appending adds a frame. For them,
+    /// `bytes_written` only counts the uncompressed bytes written by this writer.
+    pub fn from_data_sink(
+        data_sink: &Arc<dyn DataSink>,
+        file_name: impl Into<String>,
+        open_mode: OpenMode,
+        compression: Compression,
+    ) -> Result<Self> {
+        let file_name = file_name.into();
+        let to_io_error = |err: crate::errors::Error| Error::new(ErrorKind::Other, err.to_string());
+        let blob = data_sink
+            .open_blob(&file_name, open_mode)
+            .map_err(to_io_error)?;
+        let existing_size = match open_mode {
+            OpenMode::Truncate => 0,
+            OpenMode::Append => data_sink
+                .blob_size(&file_name)
+                .map_err(to_io_error)?
+                .unwrap_or(0),
+        };
+        let destination = Destination::Blob(blob, Arc::clone(data_sink), file_name);
+        let writer = match compression.encoder(destination)? {
+            Ok(encoder) => DataWriter {
+                inner: Some(Inner::Compressed(BufWriter::new(encoder))),
+                bytes_written: 0,
+                had_data: existing_size > 0,
+            },
+            Err(destination) => DataWriter {
+                inner: Some(Inner::Plain(BufWriter::new(destination))),
+                bytes_written: existing_size,
+                had_data: false,
+            },
+        };
+        Ok(writer)
+    }
+
     pub fn bytes_written(&self) -> u64 {
         self.bytes_written
     }
+
+    /// Whether neither this writer nor anyone before it wrote data.
+    pub fn is_empty(&self) -> bool {
+        self.bytes_written == 0 && !self.had_data
+    }
+
+    /// Completes the file or blob: Writes the end of a compressed stream and flushes everything,
+    /// or removes it if it is empty. Dropping the writer does the same, but can't report errors.
+    pub fn finish(mut self) -> Result<()> {
+        self.finish_in_place()
+    }
+
+    /// Like `finish`, for writers that can't be moved out of, e.g. of types implementing `Drop`.
+    /// Does nothing if the writer was finished before.
+    pub(super) fn finish_in_place(&mut self) -> Result<()> {
+        let mut destination = match self.inner.take() {
+            Some(inner) => inner.finish()?,
+            None => return Ok(()),
+        };
+        // If we did not write anything into this node, it should not exist.
+        if self.is_empty() {
+            destination.remove();
+            return Ok(());
+        }
+        destination.flush()
+    }
 }
 
 impl Write for DataWriter {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        let res = self.inner.write(buf);
+        let res = self.inner.as_mut().unwrap().writer().write(buf);
         if let Ok(size) = res {
             self.bytes_written += size as u64;
         }
@@ -65,23 +212,25 @@ impl Write for DataWriter {
     }
 
     fn flush(&mut self) -> Result<()> {
-        self.inner.flush()
+        self.inner.as_mut().unwrap().writer().flush()
     }
 }
 
 impl Seek for DataWriter {
     fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
-        self.inner.seek(pos)
+        match self.inner.as_mut().unwrap() {
+            Inner::Plain(writer) => writer.seek(pos),
+            Inner::Compressed(_) => {
+                Err(Error::new(ErrorKind::Other, "Compressed blobs can't seek."))
+            }
+        }
     }
 }
 
 impl Drop for DataWriter {
     fn drop(&mut self) {
-        // If we did not write anything into this node, it should not exist.
-        if self.bytes_written == 0 {
-            // We are ignoring deletion errors here in case the file is already gone.
-            let _ = remove_file(&self.path);
-        }
+        // Only a fallback for writers that were not finished, errors can't be reported from here.
+        let _ = self.finish_in_place();
 
         // TODO(hrapp): Add some sanity checks that we do not have nodes with ridiculously low
         // amount of points laying around?
@@ -318,4 +467,7 @@ impl WriteEncoded for Vec<Point3<f64>> {
 pub trait NodeWriter<P> {
     fn new(path: impl Into<PathBuf>, codec: Encoding, open_mode: OpenMode) -> Self;
     fn write(&mut self, p: &P) -> Result<()>;
+    /// Completes the node and reports errors doing so. Writers that are dropped instead are
+    /// completed as well, but their errors are lost.
+    fn finish(self) -> Result<()>;
 }
@@ -610,6 +610,10 @@ impl NodeWriter<PointsBatch> for PlyNodeWriter {
 
         Ok(())
     }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.finish_in_place()
+    }
 }
 
 impl NodeWriter<Point> for PlyNodeWriter {
@@ -636,30 +640,37 @@ impl NodeWriter<Point> for PlyNodeWriter {
 
         Ok(())
     }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.finish_in_place()
+    }
 }
 
 impl Drop for PlyNodeWriter {
     fn drop(&mut self) {
-        if self.point_count == 0 {
-            return;
-        }
-        self.writer.write_all(b"\n").unwrap();
-        if self
-            .writer
-            .seek(SeekFrom::Start(HEADER_START_TO_NUM_VERTICES.len() as u64))
-            .is_ok()
-        {
-            let _res = write!(
+        let _ = self.finish_in_place();
+    }
+}
+
+impl PlyNodeWriter {
+    /// Writes the number of points into the header and completes the file. Does nothing if it
+    /// was completed before.
+    fn finish_in_place(&mut self) -> io::Result<()> {
+        if self.point_count > 0 {
+            self.writer.write_all(b"\n")?;
+            self.writer
+                .seek(SeekFrom::Start(HEADER_START_TO_NUM_VERTICES.len() as u64))?;
+            write!(
                 &mut self.writer,
                 "{:0width$}",
                 self.point_count,
                 width = HEADER_NUM_VERTICES.len()
-            );
+            )?;
+            self.point_count = 0;
         }
+        self.writer.finish_in_place()
     }
-}
 
-impl PlyNodeWriter {
     pub fn new(filename: impl Into<PathBuf>, encoding: Encoding, open_mode: OpenMode) -> Self {
         let filename = filename.into();
         let mut point_count = 0;
@@ -13,17 +13,20 @@
 // limitations under the License.
 
 use crate::color;
+use crate::data_provider::{blob_name, DataSink, OnDiskDataProvider};
 use crate::errors::*;
 use crate::read_write::{
-    decode, fixpoint_decode, AttributeReader, DataWriter, Encoding, NodeWriter, OpenMode,
+    attribute_encoding::AttributeEncoder, decode, fixpoint_decode, AttributeCompression,
+    AttributeEncodings, AttributeReader, DataWriter, Encoding, NodeWriter, OpenMode,
     PositionEncoding, WriteEncoded, WriteLE,
 };
-use crate::{attribute_extension, AttributeData, AttributeDataType, Point, PointsBatch};
+use crate::{AttributeData, AttributeDataType, Point, PointCloudMeta, PointsBatch};
 use byteorder::{LittleEndian, ReadBytesExt};
 use nalgebra::{Point3, Vector3};
 use std::collections::{BTreeMap, HashMap};
 use std::io::{self, BufReader, ErrorKind, Read};
 use std::path::PathBuf;
+use std::sync::Arc;
 
 pub struct RawNodeReader {
     xyz_reader: BufReader<Box<dyn Read + Send>>,
@@ -118,7 +121,18 @@ impl RawNodeReader {
         }
 
         if let Some(ir) = self.attribute_readers.get_mut("intensity") {
-            point.intensity = Some(ir.reader.read_f32::<LittleEndian>()?);
+            point.intensity = match &mut ir.decoder {
+                Some(decoder) => match decoder.read(1, &mut ir.reader)? {
+                    AttributeData::F32(intensity) => Some(intensity[0]),
+                    _ => {
+                        return Err(io::Error::new(
+                            ErrorKind::InvalidData,
+                            "Intensity is not F32.",
+                        ))
+                    }
+                },
+                None => Some(ir.reader.read_f32::<LittleEndian>()?),
+            };
         }
 
         Ok(point)
@@ -217,7 +231,20 @@ impl RawNodeReader {
 
         // TODO(nnmm): Implement ReadLE trait and rewrite this section with a macro
         self.attribute_readers.iter_mut().try_for_each(
-            |(key, AttributeReader { data_type, reader })| -> io::Result<()> {
+            |(
+                key,
+                AttributeReader {
+                    data_type,
+                    reader,
+                    decoder,
+                },
+            )|
+             -> io::Result<()> {
+                if let Some(decoder) = decoder {
+                    let attr = decoder.read(num_points, reader)?;
+                    batch.attributes.insert(key.to_owned(), attr);
+                    return Ok(());
+                }
                 match data_type {
                     AttributeDataType::U8 => {
                         let mut attr = vec![0; num_points];
@@ -361,9 +388,14 @@ impl RawNodeReader {
 pub struct RawNodeWriter {
     xyz_writer: DataWriter,
     attribute_writers: Vec<DataWriter>,
-    stem: PathBuf,
+    // Parallel to `attribute_writers`, `None` for attributes that are stored as is.
+    attribute_encoders: Vec<Option<AttributeEncoder>>,
+    data_sink: Arc<dyn DataSink>,
+    node_id: String,
     encoding: Encoding,
     open_mode: OpenMode,
+    attribute_compression: AttributeCompression,
+    attribute_encodings: AttributeEncodings,
 }
 
 impl NodeWriter<PointsBatch> for RawNodeWriter {
@@ -377,19 +409,20 @@ impl NodeWriter<PointsBatch> for RawNodeWriter {
 
         if self.attribute_writers.is_empty() {
             for name in p.attributes.keys() {
-                self.attribute_writers.push(DataWriter::new(
-                    &self.stem.with_extension(attribute_extension(&name)),
-                    self.open_mode,
-                )?)
+                self.push_attribute_writer(name)?;
             }
         }
 
         for (i, data) in p.attributes.values().enumerate() {
-            data.write_le(&mut self.attribute_writers[i])?;
+            self.write_attribute(i, data)?;
         }
 
         Ok(())
     }
+
+    fn finish(self) -> io::Result<()> {
+        RawNodeWriter::finish(self)
+    }
 }
 
 impl NodeWriter<Point> for RawNodeWriter {
@@ -402,44 +435,113 @@ impl NodeWriter<Point> for RawNodeWriter {
             .write_encoded(&self.encoding, &mut self.xyz_writer)?;
 
         if self.attribute_writers.is_empty() {
-            self.attribute_writers.push(DataWriter::new(
-                &self.stem.with_extension(attribute_extension("color")),
-                self.open_mode,
-            )?);
+            self.push_attribute_writer("color")?;
             if p.intensity.is_some() {
-                self.attribute_writers.push(DataWriter::new(
-                    &self.stem.with_extension(attribute_extension("intensity")),
-                    self.open_mode,
-                )?);
+                self.push_attribute_writer("intensity")?;
             }
         }
-        p.color.write_le(&mut self.attribute_writers[0])?;
+        match &mut self.attribute_encoders[0] {
+            Some(encoder) => encoder.write(
+                &AttributeData::U8Vec3(vec![Vector3::new(
+                    p.color.red,
+                    p.color.green,
+                    p.color.blue,
+                )]),
+                &mut self.attribute_writers[0],
+            )?,
+            None => p.color.write_le(&mut self.attribute_writers[0])?,
+        }
         if let Some(i) = p.intensity {
-            i.write_le(&mut self.attribute_writers[1])?;
+            self.write_attribute(1, &AttributeData::F32(vec![i]))?;
         }
 
         Ok(())
     }
+
+    fn finish(self) -> io::Result<()> {
+        RawNodeWriter::finish(self)
+    }
 }
 
 impl RawNodeWriter {
+    /// Writes the node files `<path>.<extension>` on disk.
     pub fn new(path: impl Into<PathBuf>, encoding: Encoding, open_mode: OpenMode) -> Self {
-        let stem: PathBuf = path.into();
-        let xyz_writer = DataWriter::new(
-            &stem.with_extension(attribute_extension("position")),
+        let path: PathBuf = path.into();
+        let data_sink = Arc::new(OnDiskDataProvider::new(
+            path.parent().map(PathBuf::from).unwrap_or_default(),
+        ));
+        let node_id = path
+            .file_name()
+            .expect("Node path has no file name.")
+            .to_string_lossy();
+        Self::with_data_sink(
+            data_sink,
+            &node_id,
+            encoding,
             open_mode,
+            AttributeCompression::default(),
+            AttributeEncodings::default(),
+        )
+    }
+
+    /// Writes the blobs of node `node_id` into the data sink, compressed and encoded as given.
+    pub fn with_data_sink(
+        data_sink: Arc<dyn DataSink>,
+        node_id: &str,
+        encoding: Encoding,
+        open_mode: OpenMode,
+        attribute_compression: AttributeCompression,
+        attribute_encodings: AttributeEncodings,
+    ) -> Self {
+        let xyz_writer = DataWriter::from_data_sink(
+            &data_sink,
+            blob_name(node_id, "position"),
+            open_mode,
+            attribute_compression.get("position"),
         )
         .unwrap();
-        let attribute_writers = Vec::new();
         Self {
             xyz_writer,
-            attribute_writers,
-            stem,
+            attribute_writers: Vec::new(),
+            attribute_encoders: Vec::new(),
+            data_sink,
+            node_id: node_id.to_string(),
             encoding,
             open_mode,
+            attribute_compression,
+            attribute_encodings,
+        }
+    }
+
+    fn push_attribute_writer(&mut self, name: &str) -> io::Result<()> {
+        let writer = DataWriter::from_data_sink(
+            &self.data_sink,
+            blob_name(&self.node_id, name),
+            self.open_mode,
+            self.attribute_compression.get(name),
+        )?;
+        // Deltas would continue from zero instead of the last value already in the blob.
+        if self.attribute_encodings.get(name).delta && !writer.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Can't append to delta encoded attribute '{}'.", name),
+            ));
+        }
+        self.attribute_writers.push(writer);
+        self.attribute_encoders
+            .push(self.attribute_encodings.encoder(name));
+        Ok(())
+    }
+
+    fn write_attribute(&mut self, i: usize, data: &AttributeData) -> io::Result<()> {
+        match &mut self.attribute_encoders[i] {
+            Some(encoder) => encoder.write(data, &mut self.attribute_writers[i]),
+            None => data.write_le(&mut self.attribute_writers[i]),
         }
     }
 
+    /// The number of points written by this writer. With `OpenMode::Append`, points that were
+    /// in an uncompressed node before are included.
     pub fn num_written(&self) -> i64 {
         let bytes_per_coordinate = match &self.encoding {
             Encoding::Plain => std::mem::size_of::<f64>(),
@@ -447,4 +549,42 @@ impl RawNodeWriter {
         } as i64;
         self.xyz_writer.bytes_written() as i64 / bytes_per_coordinate / 3
     }
+
+    /// Completes the blobs of the node, see `DataWriter::finish`.
+    pub fn finish(self) -> io::Result<()> {
+        self.xyz_writer.finish()?;
+        for writer in self.attribute_writers {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the blobs of node `node_id` with the points, or removes the blobs of the position
+    /// and the attributes if there are no points.
+    pub(crate) fn write_or_remove(
+        data_sink: &Arc<dyn DataSink>,
+        node_id: &str,
+        encoding: Encoding,
+        meta: &impl PointCloudMeta,
+        attributes: &[&str],
+        batch: &PointsBatch,
+    ) -> Result<()> {
+        if batch.position.is_empty() {
+            for attribute in std::iter::once(&"position").chain(attributes) {
+                data_sink.remove_blob(&blob_name(node_id, attribute))?;
+            }
+            return Ok(());
+        }
+        let mut writer = Self::with_data_sink(
+            Arc::clone(data_sink),
+            node_id,
+            encoding,
+            OpenMode::Truncate,
+            meta.attribute_compression().clone(),
+            meta.attribute_encodings().clone(),
+        );
+        writer.write(batch)?;
+        writer.finish()?;
+        Ok(())
+    }
 }
@@ -1,8 +1,11 @@
+use crate::data_provider::DataSink;
 use crate::geometry::Aabb;
 use crate::math::{FromPoint3, EARTH_RADIUS_MAX_M, EARTH_RADIUS_MIN_M};
-use crate::read_write::{Encoding, NodeWriter, OpenMode};
+use crate::read_write::{
+    AttributeCompression, AttributeEncodings, Encoding, NodeWriter, OpenMode, RawNodeWriter,
+};
 use crate::s2_cells::{S2CellMeta, S2Meta};
-use crate::{AttributeData, AttributeDataType, PointsBatch};
+use crate::{AttributeData, AttributeDataType, PointCloudMeta, PointsBatch};
 use fnv::FnvHashMap;
 use lru::LruCache;
 use s2::cellid::CellID;
@@ -10,48 +13,186 @@ use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io::{Error, ErrorKind, Result};
 use std::iter::Iterator;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 /// The actual number of underlying writers is MAX_NUM_NODE_WRITERS * num_attributes.
 const MAX_NUM_NODE_WRITERS: usize = 25;
 /// Corresponds to cells of up to about 10m x 10m.
 const DEFAULT_S2_SPLIT_LEVEL: u64 = 20;
 
+/// Creates the writer of a cell, given its token.
+type NewWriter<W> = Box<dyn Fn(&str, Encoding, OpenMode) -> W + Send>;
+
 pub struct S2Splitter<W> {
     split_level: u64,
+    new_writer: NewWriter<W>,
     writers: LruCache<CellID, W>,
     already_opened_writers: HashSet<CellID>,
+    max_num_buffered_points: usize,
+    num_buffered_points: usize,
+    buffered_batches: FnvHashMap<CellID, PointsBatch>,
     cell_stats: FnvHashMap<CellID, S2CellMeta>,
     bounding_box: Option<Aabb>,
     attributes_seen: BTreeMap<String, AttributeDataType>,
     encoding: Encoding,
     open_mode: OpenMode,
-    stem: PathBuf,
+    attribute_compression: AttributeCompression,
+    attribute_encodings: AttributeEncodings,
 }
 
-impl<W> S2Splitter<W> {
+impl<W> S2Splitter<W>
+where
+    W: NodeWriter<PointsBatch> + 'static,
+{
     pub fn with_split_level(
         split_level: u64,
         path: impl Into<PathBuf>,
         encoding: Encoding,
         open_mode: OpenMode,
+    ) -> Self {
+        let stem = path.into();
+        let new_writer =
+            move |token: &str, encoding, open_mode| W::new(stem.join(token), encoding, open_mode);
+        Self::with_new_writer(
+            split_level,
+            Box::new(new_writer),
+            encoding,
+            open_mode,
+            AttributeCompression::default(),
+            AttributeEncodings::default(),
+        )
+    }
+}
+
+impl S2Splitter<RawNodeWriter> {
+    /// Writes the cells into the data sink instead of a directory, compressed and encoded as
+    /// given. Cells are reopened for appending when they are no longer among the open writers, so
+    /// the data sink has to support appending and attributes can't be delta encoded.
+    pub fn with_data_sink(
+        split_level: u64,
+        data_sink: Arc<dyn DataSink>,
+        encoding: Encoding,
+        open_mode: OpenMode,
+        attribute_compression: AttributeCompression,
+        attribute_encodings: AttributeEncodings,
+    ) -> Result<Self> {
+        if !data_sink.can_append() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "S2Splitter needs a data sink that supports appending.",
+            ));
+        }
+        if let Some(attribute) = attribute_encodings.delta_encoded().min() {
+            let msg = format!(
+                "S2Splitter can't write the delta encoded attribute '{}'.",
+                attribute
+            );
+            return Err(Error::new(ErrorKind::InvalidInput, msg));
+        }
+        let writer_compression = attribute_compression.clone();
+        let writer_encodings = attribute_encodings.clone();
+        let new_writer = move |token: &str, encoding, open_mode| {
+            RawNodeWriter::with_data_sink(
+                Arc::clone(&data_sink),
+                token,
+                encoding,
+                open_mode,
+                writer_compression.clone(),
+                writer_encodings.clone(),
+            )
+        };
+        Ok(Self::with_new_writer(
+            split_level,
+            Box::new(new_writer),
+            encoding,
+            open_mode,
+            attribute_compression,
+            attribute_encodings,
+        ))
+    }
+
+    /// Appends to the S2 cells of the meta, which are in the data sink. The points are split at
+    /// the finest level of the existing cells and compressed and encoded like them, and `get_meta`
+    /// returns the meta of the existing and the new points. Pyramids can't be appended to.
+    pub fn appending_to_data_sink(data_sink: Arc<dyn DataSink>, meta: S2Meta) -> Result<Self> {
+        // The coarser cells of a pyramid would keep subsamples of the existing points only.
+        if meta.levels().len() > 1 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Can't append to the S2 cells of a pyramid.",
+            ));
+        }
+        let split_level = meta
+            .levels()
+            .last()
+            .copied()
+            .unwrap_or(DEFAULT_S2_SPLIT_LEVEL);
+        let mut s2_splitter = Self::with_data_sink(
+            split_level,
+            data_sink,
+            Encoding::Plain,
+            OpenMode::Append,
+            meta.attribute_compression().clone(),
+            meta.attribute_encodings().clone(),
+        )?;
+        s2_splitter.cell_stats = meta.get_cells().clone();
+        s2_splitter.bounding_box = Some(meta.bounding_box().clone());
+        s2_splitter.attributes_seen = meta
+            .attribute_data_types()
+            .iter()
+            .map(|(name, data_type)| (name.clone(), *data_type))
+            .collect();
+        Ok(s2_splitter)
+    }
+}
+
+impl<W> S2Splitter<W> {
+    fn with_new_writer(
+        split_level: u64,
+        new_writer: NewWriter<W>,
+        encoding: Encoding,
+        open_mode: OpenMode,
+        attribute_compression: AttributeCompression,
+        attribute_encodings: AttributeEncodings,
     ) -> Self {
         S2Splitter {
             split_level,
+            new_writer,
             writers: LruCache::new(MAX_NUM_NODE_WRITERS),
             already_opened_writers: HashSet::new(),
+            max_num_buffered_points: 0,
+            num_buffered_points: 0,
+            buffered_batches: FnvHashMap::default(),
             cell_stats: FnvHashMap::default(),
             bounding_box: None,
             attributes_seen: BTreeMap::new(),
             encoding,
             open_mode,
-            stem: path.into(),
+            attribute_compression,
+            attribute_encodings,
         }
     }
+
+    /// Keeps up to this many cell writers open at a time, instead of `MAX_NUM_NODE_WRITERS`.
+    /// Needs to be at least 1.
+    pub fn with_max_num_writers(mut self, max_num_writers: usize) -> Self {
+        assert!(max_num_writers > 0, "S2Splitter needs at least one writer.");
+        self.writers = LruCache::new(max_num_writers);
+        self
+    }
+
+    /// Buffers up to this many points before writing them, cell by cell. With input that is not
+    /// ordered by cell, e.g. ordered by time, this avoids reopening the cells over and over again.
+    /// Points still buffered after the last batch are written by `get_meta`.
+    pub fn with_max_num_buffered_points(mut self, max_num_buffered_points: usize) -> Self {
+        self.max_num_buffered_points = max_num_buffered_points;
+        self
+    }
 }
 
 impl<W> NodeWriter<PointsBatch> for S2Splitter<W>
 where
-    W: NodeWriter<PointsBatch>,
+    W: NodeWriter<PointsBatch> + 'static,
 {
     fn new(path: impl Into<PathBuf>, encoding: Encoding, open_mode: OpenMode) -> Self {
         Self::with_split_level(DEFAULT_S2_SPLIT_LEVEL, path, encoding, open_mode)
@@ -107,20 +248,46 @@ where
             }
         }
 
-        for (cell_id, batch) in &batches_by_s2_cell {
-            self.writer(cell_id).write(batch)?;
+        if self.max_num_buffered_points == 0 {
+            for (cell_id, batch) in &batches_by_s2_cell {
+                self.writer(cell_id)?.write(batch)?;
+            }
+            return Ok(());
+        }
+        for (cell_id, mut batch) in batches_by_s2_cell {
+            self.num_buffered_points += batch.position.len();
+            match self.buffered_batches.get_mut(&cell_id) {
+                Some(buffered_batch) => buffered_batch
+                    .append(&mut batch)
+                    .map_err(|msg| Error::new(ErrorKind::InvalidInput, msg))?,
+                None => {
+                    self.buffered_batches.insert(cell_id, batch);
+                }
+            }
+        }
+        if self.num_buffered_points >= self.max_num_buffered_points {
+            self.write_buffered_points()?;
         }
         Ok(())
     }
+
+    fn finish(mut self) -> Result<()> {
+        self.flush()
+    }
 }
 
 impl<W> S2Splitter<W>
 where
     W: NodeWriter<PointsBatch>,
 {
-    fn writer(&mut self, cell_id: &CellID) -> &mut W {
-        let path = self.stem.join(cell_id.to_token());
+    fn writer(&mut self, cell_id: &CellID) -> Result<&mut W> {
         if !self.writers.contains(cell_id) {
+            // The least recently used writer is closed to make room.
+            if self.writers.len() == self.writers.cap() {
+                if let Some((_, writer)) = self.writers.pop_lru() {
+                    writer.finish()?;
+                }
+            }
             let open_mode = if self.open_mode == OpenMode::Append
                 || self.already_opened_writers.contains(cell_id)
             {
@@ -129,10 +296,32 @@ where
                 self.already_opened_writers.insert(*cell_id);
                 OpenMode::Truncate
             };
-            self.writers
-                .put(*cell_id, W::new(path, self.encoding.clone(), open_mode));
+            let writer = (self.new_writer)(&cell_id.to_token(), self.encoding.clone(), open_mode);
+            self.writers.put(*cell_id, writer);
+        }
+        Ok(self.writers.get_mut(cell_id).unwrap())
+    }
+
+    /// Writes the buffered points, ordered by cell so that each cell is opened once.
+    fn write_buffered_points(&mut self) -> Result<()> {
+        let mut buffered_batches: Vec<_> = self.buffered_batches.drain().collect();
+        buffered_batches.sort_unstable_by_key(|(cell_id, _)| *cell_id);
+        for (cell_id, batch) in &buffered_batches {
+            self.writer(cell_id)?.write(batch)?;
+        }
+        self.num_buffered_points = 0;
+        Ok(())
+    }
+
+    /// Writes the buffered points and completes the cells that are open, which reports errors
+    /// e.g. of compressing or uploading them. Cells that are written to afterwards are appended
+    /// to.
+    pub fn flush(&mut self) -> Result<()> {
+        self.write_buffered_points()?;
+        while let Some((_, writer)) = self.writers.pop_lru() {
+            writer.finish()?;
         }
-        self.writers.get_mut(cell_id).unwrap()
+        Ok(())
     }
 
     /// Records the list of attributes seen in the first batch, and checks
@@ -147,6 +336,13 @@ where
                 .extend(attr_iter.map(|(key, val)| (key.to_owned(), val)));
             Ok(())
         } else {
+            if batch.attributes.len() != self.attributes_seen.len() {
+                let msg = format!(
+                    "S2Splitter expected the attributes {:?}",
+                    self.attributes_seen.keys().collect::<Vec<_>>()
+                );
+                return Err(Error::new(ErrorKind::InvalidInput, msg));
+            }
             attr_iter.try_for_each(|(name, dtype)| {
                 self.attributes_seen
                     .get(name)
@@ -163,12 +359,21 @@ where
         }
     }
 
-    pub fn get_meta(self) -> Option<S2Meta> {
+    /// Writes the buffered points, completes all cells and returns the meta of the cells, or
+    /// `None` if no points were written.
+    pub fn get_meta(mut self) -> Result<Option<S2Meta>> {
+        self.flush()?;
+        let bounding_box = match self.bounding_box {
+            Some(bounding_box) => bounding_box,
+            None => return Ok(None),
+        };
         let meta = S2Meta::new(
             self.cell_stats,
             self.attributes_seen.into_iter().collect(),
-            self.bounding_box?,
-        );
-        Some(meta)
+            bounding_box,
+        )
+        .with_attribute_compression(self.attribute_compression)
+        .with_attribute_encodings(self.attribute_encodings);
+        Ok(Some(meta))
     }
 }
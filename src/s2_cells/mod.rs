@@ -1,19 +1,23 @@
 use crate::data_provider::DataProvider;
 use crate::errors::*;
-use crate::geometry::Aabb;
+use crate::geometry::{Aabb, S2CellVolume};
 use crate::iterator::{PointCloud, PointLocation};
-use crate::math::{ConvexPolyhedron, FromPoint3};
+use crate::math::{ConvexPolyhedron, FromPoint3, Relation};
 use crate::proto;
-use crate::read_write::{Encoding, NodeIterator};
-use crate::{AttributeDataType, PointCloudMeta, CURRENT_VERSION};
+use crate::read_write::{AttributeCompression, AttributeEncodings, Encoding, NodeIterator};
+use crate::{check_node_format_version, AttributeDataType, PointCloudMeta, CURRENT_VERSION};
 use fnv::FnvHashMap;
+use nalgebra::Vector3;
 use s2::cell::Cell;
 use s2::cellid::CellID;
 use s2::cellunion::CellUnion;
 use s2::region::Region;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::iter;
 
+mod pyramid;
+pub use self::pyramid::build_pyramid_into;
+
 pub struct S2Cells {
     data_provider: Box<dyn DataProvider>,
     cells: FnvHashMap<CellID, Cell>,
@@ -36,7 +40,11 @@ impl S2CellMeta {
 
 pub struct S2Meta {
     cells: FnvHashMap<CellID, S2CellMeta>,
+    /// The levels of the cells, coarsest first.
+    levels: Vec<u64>,
     attribute_data_types: HashMap<String, AttributeDataType>,
+    attribute_compression: AttributeCompression,
+    attribute_encodings: AttributeEncodings,
     bounding_box: Aabb,
 }
 
@@ -44,6 +52,14 @@ impl PointCloudMeta for S2Meta {
     fn attribute_data_types(&self) -> &HashMap<String, AttributeDataType> {
         &self.attribute_data_types
     }
+
+    fn attribute_compression(&self) -> &AttributeCompression {
+        &self.attribute_compression
+    }
+
+    fn attribute_encodings(&self) -> &AttributeEncodings {
+        &self.attribute_encodings
+    }
 }
 
 impl S2Meta {
@@ -53,12 +69,28 @@ impl S2Meta {
         bounding_box: Aabb,
     ) -> Self {
         S2Meta {
+            levels: levels_of(&cells),
             cells,
             attribute_data_types,
+            attribute_compression: AttributeCompression::default(),
+            attribute_encodings: AttributeEncodings::default(),
             bounding_box,
         }
     }
 
+    pub fn with_attribute_compression(
+        mut self,
+        attribute_compression: AttributeCompression,
+    ) -> Self {
+        self.attribute_compression = attribute_compression;
+        self
+    }
+
+    pub fn with_attribute_encodings(mut self, attribute_encodings: AttributeEncodings) -> Self {
+        self.attribute_encodings = attribute_encodings;
+        self
+    }
+
     pub fn iter_attr_with_xyz(&self) -> impl Iterator<Item = (&str, AttributeDataType)> {
         self.attribute_data_types
             .iter()
@@ -70,6 +102,12 @@ impl S2Meta {
         &self.cells
     }
 
+    /// The levels that cells exist on, coarsest first. Cells on coarser levels hold subsamples of
+    /// the points of their children.
+    pub fn levels(&self) -> &[u64] {
+        &self.levels
+    }
+
     pub fn bounding_box(&self) -> &Aabb {
         &self.bounding_box
     }
@@ -100,7 +138,10 @@ impl S2Meta {
         s2_meta.set_attributes(::protobuf::RepeatedField::<proto::Attribute>::from_vec(
             attributes_meta,
         ));
+        s2_meta.set_levels(self.levels.clone());
         meta.set_s2(s2_meta);
+        meta.set_attribute_compression(self.attribute_compression.to_proto());
+        meta.set_attribute_encoding(self.attribute_encodings.to_proto());
         meta
     }
 
@@ -120,6 +161,10 @@ impl S2Meta {
             )
             .into());
         }
+        if meta_proto.version > CURRENT_VERSION {
+            return Err(ErrorKind::InvalidVersion(meta_proto.version).into());
+        }
+        check_node_format_version(&meta_proto)?;
 
         let bounding_box = Aabb::from(meta_proto.get_bounding_box());
         let s2_meta_proto = meta_proto.get_s2();
@@ -135,15 +180,29 @@ impl S2Meta {
             );
         });
 
+        let levels = if s2_meta_proto.get_levels().is_empty() {
+            levels_of(&cells)
+        } else {
+            s2_meta_proto.get_levels().to_vec()
+        };
+
         let mut attribute_data_types = HashMap::default();
         for attr in s2_meta_proto.attributes.iter() {
             let attr_type: AttributeDataType = AttributeDataType::from_proto(attr.get_data_type())?;
             attribute_data_types.insert(attr.name.to_owned(), attr_type);
         }
 
+        let attribute_compression =
+            AttributeCompression::from_proto(meta_proto.get_attribute_compression());
+        let attribute_encodings =
+            AttributeEncodings::from_proto(meta_proto.get_attribute_encoding())?;
+
         Ok(S2Meta {
             cells,
+            levels,
             attribute_data_types,
+            attribute_compression,
+            attribute_encodings,
             bounding_box,
         })
     }
@@ -154,18 +213,35 @@ impl S2Meta {
     }
 }
 
+/// The smallest and largest distance of the bounding box to the earth's center.
+fn radius_range(bounding_box: &Aabb) -> (f64, f64) {
+    let closest = bounding_box
+        .min()
+        .coords
+        .sup(&Vector3::zeros())
+        .inf(&bounding_box.max().coords);
+    let farthest = bounding_box
+        .compute_corners()
+        .iter()
+        .map(|corner| corner.coords.norm())
+        .fold(0.0, f64::max);
+    (closest.norm(), farthest)
+}
+
+fn levels_of(cells: &FnvHashMap<CellID, S2CellMeta>) -> Vec<u64> {
+    let levels: BTreeSet<u64> = cells.keys().map(CellID::level).collect();
+    levels.into_iter().collect()
+}
+
 impl PointCloud for S2Cells {
     type Id = CellID;
 
-    fn nodes_in_location(&self, location: &PointLocation) -> Vec<Self::Id> {
-        match location {
-            PointLocation::AllPoints => self.cells.keys().cloned().collect(),
-            PointLocation::Aabb(aabb) => self.cells_in_convex_polyhedron(aabb),
-            PointLocation::Obb(obb) => self.cells_in_convex_polyhedron(obb),
-            PointLocation::Frustum(frustum) => self.cells_in_convex_polyhedron(frustum),
-            PointLocation::S2Cells(cell_union) => self.cells_intersecting_region(cell_union),
-            PointLocation::WebMercatorRect(wmr) => self.cells_in_convex_polyhedron(wmr),
+    fn nodes_in_location(&self, location: &PointLocation, max_level: Option<u64>) -> Vec<Self::Id> {
+        let mut cell_ids = self.cells_in_location(location);
+        if let Some(max_level) = max_level {
+            cell_ids.retain(|cell_id| cell_id.level() <= max_level);
         }
+        cell_ids
     }
 
     fn encoding_for_node(&self, _: Self::Id) -> Encoding {
@@ -182,6 +258,8 @@ impl PointCloud for S2Cells {
         let node_iterator = NodeIterator::from_data_provider(
             &*self.data_provider,
             &self.meta.attribute_data_types_for(&attributes)?,
+            &self.meta.attribute_compression,
+            &self.meta.attribute_encodings,
             self.encoding_for_node(node_id),
             &node_id,
             num_points,
@@ -215,8 +293,39 @@ impl S2Cells {
         self.meta.to_proto()
     }
 
-    /// Returns all cells that intersect this convex polyhedron
+    fn cells_in_location(&self, location: &PointLocation) -> Vec<CellID> {
+        match location {
+            PointLocation::AllPoints => self.cells.keys().cloned().collect(),
+            PointLocation::Aabb(aabb) => self.cells_in_convex_polyhedron(aabb),
+            PointLocation::Obb(obb) => self.cells_in_convex_polyhedron(obb),
+            PointLocation::Frustum(frustum) => self.cells_in_convex_polyhedron(frustum),
+            PointLocation::S2Cells(cell_union) => self.cells_intersecting_region(cell_union),
+            // The corners of the rect do not contain the earth's curvature between them.
+            PointLocation::WebMercatorRect(wmr) => self.cells_in_rect_bound(wmr),
+        }
+    }
+
+    /// Returns all cells that intersect this convex polyhedron. Candidates are preselected with a
+    /// lat/lng rectangle around the polyhedron, and each is then tested against the polyhedron
+    /// with the volume between the lowest and highest point of the point cloud.
     fn cells_in_convex_polyhedron<T>(&self, poly: &T) -> Vec<CellID>
+    where
+        T: ConvexPolyhedron,
+    {
+        let (min_radius, max_radius) = radius_range(&self.meta.bounding_box);
+        let intersector = poly.intersector();
+        self.cells_in_rect_bound(poly)
+            .into_iter()
+            .filter(|cell_id| {
+                let cell_volume = S2CellVolume::new(&self.cells[cell_id], min_radius, max_radius);
+                intersector.intersect(&cell_volume.intersector()) != Relation::Out
+            })
+            .collect()
+    }
+
+    /// Returns all cells that intersect a lat/lng rectangle around the corners of this convex
+    /// polyhedron. Used for polyhedra whose corners only approximate their shape.
+    fn cells_in_rect_bound<T>(&self, poly: &T) -> Vec<CellID>
     where
         T: ConvexPolyhedron,
     {
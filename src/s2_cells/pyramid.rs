@@ -0,0 +1,201 @@
+// Copyright 2020 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::data_provider::{ChecksummingDataSink, DataProvider, DataSink};
+use crate::errors::*;
+use crate::read_write::{Encoding, NodeIterator, RawNodeWriter};
+use crate::s2_cells::{S2CellMeta, S2Meta};
+use crate::{PointCloudMeta, PointsBatch};
+use fnv::FnvHashMap;
+use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use s2::cellid::CellID;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Builds a pyramid on top of the S2 cells that the data provider reads: Level by level up to
+/// `coarsest_level`, every parent cell takes every fourth point of its children, like the interior
+/// nodes of an octree. Children without points and children are dropped. The cells and the meta
+/// are rewritten into the data sink, which has to write to where the data provider reads from.
+/// The parents of each level are kept in memory until they were subsampled into their own
+/// parents, so that they are never read back. Returns the new meta.
+pub fn build_pyramid_into(
+    data_provider: &dyn DataProvider,
+    data_sink: Arc<dyn DataSink>,
+    coarsest_level: u64,
+) -> Result<S2Meta> {
+    let meta_proto = data_provider.meta_proto()?;
+    let meta = S2Meta::from_proto(meta_proto.clone())?;
+    let finest_level = match meta.levels() {
+        [level] => *level,
+        [] => return Err(ErrorKind::InvalidInput("There are no S2 cells.".to_string()).into()),
+        _ => {
+            return Err(
+                ErrorKind::InvalidInput("The S2 cells already are a pyramid.".to_string()).into(),
+            )
+        }
+    };
+    if coarsest_level >= finest_level {
+        return Err(ErrorKind::InvalidInput(format!(
+            "The coarsest level of the pyramid has to be below the level of the cells, {}.",
+            finest_level
+        ))
+        .into());
+    }
+    let data_sink = ChecksummingDataSink::for_rewriting(data_sink, &meta_proto);
+
+    let mut cells = meta.get_cells().clone();
+    // The parents of the previous level, which are written once they were subsampled themselves.
+    let mut rewritten: FnvHashMap<CellID, PointsBatch> = FnvHashMap::default();
+    for level in (coarsest_level..finest_level).rev() {
+        let mut children_by_parent: FnvHashMap<CellID, Vec<(CellID, Option<PointsBatch>)>> =
+            FnvHashMap::default();
+        for cell_id in cells.keys().filter(|id| id.level() == level + 1) {
+            children_by_parent
+                .entry(cell_id.immediate_parent())
+                .or_default()
+                .push((*cell_id, rewritten.remove(cell_id)));
+        }
+        let parents: Vec<_> = children_by_parent.into_iter().collect();
+        let subsampled: Vec<(CellID, PointsBatch, Vec<(CellID, u64)>)> = parents
+            .into_par_iter()
+            .map(|(parent_id, children)| {
+                let (parent, num_points) =
+                    subsample_children_into(data_provider, &data_sink, &meta, &cells, children)?;
+                Ok((parent_id, parent, num_points))
+            })
+            .collect::<Result<_>>()?;
+        for (parent_id, parent, num_child_points) in subsampled {
+            let num_points = num_child_points
+                .into_iter()
+                .chain(std::iter::once((parent_id, parent.position.len() as u64)));
+            for (cell_id, num_points) in num_points {
+                let has_children = cell_id
+                    .children()
+                    .iter()
+                    .any(|child_id| cells.contains_key(child_id));
+                if num_points == 0 && !has_children {
+                    cells.remove(&cell_id);
+                } else {
+                    cells.insert(cell_id, S2CellMeta { num_points });
+                }
+            }
+            if !parent.position.is_empty() {
+                rewritten.insert(parent_id, parent);
+            }
+        }
+    }
+    let attributes = attributes_of(&meta);
+    rewritten.par_iter().try_for_each(|(cell_id, batch)| {
+        RawNodeWriter::write_or_remove(
+            &data_sink,
+            &cell_id.to_token(),
+            Encoding::Plain,
+            &meta,
+            &attributes,
+            batch,
+        )
+    })?;
+
+    let new_meta = S2Meta::new(
+        cells,
+        meta.attribute_data_types().clone(),
+        meta.bounding_box().clone(),
+    )
+    .with_attribute_compression(meta.attribute_compression().clone())
+    .with_attribute_encodings(meta.attribute_encodings().clone());
+    let mut new_meta_proto = new_meta.to_proto();
+    new_meta_proto.set_checksums(meta_proto.get_checksums().clone());
+    data_sink.write_meta(&new_meta_proto)?;
+    data_sink.finalize()?;
+    Ok(new_meta)
+}
+
+fn attributes_of(meta: &S2Meta) -> Vec<&str> {
+    meta.attribute_data_types()
+        .keys()
+        .map(String::as_str)
+        .collect()
+}
+
+/// Moves every fourth point of the children into the parent, and writes the children. Children
+/// that were rewritten before are given with their points, the others are read. Returns the
+/// parent and the new number of points of the children.
+fn subsample_children_into(
+    data_provider: &dyn DataProvider,
+    data_sink: &Arc<dyn DataSink>,
+    meta: &S2Meta,
+    cells: &FnvHashMap<CellID, S2CellMeta>,
+    children: Vec<(CellID, Option<PointsBatch>)>,
+) -> Result<(PointsBatch, Vec<(CellID, u64)>)> {
+    let mut parent = PointsBatch {
+        position: Vec::new(),
+        attributes: BTreeMap::new(),
+    };
+    let attributes = attributes_of(meta);
+    let mut num_points = Vec::with_capacity(children.len());
+    for (child_id, child) in children {
+        let child = match child {
+            Some(child) => Some(child),
+            None => read_cell(data_provider, meta, child_id, cells[&child_id].num_points)?,
+        };
+        let mut child = match child {
+            Some(child) if !child.position.is_empty() => child,
+            _ => {
+                num_points.push((child_id, 0));
+                continue;
+            }
+        };
+        let (keep_parent, keep_child): (Vec<bool>, Vec<bool>) = (0..child.position.len())
+            .map(|i| {
+                let in_parent = i % 4 == 0;
+                (in_parent, !in_parent)
+            })
+            .unzip();
+        let mut parent_points = child.clone();
+        parent_points.retain(&keep_parent);
+        parent.append(&mut parent_points)?;
+        child.retain(&keep_child);
+        RawNodeWriter::write_or_remove(
+            data_sink,
+            &child_id.to_token(),
+            Encoding::Plain,
+            meta,
+            &attributes,
+            &child,
+        )?;
+        num_points.push((child_id, child.position.len() as u64));
+    }
+    Ok((parent, num_points))
+}
+
+/// Reads the whole cell into memory, since it is rewritten afterwards.
+fn read_cell(
+    data_provider: &dyn DataProvider,
+    meta: &S2Meta,
+    cell_id: CellID,
+    num_points: u64,
+) -> Result<Option<PointsBatch>> {
+    let num_points = num_points as usize;
+    let mut node_iterator = NodeIterator::from_data_provider(
+        data_provider,
+        meta.attribute_data_types(),
+        meta.attribute_compression(),
+        meta.attribute_encodings(),
+        Encoding::Plain,
+        &cell_id,
+        num_points,
+        std::cmp::max(num_points, 1),
+    )?;
+    node_iterator.try_next()
+}
@@ -1,3 +1,4 @@
+use nalgebra::Point3;
 use pbr::ProgressBar;
 use std::error::Error;
 use std::io;
@@ -20,6 +21,25 @@ where
     Ok((s[..pos].parse()?, s[pos + 1..].parse()?))
 }
 
+/// Parses exactly `n` numbers separated by spaces, commas or semicolons.
+pub fn f64s_from_str(s: &str, n: usize) -> Result<Vec<f64>, &'static str> {
+    let values: Result<Vec<f64>, &'static str> = s
+        .split(&[' ', ',', ';'][..])
+        .map(|s| s.parse::<f64>().map_err(|_| "Could not parse number."))
+        .collect();
+    let values = values?;
+    if values.len() != n {
+        return Err("Wrong number of values.");
+    }
+    Ok(values)
+}
+
+/// Parses a point given as e.g. "1.0,2.0,3.0".
+pub fn point3f64_from_str(s: &str) -> Result<Point3<f64>, &'static str> {
+    let coords = f64s_from_str(s, 3)?;
+    Ok(Point3::new(coords[0], coords[1], coords[2]))
+}
+
 pub fn create_progress_bar(total: usize, message: &str) -> ProgressBar<io::Stderr> {
     let mut progress_bar = ProgressBar::on(io::stderr(), total as u64);
     progress_bar.set_max_refresh_rate(Some(PROGRESS_REFRESH_RATE));
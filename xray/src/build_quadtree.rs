@@ -5,13 +5,16 @@ use crate::generation::{
 use clap::{crate_authors, ArgEnum};
 use nalgebra::Isometry3;
 use point_cloud_client::PointCloudClientBuilder;
-use point_viewer::data_provider::DataProviderFactory;
+use point_viewer::data_provider::{
+    DataProviderFactory, DataSink, S3Client, S3Config, S3Location, S3Writer,
+};
+use point_viewer::filter::{AttributeFilter, AttributeSelector};
 use point_viewer::math::ClosedInterval;
 use point_viewer::read_write::attempt_increasing_rlimit_to_max;
 use point_viewer::utils::parse_key_val;
 use quadtree::NodeId;
-use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 pub trait Extension {
     fn pre_init(app: clap::App) -> clap::App;
@@ -28,6 +31,15 @@ fn parse_arguments<T: Extension>() -> clap::ArgMatches {
                 .long("output-directory")
                 .required(true)
                 .takes_value(true),
+            clap::Arg::new("upload_to")
+                .about(
+                    "S3 location to also write the X-Ray quadtree to, e.g. \
+                     s3://bucket/path/to/xray. The endpoint and credentials are read from the \
+                     S3_ENDPOINT, AWS_REGION, AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY \
+                     environment variables.",
+                )
+                .long("upload-to")
+                .takes_value(true),
             clap::Arg::new("resolution")
                 .about("Size of 1px in meters on the finest X-Ray level.")
                 .long("resolution")
@@ -96,6 +108,14 @@ fn parse_arguments<T: Extension>() -> clap::ArgMatches {
                 .long("filter-interval")
                 .takes_value(true)
                 .multiple(true),
+            clap::Arg::new("filter")
+                .about(
+                    "Filter expression on attributes, e.g. --filter \"classification in {2, 6} && \
+                     !(intensity < 2.0) || color[0] in [200, )\". Combined with the filter \
+                     intervals if both are given.",
+                )
+                .long("filter")
+                .takes_value(true),
             clap::Arg::new("binning")
                 .about(
                     "Binning size for one attribute, e.g. --binning timestamp=30000000000, \
@@ -178,6 +198,13 @@ pub fn run<T: Extension>(data_provider_factory: DataProviderFactory) {
     .to_color();
 
     let output_directory = PathBuf::from(args.value_of("output_directory").unwrap());
+    let data_sink = args
+        .value_of("upload_to")
+        .map(|location| -> Arc<dyn DataSink> {
+            let location: S3Location = location.parse().expect("Invalid S3 location.");
+            let config = S3Config::from_env().expect("Could not configure the S3 client.");
+            Arc::new(S3Writer::new(S3Client::new(config), location))
+        });
 
     rayon::ThreadPoolBuilder::new()
         .num_threads(num_threads)
@@ -196,11 +223,22 @@ pub fn run<T: Extension>(data_provider_factory: DataProviderFactory) {
         .build()
         .expect("Could not create point cloud client.");
 
-    let filter_intervals = args
+    let mut filters = args
         .values_of("filter_interval")
         .unwrap_or_default()
-        .map(|f| parse_key_val(f).unwrap())
-        .collect::<HashMap<String, ClosedInterval<f64>>>();
+        .map(|f| {
+            let (name, interval) = parse_key_val::<String, ClosedInterval<f64>>(f).unwrap();
+            AttributeFilter::InInterval(AttributeSelector::new(name), interval.into())
+        })
+        .collect::<Vec<_>>();
+    if let Some(filter) = args.value_of("filter") {
+        filters.push(filter.parse().expect("filter could not be parsed."));
+    }
+    let filter = match filters.len() {
+        0 => None,
+        1 => filters.pop(),
+        _ => Some(AttributeFilter::And(filters)),
+    };
     let root_node_id = args
         .value_of("root_node_id")
         .unwrap()
@@ -208,9 +246,10 @@ pub fn run<T: Extension>(data_provider_factory: DataProviderFactory) {
         .expect("root_node_id could not be parsed.");
     let parameters = XrayParameters {
         output_directory,
+        data_sink,
         point_cloud_client,
         query_from_global: T::query_from_global(&args),
-        filter_intervals,
+        filter,
         tile_background_color,
         tile_size_px,
         pixel_size_m,
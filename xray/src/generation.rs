@@ -12,18 +12,20 @@ use num::clamp;
 use point_cloud_client::PointCloudClient;
 use point_viewer::attributes::AttributeData;
 use point_viewer::color::{Color, TRANSPARENT, WHITE};
+use point_viewer::data_provider::DataSink;
+use point_viewer::filter::AttributeFilter;
 use point_viewer::geometry::{Aabb, Obb};
-use point_viewer::iterator::{PointLocation, PointQuery};
-use point_viewer::math::ClosedInterval;
+use point_viewer::iterator::{OutputFrame, PointLocation, PointQuery};
 use point_viewer::utils::create_syncable_progress_bar;
 use point_viewer::{match_1d_attr_data, PointsBatch};
 use quadtree::{ChildIndex, Node, NodeId, Rect};
 use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use stats::OnlineStats;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
 use std::error::Error;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 // The number of Z-buckets we subdivide our bounding cube into along the z-direction. This affects
 // the saturation of a point in x-rays: the more buckets contain a point, the darker the pixel
@@ -452,9 +454,12 @@ pub fn build_parent(children: &[Option<RgbaImage>], tile_background_color: Color
 
 pub struct XrayParameters {
     pub output_directory: PathBuf,
+    /// If set, the finished quadtree is also written into this data sink. Tiles are read back
+    /// while building their parents, so the output directory is still needed.
+    pub data_sink: Option<Arc<dyn DataSink>>,
     pub point_cloud_client: PointCloudClient,
     pub query_from_global: Option<Isometry3<f64>>,
-    pub filter_intervals: HashMap<String, ClosedInterval<f64>>,
+    pub filter: Option<AttributeFilter>,
     pub tile_background_color: Color<u8>,
     pub tile_size_px: u32,
     pub pixel_size_m: f64,
@@ -476,25 +481,20 @@ pub fn xray_from_points(
         None => PointLocation::Aabb(bbox.clone()),
     };
     let mut attributes = coloring_strategy.attributes();
-    attributes.extend(parameters.filter_intervals.keys().cloned());
+    if let Some(filter) = &parameters.filter {
+        attributes.extend(filter.attributes().into_iter().map(String::from));
+    }
     let point_query = PointQuery {
         attributes: attributes.iter().map(|a| a.as_ref()).collect(),
         location,
-        filter_intervals: parameters
-            .filter_intervals
-            .iter()
-            .map(|(k, v)| (&k[..], *v))
-            .collect(),
+        filter: parameters.filter.clone(),
+        output_frame: parameters.query_from_global.map(OutputFrame::Isometry),
+        ..Default::default()
     };
     let _ = parameters
         .point_cloud_client
-        .for_each_point_data(&point_query, |mut points_batch| {
+        .for_each_point_data(&point_query, |points_batch| {
             seen_any_points = true;
-            if let Some(query_from_global) = &parameters.query_from_global {
-                for p in &mut points_batch.position {
-                    *p = query_from_global.transform_point(p);
-                }
-            }
             coloring_strategy.process_point_data(&points_batch, bbox, image_size);
             Ok(())
         });
@@ -609,9 +609,36 @@ pub fn build_xray_quadtree(
         tile_size: parameters.tile_size_px,
         deepest_level,
     };
-    meta.to_disk(get_meta_pb_path(&parameters.output_directory, root_node_id))
+    let meta_pb_path = get_meta_pb_path(&parameters.output_directory, root_node_id);
+    meta.to_disk(&meta_pb_path)
         .expect("Filed to write meta file to disk.");
 
+    if let Some(data_sink) = &parameters.data_sink {
+        let mut paths: Vec<_> = meta
+            .nodes
+            .iter()
+            .map(|node_id| get_image_path(&parameters.output_directory, *node_id))
+            .collect();
+        paths.push(meta_pb_path);
+        write_files_into(data_sink.as_ref(), &paths)?;
+    }
+
+    Ok(())
+}
+
+/// Writes the files into the data sink, keeping their names, and finalizes it.
+fn write_files_into(data_sink: &dyn DataSink, paths: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+    let progress_bar = create_syncable_progress_bar(paths.len(), "Writing into data sink");
+    paths
+        .par_iter()
+        .try_for_each(|path| -> point_viewer::errors::Result<()> {
+            let file_name = path.file_name().unwrap().to_string_lossy();
+            data_sink.write_blob(&file_name, &fs::read(path)?)?;
+            progress_bar.lock().unwrap().inc();
+            Ok(())
+        })?;
+    progress_bar.lock().unwrap().finish_println("");
+    data_sink.finalize()?;
     Ok(())
 }
 